@@ -1,9 +1,18 @@
 extern crate capnpc;
 
 fn main() {
+    // Sandstorm's schema files, which define grain.capnp and its imports. A dev install of
+    // Sandstorm puts them in /opt/sandstorm/latest/usr/include; point
+    // `SANDSTORM_INCLUDE_DIR` at a copy of them to build without one.
+    let include_dir = ::std::env::var("SANDSTORM_INCLUDE_DIR")
+        .unwrap_or_else(|_| "/opt/sandstorm/latest/usr/include".to_string());
+    println!("cargo:rerun-if-env-changed=SANDSTORM_INCLUDE_DIR");
+
     ::capnpc::CompilerCommand::new()
         .src_prefix("schema")
+        .import_path(include_dir)
+        // sandstorm/grain.capnp
+        .crate_provides("sandstorm", [0xc8d91463cfc4fb4a])
         .file("schema/collections.capnp")
         .run().expect("compiling");
 }
-
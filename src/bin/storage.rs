@@ -0,0 +1,636 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Inspects and repairs a grain's storage directory without Sandstorm. Point it at a copy of
+//! the grain's `/var`, or at least make sure the grain isn't running.
+
+use base64::Engine;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use collections::collections_capnp::{file_metadata, history_record, link_metadata, note_metadata,
+                                     ui_view_metadata};
+use collections::durable;
+use collections::entry_store::{self, EntryStore};
+use collections::identity_map::{self, IdentityId};
+use collections::migrations;
+use collections::server::{self, json_escape_str};
+
+const USAGE: &str = "usage: collections-storage <directory> <command>
+
+commands:
+  list        entries, quarantined entries, links, notes, files and what was set aside
+  identities  saved identities and the contents of the trash
+  check       checks what the server relies on; exits with status 1 if anything is wrong
+  repair      fixes what `check` reports as fixable; needs the current layout, see `migrate`
+  export      writes the collection to stdout in the format of GET /export.json
+  migrate     upgrades the layout to the current version, as the server does on startup";
+
+type Message = ::capnp::message::Reader<::capnp::serialize::OwnedSegments>;
+
+fn read_message_file(path: &Path) -> ::capnp::Result<Message> {
+    let mut reader = ::std::fs::File::open(path)?;
+    ::capnp::serialize::read_message(&mut reader, Default::default())
+}
+
+/// The names and paths of the files in `directory`, sorted by name. Empty if it doesn't exist.
+fn list_dir(directory: &Path) -> ::capnp::Result<Vec<(String, PathBuf)>> {
+    let dir_entries = match ::std::fs::read_dir(directory) {
+        Ok(d) => d,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut result = Vec::new();
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry?;
+        result.push((dir_entry.file_name().to_string_lossy().into_owned(), dir_entry.path()));
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn optional_text_to_json(has: bool, text: ::capnp::Result<::capnp::text::Reader>) -> ::capnp::Result<String> {
+    Ok(if has { json_escape_str(text?.to_str()?) } else { "null".into() })
+}
+
+fn entry_to_json(token: &str, bytes: &[u8]) -> ::capnp::Result<String> {
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+    let metadata: ui_view_metadata::Reader = message.get_root()?;
+    Ok(format!(
+        "{{\"token\":{},\"title\":{},\"dateAdded\":{},\"addedBy\":{},\"addedByName\":{},\"entryId\":{},\"modifiedAt\":{},\"titlePinned\":{}}}",
+        json_escape_str(token),
+        json_escape_str(metadata.get_title()?.to_str()?),
+        metadata.get_date_added(),
+        optional_text_to_json(metadata.has_added_by(), metadata.get_added_by())?,
+        optional_text_to_json(metadata.has_added_by_name(), metadata.get_added_by_name())?,
+        json_escape_str(metadata.get_entry_id()?.to_str()?),
+        metadata.get_modified_at(),
+        metadata.get_title_pinned()))
+}
+
+fn link_to_json(id: &str, path: &Path) -> ::capnp::Result<String> {
+    let message = read_message_file(path)?;
+    let link: link_metadata::Reader = message.get_root()?;
+    Ok(format!("{{\"id\":{},\"title\":{},\"url\":{},\"dateAdded\":{},\"addedBy\":{}}}",
+               json_escape_str(id),
+               json_escape_str(link.get_title()?.to_str()?),
+               json_escape_str(link.get_url()?.to_str()?),
+               link.get_date_added(),
+               optional_text_to_json(link.has_added_by(), link.get_added_by())?))
+}
+
+fn note_to_json(id: &str, path: &Path) -> ::capnp::Result<String> {
+    let message = read_message_file(path)?;
+    let note: note_metadata::Reader = message.get_root()?;
+    let kind = match note.get_kind()? {
+        note_metadata::Kind::Heading => "heading",
+        note_metadata::Kind::Paragraph => "paragraph",
+        note_metadata::Kind::Divider => "divider",
+    };
+    Ok(format!("{{\"id\":{},\"kind\":\"{}\",\"text\":{},\"position\":{},\"dateAdded\":{},\"addedBy\":{}}}",
+               json_escape_str(id),
+               kind,
+               json_escape_str(note.get_text()?.to_str()?),
+               note.get_position(),
+               note.get_date_added(),
+               optional_text_to_json(note.has_added_by(), note.get_added_by())?))
+}
+
+fn file_to_json(id: &str, path: &Path) -> ::capnp::Result<String> {
+    let message = read_message_file(path)?;
+    let file: file_metadata::Reader = message.get_root()?;
+    Ok(format!("{{\"id\":{},\"name\":{},\"mimeType\":{},\"size\":{},\"dateAdded\":{},\"addedBy\":{}}}",
+               json_escape_str(id),
+               json_escape_str(file.get_name()?.to_str()?),
+               json_escape_str(file.get_mime_type()?.to_str()?),
+               file.get_size(),
+               file.get_date_added(),
+               optional_text_to_json(file.has_added_by(), file.get_added_by())?))
+}
+
+/// Calls `f` on each intact record in the history log. Returns how many records are damaged,
+/// including one cut short at the end, which the server drops on startup.
+fn for_each_history_record<F>(root: &Path, mut f: F) -> ::capnp::Result<usize>
+    where F: FnMut(history_record::Reader) -> ::capnp::Result<()>
+{
+    let log = match ::std::fs::read(root.join("history")) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let records = durable::read_records(&log);
+    for (_, bytes) in records.records {
+        let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+        f(message.get_root()?)?;
+    }
+    Ok(records.damaged.len() + records.torn.iter().count())
+}
+
+fn optional_text(has: bool, text: ::capnp::Result<::capnp::text::Reader>) -> ::capnp::Result<Option<String>> {
+    Ok(if has { Some(text?.to_str()?.to_string()) } else { None })
+}
+
+fn added_by_of_link(path: &Path) -> ::capnp::Result<Option<String>> {
+    let message = read_message_file(path)?;
+    let link: link_metadata::Reader = message.get_root()?;
+    optional_text(link.has_added_by(), link.get_added_by())
+}
+
+fn added_by_of_note(path: &Path) -> ::capnp::Result<Option<String>> {
+    let message = read_message_file(path)?;
+    let note: note_metadata::Reader = message.get_root()?;
+    optional_text(note.has_added_by(), note.get_added_by())
+}
+
+fn added_by_of_file(path: &Path) -> ::capnp::Result<Option<String>> {
+    let message = read_message_file(path)?;
+    let file: file_metadata::Reader = message.get_root()?;
+    optional_text(file.has_added_by(), file.get_added_by())
+}
+
+fn added_by_of_entry(path: &Path) -> ::capnp::Result<Option<String>> {
+    let message = read_message_file(path)?;
+    let metadata: ui_view_metadata::Reader = message.get_root()?;
+    optional_text(metadata.has_added_by(), metadata.get_added_by())
+}
+
+fn list(root: &Path) -> ::capnp::Result<()> {
+    let contents = entry_store::read(root.join("entries"))?;
+    let mut tokens: Vec<&String> = contents.entries.keys().collect();
+    tokens.sort();
+    println!("entries ({}):", tokens.len());
+    for token in tokens {
+        match entry_to_json(token, &contents.entries[token]) {
+            Ok(json) => println!("  {}", json),
+            Err(e) => println!("  {}: unreadable: {}", token, e),
+        }
+    }
+
+    let quarantined = list_dir(&root.join("quarantine"))?;
+    println!("quarantined entries ({}):", quarantined.len());
+    for (token, path) in quarantined {
+        let result = ::std::fs::read(&path).map_err(::capnp::Error::from)
+            .and_then(|bytes| entry_to_json(&token, &bytes));
+        match result {
+            Ok(json) => println!("  {}", json),
+            Err(e) => println!("  {}: unreadable: {}", token, e),
+        }
+    }
+
+    let mut set_aside = Vec::new();
+    for kind in &["links", "notes", "files/metadata", "files/content", "profiles"] {
+        for (id, _) in list_dir(&root.join("set-aside").join(kind))? {
+            set_aside.push(format!("{}/{}", kind, id));
+        }
+    }
+    println!("set aside because they could not be loaded ({}):", set_aside.len());
+    for name in set_aside {
+        println!("  {}", name);
+    }
+
+    let kinds: [(&str, PathBuf, fn(&str, &Path) -> ::capnp::Result<String>); 3] = [
+        ("links", root.join("links"), link_to_json),
+        ("notes", root.join("notes"), note_to_json),
+        ("files", root.join("files").join("metadata"), file_to_json),
+    ];
+    for (name, directory, to_json) in kinds.iter() {
+        let files = list_dir(directory)?;
+        println!("{} ({}):", name, files.len());
+        for (id, path) in files {
+            match to_json(&id, &path) {
+                Ok(json) => println!("  {}", json),
+                Err(e) => println!("  {}: unreadable: {}", id, e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn identities(root: &Path) -> ::capnp::Result<()> {
+    let symlinks = list_dir(&root.join("identities"))?;
+    println!("identities ({}):", symlinks.len());
+    for (name, path) in symlinks {
+        match ::std::fs::read_link(&path) {
+            Ok(target) => println!("  {} -> {}", name, target.display()),
+            Err(e) => println!("  {}: not a symlink: {}", name, e),
+        }
+    }
+
+    let trash = list_dir(&root.join("trash"))?;
+    println!("trash, to be dropped when the grain next starts ({}):", trash.len());
+    for (name, _) in trash {
+        println!("  {}", name);
+    }
+    Ok(())
+}
+
+fn export(root: &Path) -> ::capnp::Result<()> {
+    let stdout = ::std::io::stdout();
+    server::export_storage(root, &mut stdout.lock())
+}
+
+/// Something that `repair` knows how to fix.
+enum Repair {
+    /// Drops the damaged records and the incomplete last record of the entry log, and compacts it.
+    CompactEntries,
+
+    /// Gives the entry a new random `entryId`.
+    AssignEntryId(String),
+
+    /// Moves the identity symlink to the trash, where the server drops its token on startup.
+    TrashIdentity(String),
+
+    RemoveFile(PathBuf),
+}
+
+struct Problem {
+    description: String,
+    repair: Option<Repair>,
+}
+
+fn problem(description: String, repair: Option<Repair>) -> Problem {
+    Problem { description: description, repair: repair }
+}
+
+/// Checks the invariants that the server relies on when it starts up.
+fn check(root: &Path) -> ::capnp::Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    let version = migrations::read_version(root)?;
+    if version != migrations::CURRENT_VERSION {
+        problems.push(problem(
+            format!("layout version is {}, but the current one is {}; run `migrate`",
+                    version, migrations::CURRENT_VERSION),
+            None));
+    }
+
+    // Identities that something refers to, by the names of their symlinks.
+    let mut referenced: HashSet<String> = HashSet::new();
+    let mut refer = |owner: &str, added_by: Option<String>, problems: &mut Vec<Problem>| {
+        match added_by.as_ref().map(|s| IdentityId::parse(s)) {
+            None => (),
+            Some(Ok(id)) => { referenced.insert(id.truncated_text()); }
+            Some(Err(e)) => problems.push(problem(format!("{}: malformed identity ID: {}", owner, e), None)),
+        }
+    };
+
+    let contents = match entry_store::read(root.join("entries")) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(problem(format!("entry store can't be read: {}", e), None));
+            return Ok(problems)
+        }
+    };
+    for &(offset, _, ref e) in &contents.damaged {
+        problems.push(problem(
+            format!("entry log has a damaged record at byte {}: {}", offset, e),
+            Some(Repair::CompactEntries)));
+    }
+    if let Some(offset) = contents.torn {
+        problems.push(problem(
+            format!("entry log ends with an incomplete record at byte {}", offset),
+            Some(Repair::CompactEntries)));
+    }
+    let mut unreadable: Vec<(&String, &::capnp::Error)> = contents.unreadable.iter().collect();
+    unreadable.sort_by_key(|&(token, _)| token);
+    for (token, e) in unreadable {
+        problems.push(problem(format!("entry {}: unreadable, will be quarantined: {}", token, e), None));
+    }
+
+    let mut tokens: Vec<&String> = contents.entries.keys().collect();
+    tokens.sort();
+    let mut entry_ids: HashMap<String, String> = HashMap::new();
+    for token in tokens {
+        let owner = format!("entry {}", token);
+        if let Err(e) = base64::engine::general_purpose::URL_SAFE.decode(token) {
+            problems.push(problem(format!("{}: malformed token: {}", owner, e), None));
+        }
+        let bytes = &contents.entries[token];
+        let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+        let metadata: ui_view_metadata::Reader = message.get_root()?;
+        if let Err(e) = metadata.get_title().and_then(|t| Ok(t.to_str()?.to_string())) {
+            problems.push(problem(format!("{}: unreadable title, will be quarantined: {}", owner, e), None));
+        }
+        refer(&owner, optional_text(metadata.has_added_by(), metadata.get_added_by())?, &mut problems);
+
+        let entry_id = metadata.get_entry_id()?.to_str()?.to_string();
+        if entry_id.is_empty() {
+            problems.push(problem(format!("{}: no entry ID", owner),
+                                  Some(Repair::AssignEntryId(token.clone()))));
+        } else if let Some(other) = entry_ids.get(&entry_id) {
+            problems.push(problem(format!("{}: same entry ID as entry {}", owner, other),
+                                  Some(Repair::AssignEntryId(token.clone()))));
+        } else {
+            entry_ids.insert(entry_id, token.clone());
+        }
+    }
+
+    // The server keeps the identities of quarantined entries, so that they can be retried.
+    for (token, path) in list_dir(&root.join("quarantine"))? {
+        if let Ok(added_by) = added_by_of_entry(&path) {
+            refer(&format!("quarantined entry {}", token), added_by, &mut problems);
+        }
+    }
+
+    for (id, path) in list_dir(&root.join("links"))? {
+        let owner = format!("link {}", id);
+        match added_by_of_link(&path) {
+            Ok(added_by) => refer(&owner, added_by, &mut problems),
+            Err(e) => problems.push(problem(format!("{}: unreadable, will be set aside: {}", owner, e), None)),
+        }
+    }
+    for (id, path) in list_dir(&root.join("notes"))? {
+        let owner = format!("note {}", id);
+        match added_by_of_note(&path) {
+            Ok(added_by) => refer(&owner, added_by, &mut problems),
+            Err(e) => problems.push(problem(format!("{}: unreadable, will be set aside: {}", owner, e), None)),
+        }
+    }
+
+    let file_contents: HashSet<String> = list_dir(&root.join("files").join("content"))?
+        .into_iter().map(|(id, _)| id).collect();
+    let mut file_ids = HashSet::new();
+    for (id, path) in list_dir(&root.join("files").join("metadata"))? {
+        let owner = format!("file {}", id);
+        match added_by_of_file(&path) {
+            Ok(added_by) => refer(&owner, added_by, &mut problems),
+            Err(e) => problems.push(problem(format!("{}: unreadable, will be set aside: {}", owner, e), None)),
+        }
+        if !file_contents.contains(&id) {
+            problems.push(problem(format!("{}: contents are missing", owner), None));
+        }
+        file_ids.insert(id);
+    }
+    for (id, path) in list_dir(&root.join("files").join("content"))? {
+        if !file_ids.contains(&id) {
+            problems.push(problem(format!("contents of file {} have no metadata", id),
+                                  Some(Repair::RemoveFile(path))));
+        }
+    }
+
+    let mut history_by = Vec::new();
+    match for_each_history_record(root, |record| {
+        history_by.push(optional_text(record.has_by(), record.get_by())?);
+        Ok(())
+    }) {
+        Ok(0) => (),
+        Ok(n) => problems.push(problem(
+            format!("history has {} damaged records, which will be dropped", n), None)),
+        Err(e) => problems.push(problem(format!("history can't be read: {}", e), None)),
+    }
+    for by in history_by {
+        refer("history", by, &mut problems);
+    }
+
+    let mut saved = HashSet::new();
+    for (name, path) in list_dir(&root.join("identities"))? {
+        match ::std::fs::read_link(&path).map_err(::capnp::Error::from)
+            .and_then(identity_map::read_sturdyref_symlink)
+        {
+            Ok(_) if referenced.contains(&name) => (),
+            Ok(_) => problems.push(problem(format!("identity {} is not referred to by anything", name),
+                                           Some(Repair::TrashIdentity(name.clone())))),
+            Err(e) => problems.push(problem(format!("identity {}: malformed symlink: {}", name, e), None)),
+        }
+        saved.insert(name);
+    }
+    let mut missing: Vec<&String> = referenced.difference(&saved).collect();
+    missing.sort();
+    for name in missing {
+        problems.push(problem(
+            format!("identity {} is referred to but not saved; its profile can't be shown", name),
+            None));
+    }
+
+    for (token, path) in list_dir(&root.join("view-info"))? {
+        if !contents.entries.contains_key(&token) {
+            problems.push(problem(format!("cached view info for missing entry {}", token),
+                                  Some(Repair::RemoveFile(path))));
+        }
+    }
+    for (id, path) in list_dir(&root.join("profiles"))? {
+        let known = IdentityId::parse(&id).map(|id| referenced.contains(&id.truncated_text()));
+        if known.ok() != Some(true) {
+            problems.push(problem(format!("cached profile of unknown identity {}", id),
+                                  Some(Repair::RemoveFile(path))));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn random_id() -> ::capnp::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 16];
+    ::std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(::hex::encode(&bytes))
+}
+
+/// Fixes what `check` reports as fixable. Refuses to touch an older layout: what refers to
+/// identities lives elsewhere there, so every identity would look unreferenced and be trashed.
+fn repair(root: &Path) -> ::capnp::Result<()> {
+    let version = migrations::read_version(root)?;
+    if version != migrations::CURRENT_VERSION {
+        return Err(::capnp::Error::failed(format!(
+            "layout version is {}, but the current one is {}; run `migrate` first",
+            version, migrations::CURRENT_VERSION)));
+    }
+    let problems = check(root)?;
+    let tmp_dir = root.join("tmp");
+    ::std::fs::create_dir_all(&tmp_dir)?;
+    // Opening the store drops damaged records and an incomplete record at the end of the log.
+    let mut store = EntryStore::open(root.join("entries"), &tmp_dir)?;
+
+    for p in problems {
+        match p.repair {
+            None => {
+                println!("can't fix: {}", p.description);
+                continue
+            }
+            Some(Repair::CompactEntries) => (),
+            Some(Repair::AssignEntryId(ref token)) => {
+                let bytes = store.entries()[token].clone();
+                let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+                let mut copy = ::capnp::message::Builder::new_default();
+                copy.set_root(message.get_root::<ui_view_metadata::Reader>()?)?;
+                copy.get_root::<ui_view_metadata::Builder>()?.set_entry_id(&random_id()?[..]);
+                store.apply(vec![entry_store::Change::Put {
+                    token: token.clone(),
+                    metadata: ::capnp::serialize::write_message_to_words(&copy),
+                }])?;
+            }
+            Some(Repair::TrashIdentity(ref name)) => {
+                let symlink = root.join("identities").join(name);
+                let trash = root.join("trash");
+                ::std::fs::create_dir_all(&trash)?;
+                durable::rename(&symlink, &trash.join(::std::fs::read_link(&symlink)?))?;
+            }
+            Some(Repair::RemoveFile(ref path)) => durable::remove_file(path)?,
+        }
+        println!("fixed: {}", p.description);
+    }
+
+    // Rebuild the snapshot, so that the next startup doesn't have to replay the log.
+    store.compact()?;
+    Ok(())
+}
+
+fn run(root: &Path, command: &str) -> ::capnp::Result<bool> {
+    match command {
+        "list" => list(root)?,
+        "identities" => identities(root)?,
+        "check" => {
+            let problems = check(root)?;
+            for p in problems.iter() {
+                println!("{}{}", p.description, if p.repair.is_some() { " (fixable)" } else { "" });
+            }
+            return Ok(problems.is_empty())
+        }
+        "repair" => repair(root)?,
+        "export" => export(root)?,
+        "migrate" => migrations::run(root)?,
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("{}", USAGE);
+        ::std::process::exit(2);
+    }
+    match run(Path::new(&args[1]), &args[2]) {
+        Ok(true) => (),
+        Ok(false) => ::std::process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use collections::collections_capnp::ui_view_metadata;
+    use collections::entry_store::{self, EntryStore};
+    use collections::identity_map::IdentityId;
+    use collections::migrations;
+
+    /// A fresh, empty directory that is deleted when this is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = ::std::env::temp_dir()
+                .join(format!("collections-storage-test-{}-{}", ::std::process::id(), name));
+            let _ = ::std::fs::remove_dir_all(&path);
+            ::std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = ::std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn metadata_bytes(title: &str, added_by: &IdentityId) -> Vec<u8> {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut metadata: ui_view_metadata::Builder = message.init_root();
+            metadata.set_title(title);
+            metadata.set_added_by(&added_by.to_string()[..]);
+            metadata.set_entry_id(title);
+        }
+        ::capnp::serialize::write_message_to_words(&message)
+    }
+
+    /// Saves `token` as the identity `id`, the way `IdentityMap` does.
+    fn save_identity(root: &Path, id: &IdentityId, token: &str) {
+        let identities = root.join("identities");
+        ::std::fs::create_dir_all(&identities).unwrap();
+        ::std::os::unix::fs::symlink(token, identities.join(id.truncated_text())).unwrap();
+    }
+
+    fn descriptions(root: &Path) -> Vec<String> {
+        super::check(root).unwrap().into_iter().map(|p| p.description).collect()
+    }
+
+    #[test]
+    fn repair_trashes_unreferenced_identities() {
+        let dir = TempDir::new("repair-current");
+        let root = &dir.0;
+        migrations::run(root).unwrap();
+        let alice = IdentityId::from_bytes(&[1; 32]).unwrap();
+        let bob = IdentityId::from_bytes(&[2; 32]).unwrap();
+        {
+            let mut store = EntryStore::open(root.join("entries"), root.join("tmp")).unwrap();
+            store.apply(vec![entry_store::Change::Put {
+                token: "dG9rZW4x".into(),
+                metadata: metadata_bytes("first", &alice),
+            }]).unwrap();
+        }
+        save_identity(root, &alice, "YWxpY2U");
+        save_identity(root, &bob, "Ym9i");
+
+        assert_eq!(descriptions(root),
+                   vec![format!("identity {} is not referred to by anything", bob.truncated_text())]);
+        assert!(super::check(root).unwrap()[0].repair.is_some());
+
+        super::repair(root).unwrap();
+        assert!(descriptions(root).is_empty());
+        assert!(::std::fs::symlink_metadata(root.join("identities").join(alice.truncated_text())).is_ok());
+        assert!(::std::fs::symlink_metadata(root.join("identities").join(bob.truncated_text())).is_err());
+        assert!(::std::fs::symlink_metadata(root.join("trash").join("Ym9i")).is_ok());
+    }
+
+    #[test]
+    fn repair_refuses_old_layout() {
+        let dir = TempDir::new("repair-old");
+        let root = &dir.0;
+        let alice = IdentityId::from_bytes(&[1; 32]).unwrap();
+        ::std::fs::write(root.join("layout-version"), "1\n").unwrap();
+        ::std::fs::create_dir_all(root.join("sturdyrefs")).unwrap();
+        ::std::fs::write(root.join("sturdyrefs").join("dG9rZW4x"), metadata_bytes("first", &alice)).unwrap();
+        save_identity(root, &alice, "YWxpY2U");
+
+        let problems = descriptions(root);
+        assert_eq!(problems[0], format!("layout version is 1, but the current one is {}; run `migrate`",
+                                        migrations::CURRENT_VERSION));
+
+        assert!(super::repair(root).is_err());
+        assert!(::std::fs::symlink_metadata(root.join("identities").join(alice.truncated_text())).is_ok());
+        assert!(!root.join("trash").exists());
+
+        // Once migrated, the identity is referred to from the entry store.
+        migrations::run(root).unwrap();
+        assert!(descriptions(root).is_empty());
+        super::repair(root).unwrap();
+        assert!(::std::fs::symlink_metadata(root.join("identities").join(alice.truncated_text())).is_ok());
+    }
+}
@@ -0,0 +1,522 @@
+// Copyright (c) 2014-2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Serves a collection over plain HTTP on a local port, with the fakes in `fake.rs` standing
+//! in for Sandstorm, so that the app can be tried out without a Sandstorm dev install.
+//! Enabled by setting `COLLECTIONS_DEV_ADDRESS`.
+//!
+//! Each HTTP request gets a `WebSession` of its own. There is no access control: whoever
+//! can reach the port is an editor, unless the request carries an `X-Sandstorm-Permissions`
+//! header that doesn't include "write".
+
+use capnp::Error;
+use base64::{self, Engine};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt, StreamExt};
+use futures::channel::mpsc;
+use futures::future::Either;
+use sha1::{Digest, Sha1};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::fake::{FakeSandstormApi, FakeSessionContext, new_identity};
+use crate::server::{self, Config};
+
+use sandstorm::identity_capnp::{identity};
+use sandstorm::grain_capnp::{session_context, ui_view, sandstorm_api};
+use sandstorm::util_capnp::{byte_stream};
+use sandstorm::web_session_capnp::{web_session};
+use sandstorm::web_session_capnp::web_session::{response, web_socket_stream};
+
+/// The identity that every request is made as.
+const DEV_IDENTITY_ID: [u8; 32] = [0xde; 32];
+
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+/// What every session shares.
+struct Dev {
+    view: ui_view::Client,
+    context: session_context::Client,
+    identity: identity::Client,
+}
+
+struct Request {
+    method: String,
+
+    /// Without the leading '/', like Sandstorm passes it on.
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+
+    fn can_write(&self) -> bool {
+        match self.header("X-Sandstorm-Permissions") {
+            None => true,
+            Some(permissions) => permissions.split(',').any(|p| p.trim() == "write"),
+        }
+    }
+
+    fn is_web_socket(&self) -> bool {
+        self.header("Upgrade").map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Reads one request. Returns `None` if the connection closes before it starts.
+async fn read_request<R>(reader: &mut R) -> Result<Option<Request>, Error>
+    where R: AsyncRead + Unpin
+{
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    let head_end = loop {
+        if let Some(i) = find(&buffer, b"\r\n\r\n") {
+            break i;
+        }
+        if buffer.len() > MAX_HEAD_BYTES {
+            return Err(Error::failed("request head is too large".into()));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::failed("connection closed in the middle of a request".into()));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = ::std::str::from_utf8(&buffer[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_string();
+    let target = request_line.next().unwrap_or("");
+    let path = target.strip_prefix('/').unwrap_or(target).to_string();
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.find(':') {
+            Some(i) => headers.push((line[..i].trim().to_string(), line[i + 1..].trim().to_string())),
+            None => return Err(Error::failed(format!("malformed header: {}", line))),
+        }
+    }
+
+    let mut request = Request {
+        method: method,
+        path: path,
+        headers: headers,
+        body: buffer[head_end + 4..].to_vec(),
+    };
+    let length: usize = match request.header("Content-Length") {
+        None => 0,
+        Some(l) => l.parse().map_err(|_| Error::failed(format!("bad Content-Length: {}", l)))?,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(Error::failed("request body is too large".into()));
+    }
+    while request.body.len() < length {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::failed("connection closed in the middle of a request".into()));
+        }
+        request.body.extend_from_slice(&chunk[..n]);
+    }
+    request.body.truncate(length);
+    Ok(Some(request))
+}
+
+async fn open_session(dev: &Dev, can_write: bool) -> Result<web_session::Client, Error> {
+    let mut req = dev.view.new_session_request();
+    {
+        use capnp::traits::HasTypeId;
+        let mut params = req.get();
+        params.set_session_type(web_session::Client::TYPE_ID);
+        params.set_context(dev.context.clone());
+        let mut user_info = params.init_user_info();
+        user_info.reborrow().init_display_name().set_default_text("Dev User");
+        user_info.set_identity_id(&DEV_IDENTITY_ID[..]);
+        user_info.set_identity(dev.identity.clone());
+        user_info.init_permissions(1).set(0, can_write);
+    }
+    let response = req.send().promise.await?;
+    Ok(web_session::Client { client: response.get()?.get_session()?.client })
+}
+
+/// The status line and headers. Without a content length, the body ends when the connection
+/// is closed.
+fn http_head(status: (u16, &str), headers: &[(&str, String)], content_length: Option<usize>)
+             -> Vec<u8>
+{
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.0, status.1);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(length) = content_length {
+        head.push_str(&format!("Content-Length: {}\r\n", length));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+fn http_response(status: (u16, &str), headers: &[(&str, String)], body: &[u8]) -> Vec<u8> {
+    let mut result = http_head(status, headers, Some(body.len()));
+    result.extend_from_slice(body);
+    result
+}
+
+/// Receives the body of a streamed response and passes it on to the connection.
+struct ResponseStream {
+    sender: RefCell<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+impl byte_stream::Server for ResponseStream {
+    async fn write(self: Rc<Self>,
+                   params: byte_stream::WriteParams,
+                   _results: byte_stream::WriteResults)
+                   -> Result<(), Error>
+    {
+        let data = params.get()?.get_data()?.to_vec();
+        match *self.sender.borrow() {
+            Some(ref sender) => sender.unbounded_send(data)
+                .map_err(|_| Error::disconnected("connection is closed".into())),
+            None => Err(Error::failed("write() after done()".into())),
+        }
+    }
+
+    async fn done(self: Rc<Self>,
+                  _params: byte_stream::DoneParams,
+                  _results: byte_stream::DoneResults)
+                  -> Result<(), Error>
+    {
+        self.sender.borrow_mut().take();
+        Ok(())
+    }
+}
+
+fn success_status(code: response::SuccessCode) -> (u16, &'static str) {
+    match code {
+        response::SuccessCode::Created => (201, "Created"),
+        response::SuccessCode::Accepted => (202, "Accepted"),
+        _ => (200, "OK"),
+    }
+}
+
+fn client_error_status(code: response::ClientErrorCode) -> (u16, &'static str) {
+    match code {
+        response::ClientErrorCode::Forbidden => (403, "Forbidden"),
+        response::ClientErrorCode::NotFound => (404, "Not Found"),
+        response::ClientErrorCode::RequestEntityTooLarge => (413, "Payload Too Large"),
+        _ => (400, "Bad Request"),
+    }
+}
+
+/// Translates a `WebSession.Response` into HTTP. If its body was streamed, it's left out, and
+/// comes from `body_stream` instead.
+fn to_http(response: response::Reader,
+           body_stream: Option<mpsc::UnboundedReceiver<Vec<u8>>>)
+           -> Result<(Vec<u8>, Option<mpsc::UnboundedReceiver<Vec<u8>>>), Error>
+{
+    let mut headers = Vec::new();
+    let (status, body) = match response.which()? {
+        response::Content(content) => {
+            headers.push(("Content-Type", content.get_mime_type()?.to_str()?.to_string()));
+            if content.has_encoding() {
+                headers.push(("Content-Encoding", content.get_encoding()?.to_str()?.to_string()));
+            }
+            if let response::content::disposition::Download(filename) =
+                content.get_disposition().which()?
+            {
+                headers.push(("Content-Disposition",
+                              format!("attachment; filename=\"{}\"", filename?.to_str()?)));
+            }
+            let body = match content.get_body().which()? {
+                response::content::body::Bytes(bytes) => bytes?.to_vec(),
+                response::content::body::Stream(_) => match body_stream {
+                    Some(stream) => {
+                        let head = http_head(success_status(content.get_status_code()?),
+                                             &headers, None);
+                        return Ok((head, Some(stream)));
+                    }
+                    None => return Err(Error::failed("unexpected streamed response".into())),
+                },
+            };
+            (success_status(content.get_status_code()?), body)
+        }
+        response::NoContent(_) => ((204, "No Content"), Vec::new()),
+        response::PreconditionFailed(_) => ((412, "Precondition Failed"), Vec::new()),
+        response::Redirect(redirect) => {
+            headers.push(("Location", redirect.get_location()?.to_str()?.to_string()));
+            if redirect.get_is_permanent() {
+                ((301, "Moved Permanently"), Vec::new())
+            } else {
+                ((302, "Found"), Vec::new())
+            }
+        }
+        response::ClientError(error) => {
+            headers.push(("Content-Type", "text/html; charset=UTF-8".to_string()));
+            (client_error_status(error.get_status_code()?),
+             error.get_description_html()?.as_bytes().to_vec())
+        }
+        response::ServerError(error) => {
+            headers.push(("Content-Type", "text/html; charset=UTF-8".to_string()));
+            ((500, "Internal Server Error"), error.get_description_html()?.as_bytes().to_vec())
+        }
+    };
+    Ok((http_response(status, &headers, &body), None))
+}
+
+/// Makes the call on `session` that corresponds to `request`. Returns the response, and the
+/// rest of its body if that is streamed.
+async fn respond(session: &web_session::Client, request: &Request)
+                 -> Result<(Vec<u8>, Option<mpsc::UnboundedReceiver<Vec<u8>>>), Error>
+{
+    let mime_type = request.header("Content-Type").unwrap_or("application/octet-stream");
+    let mut body_stream = None;
+    let response = match &request.method[..] {
+        "GET" => {
+            let (sender, receiver) = mpsc::unbounded();
+            body_stream = Some(receiver);
+            let mut req = session.get_request();
+            req.get().set_path(&request.path);
+            req.get().init_context().set_response_stream(capnp_rpc::new_client(ResponseStream {
+                sender: RefCell::new(Some(sender)),
+            }));
+            req.send().promise.await?
+        }
+        "POST" => {
+            let mut req = session.post_request();
+            req.get().set_path(&request.path);
+            {
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(&request.body[..]);
+            }
+            req.get().init_context();
+            req.send().promise.await?
+        }
+        "PUT" => {
+            let mut req = session.put_request();
+            req.get().set_path(&request.path);
+            {
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(&request.body[..]);
+            }
+            req.get().init_context();
+            req.send().promise.await?
+        }
+        "DELETE" => {
+            let mut req = session.delete_request();
+            req.get().set_path(&request.path);
+            req.get().init_context();
+            req.send().promise.await?
+        }
+        _ => return Ok((http_response((405, "Method Not Allowed"), &[], b""), None)),
+    };
+    to_http(response.get()?, body_stream)
+}
+
+/// Receives the frames that the server sends to the browser.
+struct ClientStream {
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl web_socket_stream::Server for ClientStream {
+    async fn send_bytes(self: Rc<Self>,
+                        params: web_socket_stream::SendBytesParams,
+                        _results: web_socket_stream::SendBytesResults)
+                        -> Result<(), Error>
+    {
+        let message = params.get()?.get_message()?.to_vec();
+        self.sender.unbounded_send(message)
+            .map_err(|_| Error::disconnected("websocket connection is closed".into()))
+    }
+}
+
+/// The value of the Sec-WebSocket-Accept header that answers `key`, as in RFC 6455.
+fn web_socket_accept(key: &str) -> String {
+    let mut input = key.trim().as_bytes().to_vec();
+    input.extend_from_slice(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    base64::engine::general_purpose::STANDARD.encode(Sha1::digest(&input))
+}
+
+/// Hands the connection over to a websocket opened on `session`. Sandstorm passes raw
+/// websocket frames back and forth, so all we do is copy bytes.
+async fn bridge_web_socket<R, W>(session: web_session::Client,
+                                 request: Request,
+                                 mut reader: R,
+                                 mut writer: W)
+                                 -> Result<(), Error>
+    where R: AsyncRead + Unpin,
+          W: futures::AsyncWrite + Unpin
+{
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(k) => k.to_string(),
+        None => return Err(Error::failed("websocket request has no Sec-WebSocket-Key".into())),
+    };
+
+    let (sender, mut receiver) = mpsc::unbounded();
+    let client_stream: web_socket_stream::Client = capnp_rpc::new_client(ClientStream { sender });
+    let mut req = session.open_web_socket_request();
+    req.get().set_path(&request.path);
+    req.get().init_context();
+    req.get().set_client_stream(client_stream);
+    let response = req.send().promise.await?;
+    let server_stream = response.get()?.get_server_stream()?;
+
+    writer.write_all(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                              Upgrade: websocket\r\n\
+                              Connection: Upgrade\r\n\
+                              Sec-WebSocket-Accept: {}\r\n\r\n",
+                             web_socket_accept(&key)).as_bytes()).await?;
+
+    let outgoing = async move {
+        while let Some(bytes) = receiver.next().await {
+            writer.write_all(&bytes).await?;
+        }
+        Ok::<(), Error>(())
+    };
+    let incoming = async move {
+        let mut chunk = [0; 4096];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok::<(), Error>(());
+            }
+            let mut req = server_stream.send_bytes_request();
+            req.get().set_message(&chunk[..n]);
+            req.send().promise.await?;
+        }
+    };
+
+    match futures::future::select(Box::pin(incoming), Box::pin(outgoing)).await {
+        Either::Left((result, _)) | Either::Right((result, _)) => result,
+    }
+}
+
+async fn handle_connection(stream: tokio::net::TcpStream, dev: Rc<Dev>) -> Result<(), Error> {
+    let (mut reader, mut writer) =
+        tokio_util::compat::Tokio02AsyncReadCompatExt::compat(stream).split();
+    let request = match read_request(&mut reader).await? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let session = open_session(&dev, request.can_write()).await?;
+    if request.is_web_socket() {
+        return bridge_web_socket(session, request, reader, writer).await;
+    }
+
+    let (response, body_stream) = match respond(&session, &request).await {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{} /{} failed: {}", request.method, request.path, e);
+            (http_response((500, "Internal Server Error"),
+                           &[("Content-Type", "text/plain; charset=UTF-8".to_string())],
+                           format!("{}", e).as_bytes()),
+             None)
+        }
+    };
+    writer.write_all(&response).await?;
+    if let Some(mut body_stream) = body_stream {
+        while let Some(chunk) = body_stream.next().await {
+            writer.write_all(&chunk).await?;
+        }
+    }
+    writer.close().await?;
+    Ok(())
+}
+
+async fn serve(address: &str, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    // Tokens don't survive a restart, so keep new ones from colliding with those that
+    // earlier runs stored.
+    let started = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)?;
+    let token_prefix = format!("{}-", started.as_millis());
+    let sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+        capnp_rpc::new_client(FakeSandstormApi::with_token_prefix(&token_prefix));
+    let view = server::open_collection(&config, sandstorm_api)?;
+    let dev = Rc::new(Dev {
+        view: view,
+        context: capnp_rpc::new_client(FakeSessionContext::default()),
+        identity: new_identity("Dev User"),
+    });
+
+    let mut listener = tokio::net::TcpListener::bind(address).await?;
+    println!("serving the collection in {} at http://{}/",
+             config.storage_root.display(), address);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let dev = dev.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_connection(stream, dev).await {
+                println!("connection failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Runs the server outside of Sandstorm, listening on `address`. Unless configured
+/// otherwise, data goes into ./dev-storage and the assets come from ./spk, where
+/// `make dev-deps` puts them.
+pub fn main(address: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::from_env("dev-storage", "spk")?;
+    let mut rt = tokio::runtime::Runtime::new()?;
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&mut rt, serve(address, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_request, web_socket_accept};
+
+    #[test]
+    fn web_socket_accept_matches_rfc_6455() {
+        assert_eq!(web_socket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn read_request_with_body() {
+        let bytes = b"POST /description HTTP/1.1\r\n\
+                      Host: localhost\r\n\
+                      content-length: 5\r\n\r\n\
+                      helloGET / HTTP/1.1\r\n\r\n";
+        let mut reader = futures::io::Cursor::new(&bytes[..]);
+        let request = futures::executor::block_on(read_request(&mut reader)).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "description");
+        assert_eq!(request.header("Content-Length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+        assert!(request.can_write());
+    }
+
+    #[test]
+    fn read_request_at_end_of_stream() {
+        let mut reader = futures::io::Cursor::new(&b""[..]);
+        assert!(futures::executor::block_on(read_request(&mut reader)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Filesystem updates that survive a crash at any point: afterwards, each file holds either
+//! its old contents or all of its new contents, and each directory change is either fully
+//! done or not done at all.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+/// Flushes the entries of `directory` to disk, so that creations, renames and removals
+/// within it are not lost.
+pub fn sync_dir(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+/// Replaces `path` with a file whose contents are produced by `write`. The contents first go
+/// to a temporary file in `tmp_dir`, which must be on the same filesystem as `path` and whose
+/// leftovers the caller should clear out at startup. The temporary file is synced before it
+/// is renamed over `path`, and the parent directory of `path` is synced after.
+pub fn write_atomically<F>(tmp_dir: &Path, path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()>
+{
+    write_atomically_with(tmp_dir, path, write, |_| Ok(()))
+}
+
+/// `write_atomically()`, with `before_rename` called on the synced temporary file just before
+/// it is renamed. If it fails, this gives up on the spot, as a crash would.
+fn write_atomically_with<F, G>(tmp_dir: &Path, path: &Path, write: F, before_rename: G)
+                               -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()>, G: FnOnce(&Path) -> io::Result<()>
+{
+    let mut temp_name = match path.file_name() {
+        Some(n) => n.to_os_string(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("not a file path: {:?}", path))),
+    };
+    temp_name.push(".uploading");
+    let temp_path = tmp_dir.join(temp_name);
+
+    let mut file = File::create(&temp_path)?;
+    if let Err(e) = write(&mut file).and_then(|()| file.sync_all()) {
+        let _ = ::std::fs::remove_file(&temp_path);
+        return Err(e)
+    }
+    before_rename(&temp_path)?;
+    ::std::fs::rename(&temp_path, path)?;
+    sync_dir(parent(path))
+}
+
+pub fn write_bytes_atomically(tmp_dir: &Path, path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomically(tmp_dir, path, |f| f.write_all(contents))
+}
+
+/// Appends `contents` to `path`, creating it if needed. A crash partway through may leave part
+/// of `contents` behind, so logs go through `append_record()` instead.
+fn append(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let existed = path.exists();
+    let mut file = ::std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    if !existed {
+        sync_dir(parent(path))?;
+    }
+    Ok(())
+}
+
+/// Length of the header that `frame()` puts in front of a record: the record's length and its
+/// CRC-32, both as little-endian 32-bit numbers.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Returns `record` behind a header that lets `read_records()` tell whether it is complete and
+/// intact.
+pub fn frame(record: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(framed_len(record));
+    result.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    result.extend_from_slice(&::crc32fast::hash(record).to_le_bytes());
+    result.extend_from_slice(record);
+    result
+}
+
+/// How many bytes `frame(record)` takes up.
+pub fn framed_len(record: &[u8]) -> usize {
+    FRAME_HEADER_LEN + record.len()
+}
+
+/// Appends `record` to the log at `path`, framed. A crash partway through leaves a frame at the
+/// end that `read_records()` reports as torn, so the records before it are safe.
+pub fn append_record(path: &Path, record: &[u8]) -> io::Result<()> {
+    append(path, &frame(record))
+}
+
+/// What `read_records()` found in a log.
+pub struct Records<'a> {
+    /// Each intact record, along with where its frame starts.
+    pub records: Vec<(usize, &'a [u8])>,
+
+    /// Frames before the last one whose checksum doesn't match: where each one starts and how
+    /// long it is. The records after them are still read.
+    pub damaged: Vec<(usize, usize)>,
+
+    /// Where the last frame starts, if it is incomplete or doesn't match its checksum, as when
+    /// a crash interrupted `append_record()`.
+    pub torn: Option<usize>,
+}
+
+/// Splits a log written by `append_record()` into its records.
+pub fn read_records(log: &[u8]) -> Records {
+    let mut result = Records { records: Vec::new(), damaged: Vec::new(), torn: None };
+    let mut offset = 0;
+    while offset < log.len() {
+        let header = &log[offset..];
+        if header.len() < FRAME_HEADER_LEN {
+            result.torn = Some(offset);
+            break
+        }
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let end = match (offset + FRAME_HEADER_LEN).checked_add(len) {
+            Some(end) if end <= log.len() => end,
+            _ => {
+                result.torn = Some(offset);
+                break
+            }
+        };
+        let record = &log[offset + FRAME_HEADER_LEN..end];
+        if ::crc32fast::hash(record) == checksum {
+            result.records.push((offset, record));
+        } else if end == log.len() {
+            result.torn = Some(offset);
+        } else {
+            result.damaged.push((offset, end - offset));
+        }
+        offset = end;
+    }
+    result
+}
+
+/// A new file next to the log at `path` for bytes dropped from it, named after the log with
+/// `.damaged.<millis>` appended. Each time gets its own file, so that earlier ones are kept.
+fn damaged_path(path: &Path) -> io::Result<PathBuf> {
+    let timestamp = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .as_millis();
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let mut result = path.with_file_name(format!("{}.damaged.{}", name, timestamp));
+    let mut n = 1;
+    while result.exists() {
+        result = path.with_file_name(format!("{}.damaged.{}-{}", name, timestamp, n));
+        n += 1;
+    }
+    Ok(result)
+}
+
+/// Rewrites the log at `path`, whose contents are `log`, without the byte ranges in `dropped`,
+/// which are sorted and disjoint. The dropped bytes are saved first, in a new file next to the
+/// log, in case they turn out to matter.
+pub fn drop_from_log(tmp_dir: &Path, path: &Path, log: &[u8], dropped: &[(usize, usize)])
+                     -> io::Result<()>
+{
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    let mut start = 0;
+    for &(offset, len) in dropped {
+        kept.extend_from_slice(&log[start..offset]);
+        removed.extend_from_slice(&log[offset..offset + len]);
+        start = offset + len;
+    }
+    kept.extend_from_slice(&log[start..]);
+
+    write_bytes_atomically(tmp_dir, &damaged_path(path)?, &removed)?;
+    write_bytes_atomically(tmp_dir, path, &kept)
+}
+
+pub fn remove_file(path: &Path) -> io::Result<()> {
+    ::std::fs::remove_file(path)?;
+    sync_dir(parent(path))
+}
+
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    ::std::fs::rename(from, to)?;
+    sync_dir(parent(to))?;
+    if parent(from) != parent(to) {
+        sync_dir(parent(from))?;
+    }
+    Ok(())
+}
+
+pub fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, link)?;
+    sync_dir(parent(link))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, read_records, write_atomically, write_atomically_with, write_bytes_atomically};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+
+    use crate::test_util::{TempDir, metadata_bytes, title_of};
+
+    /// Creates fresh, empty data and temporary directories.
+    fn temp_dirs(name: &str) -> (TempDir, PathBuf, PathBuf) {
+        let base = TempDir::new(&format!("durable-{}", name));
+        let (data, tmp) = (base.join("data"), base.join("tmp"));
+        ::std::fs::create_dir_all(&data).unwrap();
+        ::std::fs::create_dir_all(&tmp).unwrap();
+        (base, data, tmp)
+    }
+
+    /// Reads `path` the way `SavedUiViewSet::new()` does on restart.
+    fn read_title(path: &Path) -> String {
+        title_of(&::std::fs::read(path).unwrap())
+    }
+    #[test]
+    fn failed_write_leaves_old_contents() {
+        let (_base, data, tmp) = temp_dirs("failed-write");
+        let path = data.join("entry");
+        write_bytes_atomically(&tmp, &path, &metadata_bytes("old")).unwrap();
+
+        // Give up after every possible prefix of the new contents.
+        let new = metadata_bytes("a considerably longer new title");
+        for len in 0..new.len() {
+            let result = write_atomically(&tmp, &path, |f| {
+                f.write_all(&new[..len])?;
+                Err(io::Error::new(io::ErrorKind::Other, "interrupted"))
+            });
+            assert!(result.is_err());
+            assert_eq!(read_title(&path), "old");
+        }
+        assert!(::std::fs::read_dir(&tmp).unwrap().next().is_none());
+
+        write_bytes_atomically(&tmp, &path, &new).unwrap();
+        assert_eq!(read_title(&path), "a considerably longer new title");
+    }
+
+    #[test]
+    fn crash_before_rename_leaves_old_contents() {
+        let (_base, data, tmp) = temp_dirs("crash");
+        let path = data.join("entry");
+        write_bytes_atomically(&tmp, &path, &metadata_bytes("old")).unwrap();
+
+        // A crash after the new contents are synced, but before they are renamed into place,
+        // leaves them behind in `tmp`, which startup clears out.
+        let new = metadata_bytes("new");
+        let result = write_atomically_with(&tmp, &path, |f| f.write_all(&new), |temp_path| {
+            assert_eq!(read_title(temp_path), "new");
+            Err(io::Error::new(io::ErrorKind::Other, "crashed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(read_title(&path), "old");
+        assert_eq!(::std::fs::read_dir(&data).unwrap().count(), 1);
+        assert_eq!(read_title(&tmp.join("entry.uploading")), "new");
+        ::std::fs::remove_dir_all(&tmp).unwrap();
+        ::std::fs::create_dir_all(&tmp).unwrap();
+
+        write_bytes_atomically(&tmp, &path, &new).unwrap();
+        assert_eq!(read_title(&path), "new");
+    }
+
+    #[test]
+    fn read_records_skips_damage() {
+        let mut log = Vec::new();
+        for record in &[&b"first"[..], b"second", b"third"] {
+            log.extend(frame(record));
+        }
+        let second = frame(b"first").len();
+        let third = second + frame(b"second").len();
+
+        // A damaged record in the middle doesn't hide the ones after it.
+        let mut damaged = log.clone();
+        damaged[second + 9] ^= 0xff;
+        let records = read_records(&damaged);
+        assert_eq!(records.records, vec![(0, &b"first"[..]), (third, &b"third"[..])]);
+        assert_eq!(records.damaged, vec![(second, third - second)]);
+        assert_eq!(records.torn, None);
+
+        // Neither does a damaged last record, but it counts as torn.
+        let mut damaged = log.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        let records = read_records(&damaged);
+        assert_eq!(records.records.len(), 2);
+        assert!(records.damaged.is_empty());
+        assert_eq!(records.torn, Some(third));
+
+        // Every way of cutting the log short keeps the complete records.
+        for len in third..log.len() {
+            let records = read_records(&log[..len]);
+            assert_eq!(records.records.len(), 2);
+            assert_eq!(records.torn, if len == third { None } else { Some(third) });
+        }
+    }
+
+    #[test]
+    fn write_creates_missing_file() {
+        let (_base, data, tmp) = temp_dirs("create");
+        let path = data.join("entry");
+        write_bytes_atomically(&tmp, &path, &metadata_bytes("first")).unwrap();
+        assert_eq!(read_title(&path), "first");
+        assert!(::std::fs::read_dir(&tmp).unwrap().next().is_none());
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Stores the entries of a collection in two files: a snapshot of every entry as of the last
+//! compaction, and a log of the batches of changes made since then. Each batch is appended as
+//! a single framed record (see `durable::append_record()`), so it takes effect all at once.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::collections_capnp::{entry_log_record, ui_view_metadata};
+use crate::durable;
+
+/// The log gets compacted once it has this many records and more records than there are entries.
+const COMPACT_AFTER_RECORDS: usize = 1000;
+
+pub enum Change {
+    /// Adds or replaces an entry. `metadata` is a serialized `UiViewMetadata` message.
+    Put { token: String, metadata: Vec<u8> },
+    Remove { token: String },
+}
+
+pub struct EntryStore {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    tmp_dir: PathBuf,
+
+    /// Number of records in the log.
+    log_records: usize,
+
+    /// The current metadata of each entry, keyed by token, as serialized `UiViewMetadata` messages.
+    entries: HashMap<String, Vec<u8>>,
+
+    /// Entries whose metadata can't be read, keyed by token. See `Contents::unreadable`.
+    unreadable: HashMap<String, ::capnp::Error>,
+}
+
+/// Turns a serialized `UiViewMetadata` message into a standalone one, validating it on the way.
+fn copy_metadata(metadata: ui_view_metadata::Reader) -> ::capnp::Result<Vec<u8>> {
+    let mut message = ::capnp::message::Builder::new_default();
+    message.set_root(metadata)?;
+    Ok(::capnp::serialize::write_message_to_words(&message))
+}
+
+/// Reads the changes of the record in `bytes`. A change whose metadata can't be read comes back
+/// as an error along with its token, so that one bad entry doesn't take the others with it.
+fn read_record(bytes: &[u8]) -> ::capnp::Result<Vec<Result<Change, (String, ::capnp::Error)>>> {
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+    let record: entry_log_record::Reader = message.get_root()?;
+    let mut result = Vec::new();
+    for change in record.get_changes()? {
+        let token = change.get_token()?.to_string()?;
+        result.push(match change.which() {
+            Ok(entry_log_record::change::Put(metadata)) => {
+                match metadata.and_then(copy_metadata) {
+                    Ok(metadata) => Ok(Change::Put { token: token, metadata: metadata }),
+                    Err(e) => Err((token, e)),
+                }
+            }
+            Ok(entry_log_record::change::Remove(())) => Ok(Change::Remove { token: token }),
+            Err(e) => Err((token, e.into())),
+        });
+    }
+    Ok(result)
+}
+
+fn write_record<'a, I>(changes: I) -> ::capnp::Result<Vec<u8>>
+    where I: ExactSizeIterator<Item = &'a Change>
+{
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let record: entry_log_record::Builder = message.init_root();
+        let mut list = record.init_changes(changes.len() as u32);
+        for (i, change) in changes.enumerate() {
+            let mut builder = list.reborrow().get(i as u32);
+            match change {
+                &Change::Put { ref token, ref metadata } => {
+                    builder.set_token(&token[..]);
+                    let metadata_message =
+                        ::capnp::serialize::read_message(&mut &metadata[..], Default::default())?;
+                    builder.set_put(metadata_message.get_root::<ui_view_metadata::Reader>()?)?;
+                }
+                &Change::Remove { ref token } => {
+                    builder.set_token(&token[..]);
+                    builder.set_remove(());
+                }
+            }
+        }
+    }
+    Ok(::capnp::serialize::write_message_to_words(&message))
+}
+
+fn apply_to(entries: &mut HashMap<String, Vec<u8>>, changes: Vec<Change>) {
+    for change in changes {
+        match change {
+            Change::Put { token, metadata } => { entries.insert(token, metadata); }
+            Change::Remove { token } => { entries.remove(&token); }
+        }
+    }
+}
+
+/// What `read()` found in a store's directory.
+pub struct Contents {
+    /// The metadata of each entry, keyed by token, as serialized `UiViewMetadata` messages.
+    pub entries: HashMap<String, Vec<u8>>,
+
+    /// Entries whose latest change can't be read, keyed by token, with the reason. They are not
+    /// in `entries`; it's up to the caller to set them aside and remove them.
+    pub unreadable: HashMap<String, ::capnp::Error>,
+
+    /// Number of records in the log that were applied.
+    pub log_records: usize,
+
+    /// Records in the middle of the log that are damaged or can't be read, and were skipped:
+    /// where each one starts, how long it is, and why it was skipped.
+    pub damaged: Vec<(usize, usize, ::capnp::Error)>,
+
+    /// If the log ends with a record that was cut short by a crash, where that record starts.
+    pub torn: Option<usize>,
+}
+
+impl Contents {
+    fn apply(&mut self, changes: Vec<Result<Change, (String, ::capnp::Error)>>) {
+        for change in changes {
+            match change {
+                Ok(change) => {
+                    match change {
+                        Change::Put { ref token, .. } | Change::Remove { ref token } => {
+                            self.unreadable.remove(token);
+                        }
+                    }
+                    apply_to(&mut self.entries, vec![change]);
+                }
+                Err((token, e)) => {
+                    self.entries.remove(&token);
+                    self.unreadable.insert(token, e);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the store in `directory` without changing anything.
+pub fn read<P>(directory: P) -> ::capnp::Result<Contents>
+    where P: AsRef<Path>
+{
+    let mut contents = Contents {
+        entries: HashMap::new(),
+        unreadable: HashMap::new(),
+        log_records: 0,
+        damaged: Vec::new(),
+        torn: None,
+    };
+
+    match ::std::fs::read(directory.as_ref().join("snapshot")) {
+        Ok(bytes) => contents.apply(read_record(&bytes)?),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let log = match ::std::fs::read(directory.as_ref().join("log")) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let records = durable::read_records(&log);
+    for (offset, record) in records.records {
+        match read_record(record) {
+            Ok(changes) => {
+                contents.apply(changes);
+                contents.log_records += 1;
+            }
+            Err(e) => contents.damaged.push((offset, durable::framed_len(record), e)),
+        }
+    }
+    for (offset, len) in records.damaged {
+        contents.damaged.push((offset, len, ::capnp::Error::failed("checksum mismatch".into())));
+    }
+    contents.damaged.sort_by_key(|&(offset, _, _)| offset);
+    contents.torn = records.torn;
+    Ok(contents)
+}
+
+impl EntryStore {
+    /// Opens the store in `directory`, creating it if needed. `tmp_dir` is where new snapshots
+    /// are written before they replace the old one.
+    ///
+    /// A record at the end of the log that was cut short by a crash while it was being appended
+    /// is dropped. So are damaged records elsewhere in the log, but the records around them are
+    /// kept. The dropped bytes are kept in a new `log.damaged.<millis>` file in case they turn
+    /// out to matter.
+    pub fn open<P1, P2>(directory: P1, tmp_dir: P2) -> ::capnp::Result<EntryStore>
+        where P1: AsRef<Path>, P2: AsRef<Path>
+    {
+        ::std::fs::create_dir_all(&directory)?;
+        let contents = read(&directory)?;
+        let store = EntryStore {
+            snapshot_path: directory.as_ref().join("snapshot"),
+            log_path: directory.as_ref().join("log"),
+            tmp_dir: tmp_dir.as_ref().to_path_buf(),
+            log_records: contents.log_records,
+            entries: contents.entries,
+            unreadable: contents.unreadable,
+        };
+
+        if contents.torn.is_some() || !contents.damaged.is_empty() {
+            let log = ::std::fs::read(&store.log_path)?;
+            let mut dropped: Vec<(usize, usize)> = contents.damaged.iter()
+                .map(|&(offset, len, ref e)| {
+                    println!("dropping the damaged record at byte {} of {:?}: {}",
+                             offset, store.log_path, e);
+                    (offset, len)
+                })
+                .collect();
+            if let Some(offset) = contents.torn {
+                println!("dropping the last {} bytes of {:?}, which were cut short",
+                         log.len() - offset, store.log_path);
+                dropped.push((offset, log.len() - offset));
+            }
+            durable::drop_from_log(&store.tmp_dir, &store.log_path, &log, &dropped)?;
+        }
+
+        Ok(store)
+    }
+
+    /// The current metadata of each entry, keyed by token, as serialized `UiViewMetadata` messages.
+    pub fn entries(&self) -> &HashMap<String, Vec<u8>> {
+        &self.entries
+    }
+
+    /// Entries whose metadata couldn't be read when the store was opened, with the reason. They
+    /// stay here until a change to them is applied.
+    pub fn unreadable(&self) -> &HashMap<String, ::capnp::Error> {
+        &self.unreadable
+    }
+
+    /// Durably applies `changes`, all at once. Compacts the log if it has grown large.
+    pub fn apply(&mut self, changes: Vec<Change>) -> ::capnp::Result<()> {
+        if changes.is_empty() {
+            return Ok(())
+        }
+        let record = write_record(changes.iter())?;
+        durable::append_record(&self.log_path, &record)?;
+        for change in &changes {
+            match change {
+                &Change::Put { ref token, .. } | &Change::Remove { ref token } => {
+                    self.unreadable.remove(token);
+                }
+            }
+        }
+        apply_to(&mut self.entries, changes);
+        self.log_records += 1;
+
+        if self.log_records >= COMPACT_AFTER_RECORDS && self.log_records > self.entries.len() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every entry into a new snapshot and empties the log. A crash in between leaves
+    /// the old log in place, which is harmless: replaying it on top of the new snapshot ends up
+    /// in the same state.
+    pub fn compact(&mut self) -> ::capnp::Result<()> {
+        let mut tokens: Vec<&String> = self.entries.keys().collect();
+        tokens.sort();
+        let changes: Vec<Change> = tokens.into_iter().map(|t| Change::Put {
+            token: t.clone(),
+            metadata: self.entries[t].clone(),
+        }).collect();
+        let snapshot = write_record(changes.iter())?;
+        durable::write_bytes_atomically(&self.tmp_dir, &self.snapshot_path, &snapshot)?;
+        durable::write_bytes_atomically(&self.tmp_dir, &self.log_path, b"")?;
+        self.log_records = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, EntryStore};
+    use std::path::{Path, PathBuf};
+
+    use crate::test_util::{TempDir, metadata_bytes, titles};
+
+    /// Creates fresh, empty store and temporary directories.
+    fn temp_dirs(name: &str) -> (TempDir, PathBuf, PathBuf) {
+        let base = TempDir::new(&format!("entry-store-{}", name));
+        let (store, tmp) = (base.join("entries"), base.join("tmp"));
+        ::std::fs::create_dir_all(&tmp).unwrap();
+        (base, store, tmp)
+    }
+
+    fn put(token: &str, title: &str) -> Change {
+        Change::Put { token: token.to_string(), metadata: metadata_bytes(title) }
+    }
+
+    fn remove(token: &str) -> Change {
+        Change::Remove { token: token.to_string() }
+    }
+
+    fn damaged_files(dir: &Path) -> Vec<PathBuf> {
+        ::std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains("log.damaged."))
+            .collect()
+    }
+
+    fn expected(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(t, title)| (t.to_string(), title.to_string())).collect()
+    }
+
+    #[test]
+    fn reopening_replays_the_log() {
+        let (_base, dir, tmp) = temp_dirs("replay");
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "first"), put("b", "second")]).unwrap();
+            store.apply(vec![remove("a"), put("b", "renamed"), put("c", "third")]).unwrap();
+        }
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("b", "renamed"), ("c", "third")]));
+    }
+
+    #[test]
+    fn torn_record_at_end_of_log_is_dropped() {
+        let (_base, dir, tmp) = temp_dirs("torn");
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "first")]).unwrap();
+        }
+        let complete = ::std::fs::read(dir.join("log")).unwrap();
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "changed"), put("b", "second")]).unwrap();
+        }
+        let log = ::std::fs::read(dir.join("log")).unwrap();
+
+        // Cut the second record short at every possible point. None of it may take effect.
+        for len in complete.len()..log.len() {
+            ::std::fs::write(dir.join("log"), &log[..len]).unwrap();
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            assert_eq!(titles(&store), expected(&[("a", "first")]));
+
+            // The torn record must not swallow the next one.
+            store.apply(vec![put("c", "third")]).unwrap();
+            let store = EntryStore::open(&dir, &tmp).unwrap();
+            assert_eq!(titles(&store), expected(&[("a", "first"), ("c", "third")]));
+            ::std::fs::write(dir.join("log"), &complete).unwrap();
+        }
+    }
+
+    #[test]
+    fn damaged_record_in_the_middle_is_skipped() {
+        let (_base, dir, tmp) = temp_dirs("damaged");
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "first")]).unwrap();
+            store.apply(vec![put("b", "second")]).unwrap();
+            store.apply(vec![put("c", "third")]).unwrap();
+        }
+        let mut log = ::std::fs::read(dir.join("log")).unwrap();
+        let records = ::crate::durable::read_records(&log);
+        let (second, _) = records.records[1];
+        log[second + 20] ^= 0xff;
+        ::std::fs::write(dir.join("log"), &log).unwrap();
+
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("a", "first"), ("c", "third")]));
+        drop(store);
+
+        // The damaged record was moved aside, in a file of its own.
+        let damaged = damaged_files(&dir);
+        assert_eq!(damaged.len(), 1);
+        assert_eq!(::std::fs::read(&damaged[0]).unwrap().len(), records.records[2].0 - second);
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("a", "first"), ("c", "third")]));
+        assert_eq!(damaged_files(&dir).len(), 1);
+    }
+
+    #[test]
+    fn unreadable_entry_in_snapshot_is_set_aside() {
+        let (_base, dir, tmp) = temp_dirs("unreadable");
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "first"), put("b", "second")]).unwrap();
+            store.compact().unwrap();
+        }
+
+        // Point the title of "b" far past the end of the message.
+        let mut snapshot = ::std::fs::read(dir.join("snapshot")).unwrap();
+        let title = snapshot.windows(6).position(|w| w == b"second").unwrap();
+        let pointer = (1..=title / 8).map(|words| title - 8 * words)
+            .find(|&i| snapshot[i] & 3 == 1).unwrap();
+        snapshot[pointer + 2] = 0xff;
+        snapshot[pointer + 3] = 0x7f;
+        ::std::fs::write(dir.join("snapshot"), &snapshot).unwrap();
+
+        let mut store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("a", "first")]));
+        assert!(store.unreadable().contains_key("b"));
+
+        store.apply(vec![remove("b")]).unwrap();
+        assert!(store.unreadable().is_empty());
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert!(store.unreadable().is_empty());
+    }
+
+    #[test]
+    fn compaction_keeps_every_entry() {
+        let (_base, dir, tmp) = temp_dirs("compact");
+        {
+            let mut store = EntryStore::open(&dir, &tmp).unwrap();
+            store.apply(vec![put("a", "first"), put("b", "second")]).unwrap();
+            store.apply(vec![remove("b")]).unwrap();
+            store.compact().unwrap();
+            assert_eq!(::std::fs::metadata(dir.join("log")).unwrap().len(), 0);
+            store.apply(vec![put("c", "third")]).unwrap();
+        }
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("a", "first"), ("c", "third")]));
+    }
+
+    #[test]
+    fn log_replayed_over_newer_snapshot_is_harmless() {
+        let (_base, dir, tmp) = temp_dirs("crash-during-compaction");
+        let mut store = EntryStore::open(&dir, &tmp).unwrap();
+        store.apply(vec![put("a", "first"), put("b", "second")]).unwrap();
+        store.apply(vec![remove("a")]).unwrap();
+        let log = ::std::fs::read(dir.join("log")).unwrap();
+        store.compact().unwrap();
+
+        // Simulate a crash after the snapshot was replaced but before the log was emptied.
+        ::std::fs::write(dir.join("log"), &log).unwrap();
+        let store = EntryStore::open(&dir, &tmp).unwrap();
+        assert_eq!(titles(&store), expected(&[("b", "second")]));
+    }
+}
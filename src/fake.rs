@@ -0,0 +1,304 @@
+// Copyright (c) 2014-2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! In-memory stand-ins for the capabilities that Sandstorm hands a grain, so that a
+//! collection can run outside of Sandstorm. Used by the standalone dev mode and by tests.
+
+use capnp::Error;
+use capnp::private::capability::ClientHook;
+use futures::channel::oneshot;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::collections_capnp::titled_view;
+
+use sandstorm::identity_capnp::{identity};
+use sandstorm::grain_capnp::{session_context, ui_view, sandstorm_api};
+use sandstorm::util_capnp::{static_asset};
+
+/// `save` hands out tokens for capabilities that `restore` gives back. Nothing outlives
+/// the process. Restores of some tokens can be held open, like a slow backend.
+#[derive(Default)]
+pub struct FakeSandstormApi {
+    saved: RefCell<HashMap<Vec<u8>, Box<dyn ClientHook>>>,
+    token_prefix: String,
+    next_token: Cell<u64>,
+
+    /// Tokens whose restores wait for `release_restores()`.
+    holding: RefCell<HashSet<Vec<u8>>>,
+
+    /// Restores that are waiting, oldest first.
+    held: RefCell<Vec<(Vec<u8>, oneshot::Sender<()>)>>,
+
+    /// Tokens of all restores so far, in the order they were called.
+    restored: RefCell<Vec<Vec<u8>>>,
+}
+
+impl FakeSandstormApi {
+    /// Starts every token with `prefix`, so that tokens from different runs don't collide.
+    pub fn with_token_prefix(prefix: &str) -> FakeSandstormApi {
+        FakeSandstormApi {
+            token_prefix: prefix.into(),
+            .. Default::default()
+        }
+    }
+
+    /// How many saved capabilities haven't been dropped.
+    pub fn saved_count(&self) -> usize {
+        self.saved.borrow().len()
+    }
+
+    /// Makes restores of `token` wait until `release_restores()` is called for it.
+    pub fn hold_restores(&self, token: &[u8]) {
+        self.holding.borrow_mut().insert(token.to_vec());
+    }
+
+    /// Lets the waiting restores of `token`, and later ones, go through.
+    pub fn release_restores(&self, token: &[u8]) {
+        self.holding.borrow_mut().remove(token);
+        let held: Vec<_> = self.held.borrow_mut().drain(..).collect();
+        for (t, sender) in held {
+            if t == token {
+                let _ = sender.send(());
+            } else {
+                self.held.borrow_mut().push((t, sender));
+            }
+        }
+    }
+
+    /// Tokens of the restores that are waiting, oldest first.
+    pub fn held_restores(&self) -> Vec<Vec<u8>> {
+        self.held.borrow().iter().map(|(t, _)| t.clone()).collect()
+    }
+
+    /// Tokens of all restores so far, in the order they were called.
+    pub fn restored(&self) -> Vec<Vec<u8>> {
+        self.restored.borrow().clone()
+    }
+}
+
+impl sandstorm_api::Server<::capnp::any_pointer::Owned> for FakeSandstormApi {
+    async fn save(self: Rc<Self>,
+                  params: sandstorm_api::SaveParams<::capnp::any_pointer::Owned>,
+                  mut results: sandstorm_api::SaveResults<::capnp::any_pointer::Owned>)
+                  -> Result<(), Error>
+    {
+        let cap: ::capnp::capability::Client = params.get()?.get_cap().get_as_capability()?;
+        let token = format!("{}token-{}", self.token_prefix, self.next_token.get()).into_bytes();
+        self.next_token.set(self.next_token.get() + 1);
+        self.saved.borrow_mut().insert(token.clone(), cap.hook);
+        results.get().set_token(&token[..]);
+        Ok(())
+    }
+
+    async fn restore(self: Rc<Self>,
+                     params: sandstorm_api::RestoreParams<::capnp::any_pointer::Owned>,
+                     mut results: sandstorm_api::RestoreResults<::capnp::any_pointer::Owned>)
+                     -> Result<(), Error>
+    {
+        let token = params.get()?.get_token()?.to_vec();
+        self.restored.borrow_mut().push(token.clone());
+        if self.holding.borrow().contains(&token) {
+            let (sender, receiver) = oneshot::channel();
+            self.held.borrow_mut().push((token.clone(), sender));
+            let _ = receiver.await;
+        }
+        match self.saved.borrow().get(&token) {
+            Some(hook) => {
+                results.get().init_cap().set_as_capability(hook.add_ref());
+                Ok(())
+            }
+            None => Err(Error::failed(format!("no such token: {:?}", token))),
+        }
+    }
+
+    async fn drop(self: Rc<Self>,
+                  params: sandstorm_api::DropParams<::capnp::any_pointer::Owned>,
+                  _results: sandstorm_api::DropResults<::capnp::any_pointer::Owned>)
+                  -> Result<(), Error>
+    {
+        let token = params.get()?.get_token()?;
+        match self.saved.borrow_mut().remove(token) {
+            Some(_) => Ok(()),
+            None => Err(Error::failed(format!("no such token: {:?}", token))),
+        }
+    }
+}
+
+pub struct FakeIdentity {
+    name: String,
+}
+
+impl identity::Server for FakeIdentity {
+    async fn get_profile(self: Rc<Self>,
+                         _params: identity::GetProfileParams,
+                         mut results: identity::GetProfileResults)
+                         -> Result<(), Error>
+    {
+        let mut profile = results.get().init_profile();
+        profile.reborrow().init_display_name().set_default_text(&self.name);
+        profile.set_picture(new_icon("pictures.example.com/user.png"));
+        Ok(())
+    }
+}
+
+pub fn new_identity(name: &str) -> identity::Client {
+    capnp_rpc::new_client(FakeIdentity { name: name.into() })
+}
+
+pub struct FakeStaticAsset {
+    host_path: String,
+}
+
+impl static_asset::Server for FakeStaticAsset {
+    async fn get_url(self: Rc<Self>,
+                     _params: static_asset::GetUrlParams,
+                     mut results: static_asset::GetUrlResults)
+                     -> Result<(), Error>
+    {
+        let mut results = results.get();
+        results.set_protocol(static_asset::Protocol::Https);
+        results.set_host_path(&self.host_path);
+        Ok(())
+    }
+}
+
+/// A grain icon served from `host_path`.
+pub fn new_icon(host_path: &str) -> static_asset::Client {
+    capnp_rpc::new_client(FakeStaticAsset { host_path: host_path.into() })
+}
+
+/// The main view of a grain, as it would come out of the powerbox. Its title can be
+/// changed after the fact, like a grain being renamed, and it can be made to fail, like a
+/// grain that is restarting.
+pub struct FakeUiView {
+    app_title: String,
+    title: RefCell<String>,
+    failure: RefCell<Option<Error>>,
+}
+
+impl FakeUiView {
+    pub fn new(app_title: &str, title: &str) -> Rc<FakeUiView> {
+        Rc::new(FakeUiView {
+            app_title: app_title.into(),
+            title: RefCell::new(title.into()),
+            failure: RefCell::new(None),
+        })
+    }
+
+    pub fn client(self: &Rc<Self>) -> ui_view::Client {
+        let client: titled_view::Client = capnp_rpc::new_client_from_rc(self.clone());
+        ui_view::Client { client: client.client }
+    }
+
+    pub fn set_title(&self, title: &str) {
+        *self.title.borrow_mut() = title.into();
+    }
+
+    /// Makes `getViewInfo()` fail with `error` from now on, or work again if it's `None`.
+    pub fn fail_with(&self, error: Option<Error>) {
+        *self.failure.borrow_mut() = error;
+    }
+}
+
+impl ui_view::Server for FakeUiView {
+    async fn get_view_info(self: Rc<Self>,
+                           _params: ui_view::GetViewInfoParams,
+                           mut results: ui_view::GetViewInfoResults)
+                           -> Result<(), Error>
+    {
+        if let Some(ref e) = *self.failure.borrow() {
+            return Err(e.clone())
+        }
+        let mut view_info = results.get();
+        view_info.reborrow().init_app_title().set_default_text(&self.app_title);
+        view_info.set_grain_icon(new_icon(&format!("icons.example.com/{}.svg", self.app_title)));
+        Ok(())
+    }
+}
+
+impl titled_view::Server for FakeUiView {
+    async fn get_title(self: Rc<Self>,
+                       _params: titled_view::GetTitleParams,
+                       mut results: titled_view::GetTitleResults)
+                       -> Result<(), Error>
+    {
+        results.get().set_title(self.title.borrow().as_str());
+        Ok(())
+    }
+}
+
+/// Stands in for the Sandstorm shell around a session. The powerbox is stubbed out: a
+/// request token claims whatever view was registered for it with `add_claimable()`, or
+/// else a new `FakeUiView` titled after the token.
+#[derive(Default)]
+pub struct FakeSessionContext {
+    claimable: RefCell<HashMap<String, ui_view::Client>>,
+
+    /// Event type indices of the activities posted so far, oldest first.
+    activities: RefCell<Vec<u16>>,
+}
+
+impl FakeSessionContext {
+    pub fn add_claimable(&self, request_token: &str, view: ui_view::Client) {
+        self.claimable.borrow_mut().insert(request_token.into(), view);
+    }
+
+    pub fn activities(&self) -> Vec<u16> {
+        self.activities.borrow().clone()
+    }
+}
+
+impl session_context::Server for FakeSessionContext {
+    async fn claim_request(self: Rc<Self>,
+                           params: session_context::ClaimRequestParams,
+                           mut results: session_context::ClaimRequestResults)
+                           -> Result<(), Error>
+    {
+        let request_token = params.get()?.get_request_token()?.to_str()?.to_string();
+        let view = match self.claimable.borrow_mut().remove(&request_token) {
+            Some(view) => view,
+            None => FakeUiView::new("Fake App", &request_token).client(),
+        };
+        results.get().init_cap().set_as_capability(view.client.hook);
+        Ok(())
+    }
+
+    async fn activity(self: Rc<Self>,
+                      params: session_context::ActivityParams,
+                      _results: session_context::ActivityResults)
+                      -> Result<(), Error>
+    {
+        let event_type = params.get()?.get_event()?.get_type();
+        self.activities.borrow_mut().push(event_type);
+        Ok(())
+    }
+
+    async fn offer(self: Rc<Self>,
+                   _params: session_context::OfferParams,
+                   _results: session_context::OfferResults)
+                   -> Result<(), Error>
+    {
+        // There is no one to offer anything to.
+        Ok(())
+    }
+}
@@ -24,12 +24,72 @@ use capnp::Error;
 use futures::{FutureExt, TryFutureExt};
 use url::percent_encoding;
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::rc::Rc;
 
 use sandstorm::identity_capnp::{identity};
 use sandstorm::grain_capnp::{sandstorm_api};
 
-fn read_sturdyref_symlink(pointed_to: ::std::path::PathBuf) -> Result<Vec<u8>, Error>
+use crate::durable;
+
+/// Whether `e` is how Sandstorm fails to drop or restore a token that doesn't exist, as when an
+/// earlier drop went through but the grain died before it could record that.
+pub fn is_no_such_token(e: &Error) -> bool {
+    format!("{}", e).to_lowercase().contains("no such token")
+}
+
+/// The ID of a Sandstorm identity, as found in `UserInfo.identityId`: a 256-bit hash.
+/// In text form, e.g. in `UiViewMetadata.addedBy`, it is encoded as 64 hexadecimal digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IdentityId([u8; 32]);
+
+impl IdentityId {
+    pub fn from_bytes(bytes: &[u8]) -> Result<IdentityId, Error> {
+        if bytes.len() != 32 {
+            return Err(Error::failed(format!("invalid identity ID {:?}", bytes)))
+        }
+        let mut result = [0; 32];
+        result.copy_from_slice(bytes);
+        Ok(IdentityId(result))
+    }
+
+    pub fn parse(text: &str) -> Result<IdentityId, Error> {
+        let bytes = match ::hex::decode(text) {
+            Ok(b) => b,
+            Err(_) => return Err(Error::failed(format!("invalid identity ID {}", text))),
+        };
+        if bytes.len() != 32 {
+            return Err(Error::failed(format!("invalid identity ID {}", text)))
+        }
+        IdentityId::from_bytes(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The name under which `IdentityMap` stores the identity: the first 128 bits, in hex.
+    /// That's plenty to avoid collisions, and keeps file names short.
+    pub fn truncated_text(&self) -> String {
+        ::hex::encode(&self.0[..16])
+    }
+}
+
+impl ::std::fmt::Display for IdentityId {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}", ::hex::encode(&self.0))
+    }
+}
+
+impl ::std::str::FromStr for IdentityId {
+    type Err = Error;
+    fn from_str(text: &str) -> Result<IdentityId, Error> {
+        IdentityId::parse(text)
+    }
+}
+
+/// Decodes the token that an identity symlink points to.
+pub fn read_sturdyref_symlink(pointed_to: ::std::path::PathBuf) -> Result<Vec<u8>, Error>
 {
     let encoded_sturdyref = match pointed_to.to_str() {
         Some(s) => s.to_string(),
@@ -47,6 +107,14 @@ fn read_sturdyref_symlink(pointed_to: ::std::path::PathBuf) -> Result<Vec<u8>, E
 }
 
 
+/// How many times we try to drop a trashed sturdyref before giving up until the next startup.
+const DROP_ATTEMPTS: u32 = 8;
+
+/// Delay before the first retry of a failed drop. Doubles after each attempt.
+const DROP_RETRY_INITIAL_DELAY: ::std::time::Duration = ::std::time::Duration::from_secs(1);
+
+const DROP_RETRY_MAX_DELAY: ::std::time::Duration = ::std::time::Duration::from_secs(10 * 60);
+
 struct Reaper;
 
 impl ::multipoll::Finisher<Error> for Reaper {
@@ -108,8 +176,7 @@ impl IdentityMapInner {
 
                IdentityMapInner::drop_identity(&inner1, &symlink)?;
 
-               ::std::os::unix::fs::symlink(encoded_token, symlink)?;
-               // TODO fsync?
+               durable::symlink(::std::path::Path::new(&encoded_token), &symlink)?;
 
                Ok(())
            }
@@ -126,25 +193,63 @@ impl IdentityMapInner {
                 // symlink exists!
                 let mut trash_file = inner.borrow().trash_directory.clone();
                 trash_file.push(&pointed_to);
-                ::std::fs::rename(symlink, &trash_file)?;
+                durable::rename(symlink.as_ref(), &trash_file)?;
 
-                let mut req = inner.borrow().api.drop_request();
-                let sturdyref = read_sturdyref_symlink(pointed_to)?;
-                req.get().set_token(&sturdyref[..]);
-                inner.borrow_mut().tasks.add(req.send().promise.map(move |r| match r {
-                    Ok(_) => {
-                        ::std::fs::remove_file(trash_file)?;
-                        // TODO fsync?
-                        Ok(())
-                    }
-                    Err(e) => Err(e),
-                }));
+                let task = IdentityMapInner::drop_trashed(inner, trash_file);
+                inner.borrow_mut().tasks.add(task);
 
                 Ok(())
             }
             _ => Ok(()),
         }
     }
+
+    /// Drops the sturdyref that the symlink `trash_file` points to, retrying with exponential
+    /// backoff, and then deletes `trash_file`. If all attempts fail, `trash_file` stays in
+    /// place so that `IdentityMap::new()` tries again on the next startup. A sturdyref that
+    /// is already gone counts as dropped.
+    fn drop_trashed(inner: &Rc<RefCell<IdentityMapInner>>,
+                    trash_file: ::std::path::PathBuf) -> Promise<(), Error>
+    {
+        let inner = inner.clone();
+        Promise::from_future(async move {
+            let sturdyref = read_sturdyref_symlink(::std::fs::read_link(&trash_file)?)?;
+            let mut delay = DROP_RETRY_INITIAL_DELAY;
+            let mut attempt = 1;
+            loop {
+                let mut req = inner.borrow().api.drop_request();
+                req.get().set_token(&sturdyref[..]);
+                match req.send().promise.await {
+                    Ok(_) => {
+                        durable::remove_file(&trash_file)?;
+                        return Ok(())
+                    }
+                    Err(ref e) if is_no_such_token(e) => {
+                        durable::remove_file(&trash_file)?;
+                        return Ok(())
+                    }
+                    Err(e) if attempt >= DROP_ATTEMPTS => {
+                        return Err(Error::failed(format!(
+                            "giving up on dropping {:?} after {} attempts: {}",
+                            trash_file, attempt, e)))
+                    }
+                    Err(_) => {
+                        tokio::time::delay_for(delay).await;
+                        delay = ::std::cmp::min(delay * 2, DROP_RETRY_MAX_DELAY);
+                        attempt += 1;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Outcome of `IdentityMap::collect_garbage()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcReport {
+    pub kept: usize,
+    pub reclaimed: usize,
+    pub failed: usize,
 }
 
 #[derive(Clone)]
@@ -167,28 +272,29 @@ impl IdentityMap {
         let (tx, poller) = ::multipoll::Poller::new(Box::new(Reaper));
         tokio::task::spawn_local(poller.map_err(|_|()));
 
-        Ok(IdentityMap {
+        let result = IdentityMap {
             inner: Rc::new(RefCell::new(IdentityMapInner {
                 directory: directory.as_ref().to_path_buf(),
                 trash_directory: trash_directory.as_ref().to_path_buf(),
                 api: api.clone(),
                 tasks: tx,
             })),
-        })
-    }
+        };
+
+        // Anything still in the trash was moved there before the grain shut down, but not yet
+        // dropped.
+        for dir_entry in ::std::fs::read_dir(&trash_directory)? {
+            let trash_file = dir_entry?.path();
+            println!("retrying drop of trashed identity {:?}", trash_file);
+            let task = IdentityMapInner::drop_trashed(&result.inner, trash_file);
+            result.inner.borrow_mut().tasks.add(task);
+        }
 
-    pub fn put(&mut self, id: &[u8], identity: identity::Client) -> Result<(), Error> {
-        let text_id = ::hex::encode(id);
-        self.put_by_text(&text_id, identity)
+        Ok(result)
     }
 
-    pub fn put_by_text(&mut self, text_id: &str, identity: identity::Client) -> Result<(), Error> {
-        if text_id.len() != 64 {
-            return Err(Error::failed(format!("invalid identity ID {}", text_id)))
-        }
-
-        // truncate to 128 bits
-        let truncated_text_id = &text_id[..32];
+    pub fn put(&mut self, id: &IdentityId, identity: identity::Client) -> Result<(), Error> {
+        let truncated_text_id = &id.truncated_text()[..];
 
         let mut symlink = self.inner.borrow().directory.clone();
         symlink.push(&truncated_text_id);
@@ -222,24 +328,239 @@ impl IdentityMap {
         }
     }
 
-    pub fn get(&mut self, id: &[u8]) -> Promise<identity::Client, Error> {
-        if id.len() != 32 {
-            return Promise::err(Error::failed(format!("invalid identity ID {:?}", id)))
+    pub fn get(&mut self, id: &IdentityId) -> Promise<identity::Client, Error> {
+        IdentityMapInner::read_from_disk(&self.inner, &id.truncated_text())
+    }
+
+    /// Whether `id` is saved, i.e. has been `put()` and not collected as garbage since.
+    pub fn contains(&self, id: &IdentityId) -> bool {
+        let symlink = self.inner.borrow().directory.join(id.truncated_text());
+        ::std::fs::symlink_metadata(symlink).is_ok()
+    }
+
+    /// Drops every saved identity that is not in `keep`. The sturdyrefs are moved to the trash
+    /// directory right away and dropped in the background.
+    pub fn collect_garbage(&mut self, keep: &HashSet<IdentityId>) -> Result<GcReport, Error> {
+        let keep: HashSet<String> = keep.iter().map(|id| id.truncated_text()).collect();
+        let directory = self.inner.borrow().directory.clone();
+        let mut report = GcReport::default();
+        for dir_entry in ::std::fs::read_dir(&directory)? {
+            let dir_entry = dir_entry?;
+            let name = match dir_entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(n) => {
+                    println!("malformed identity file name: {:?}", n);
+                    report.failed += 1;
+                    continue
+                }
+            };
+            if keep.contains(&name) {
+                report.kept += 1;
+                continue
+            }
+            match IdentityMapInner::drop_identity(&self.inner, &dir_entry.path()) {
+                Ok(()) => report.reclaimed += 1,
+                Err(e) => {
+                    println!("could not drop identity {}: {}", name, e);
+                    report.failed += 1;
+                }
+            }
         }
+        Ok(report)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdentityId, IdentityMap};
+    use crate::fake::{FakeSandstormApi, new_identity};
+    use crate::test_util::{TempDir, run};
+    use capnp::Error;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use sandstorm::identity_capnp::{identity};
+    use sandstorm::grain_capnp::{sandstorm_api};
+
+    async fn display_name(identity: identity::Client) -> Result<String, Error> {
+        let response = identity.get_profile_request().send().promise.await?;
+        Ok(response.get()?.get_profile()?.get_display_name()?.get_default_text()?.to_string()?)
+    }
 
-        let text_id = ::hex::encode(&id[..16]);
-        self.get_by_text(&text_id)
+    /// Creates a fresh, empty base directory for the identity and trash directories, which
+    /// don't exist yet.
+    fn temp_dirs(name: &str) -> (TempDir, PathBuf, PathBuf) {
+        let base = TempDir::new(&format!("identity-map-{}", name));
+        let (directory, trash) = (base.join("identities"), base.join("trash"));
+        (base, directory, trash)
     }
 
-    pub fn get_by_text(&mut self, text_id: &str) -> Promise<identity::Client, Error> {
-        if text_id.len() != 64 {
-            return Promise::err(Error::failed(format!("invalid identity ID {}", text_id)))
+    /// `put` saves the identity in the background. Waits until that has happened.
+    async fn wait_for_symlink(directory: &::std::path::Path, id: &IdentityId) {
+        let symlink = directory.join(&id.truncated_text());
+        for _ in 0..100 {
+            if ::std::fs::symlink_metadata(&symlink).is_ok() {
+                return
+            }
+            tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
         }
+        panic!("identity was never saved");
+    }
+
+    #[test]
+    fn identity_id_text_round_trip() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let id = IdentityId::from_bytes(&bytes).unwrap();
+        let text = id.to_string();
+        assert_eq!(text.len(), 64);
+        assert_eq!(IdentityId::parse(&text).unwrap(), id);
+        assert_eq!(id.as_bytes(), &bytes[..]);
+        assert_eq!(id.truncated_text(), text[..32]);
+    }
 
-        // truncate to 128 bits
-        let truncated_text_id = &text_id[..32];
+    #[test]
+    fn identity_id_rejects_malformed_input() {
+        assert!(IdentityId::from_bytes(&[0; 16]).is_err());
+        assert!(IdentityId::parse(&"ab".repeat(16)).is_err());
+        assert!(IdentityId::parse(&"ab".repeat(33)).is_err());
+        assert!(IdentityId::parse(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn put_then_get() {
+        run(async {
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client(FakeSandstormApi::default());
+            let (_base, directory, trash) = temp_dirs("put-then-get");
+            let mut map = IdentityMap::new(&directory, &trash, &api)?;
+
+            let alice = IdentityId::from_bytes(&[1; 32])?;
+            let bob = IdentityId::from_bytes(&[2; 32])?;
+            map.put(&alice, new_identity("Alice"))?;
+            map.put(&bob, new_identity("Bob"))?;
+            wait_for_symlink(&directory, &alice).await;
+            wait_for_symlink(&directory, &bob).await;
+
+            assert_eq!(display_name(map.get(&alice).await?).await?, "Alice");
+            assert_eq!(display_name(map.get(&bob).await?).await?, "Bob");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn get_survives_reopening() {
+        run(async {
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client(FakeSandstormApi::default());
+            let (_base, directory, trash) = temp_dirs("reopen");
+            let alice = IdentityId::from_bytes(&[3; 32])?;
+            {
+                let mut map = IdentityMap::new(&directory, &trash, &api)?;
+                map.put(&alice, new_identity("Alice"))?;
+                wait_for_symlink(&directory, &alice).await;
+            }
 
-        IdentityMapInner::read_from_disk(&self.inner, truncated_text_id)
+            let mut map = IdentityMap::new(&directory, &trash, &api)?;
+            let parsed = IdentityId::parse(&alice.to_string())?;
+            assert_eq!(display_name(map.get(&parsed).await?).await?, "Alice");
+            Ok(())
+        });
     }
 
+    #[test]
+    fn collect_garbage_keeps_referenced_identities() {
+        run(async {
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client(FakeSandstormApi::default());
+            let (_base, directory, trash) = temp_dirs("gc");
+            let mut map = IdentityMap::new(&directory, &trash, &api)?;
+
+            let alice = IdentityId::from_bytes(&[5; 32])?;
+            let bob = IdentityId::from_bytes(&[6; 32])?;
+            map.put(&alice, new_identity("Alice"))?;
+            map.put(&bob, new_identity("Bob"))?;
+            wait_for_symlink(&directory, &alice).await;
+            wait_for_symlink(&directory, &bob).await;
+
+            let keep: super::HashSet<IdentityId> = vec![alice].into_iter().collect();
+            let report = map.collect_garbage(&keep)?;
+            assert_eq!(report, super::GcReport { kept: 1, reclaimed: 1, failed: 0 });
+
+            assert_eq!(display_name(map.get(&alice).await?).await?, "Alice");
+            assert!(map.get(&bob).await.is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn new_drops_leftover_trash() {
+        run(async {
+            let fake = Rc::new(FakeSandstormApi::default());
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client_from_rc(fake.clone());
+            let (_base, directory, trash) = temp_dirs("trash");
+
+            // Simulate a crash between moving the symlink to the trash and dropping the token.
+            let mut req = api.save_request();
+            req.get().init_cap().set_as_capability(new_identity("Alice").client.hook);
+            let token = req.send().promise.await?.get()?.get_token()?.to_vec();
+            ::std::fs::create_dir_all(&trash)?;
+            let encoded = ::std::str::from_utf8(&token).unwrap();
+            ::std::os::unix::fs::symlink(encoded, trash.join(encoded))?;
+
+            let _map = IdentityMap::new(&directory, &trash, &api)?;
+            for _ in 0..100 {
+                if ::std::fs::read_dir(&trash)?.next().is_none() {
+                    break
+                }
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+            }
+            assert!(::std::fs::read_dir(&trash)?.next().is_none());
+            assert_eq!(fake.saved_count(), 0);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn new_finishes_drop_that_already_happened() {
+        run(async {
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client(FakeSandstormApi::default());
+            let (_base, directory, trash) = temp_dirs("dropped-trash");
+
+            // Simulate a crash between dropping the token and deleting the trashed symlink.
+            let mut req = api.save_request();
+            req.get().init_cap().set_as_capability(new_identity("Alice").client.hook);
+            let token = req.send().promise.await?.get()?.get_token()?.to_vec();
+            let mut req = api.drop_request();
+            req.get().set_token(&token[..]);
+            req.send().promise.await?;
+            ::std::fs::create_dir_all(&trash)?;
+            let encoded = ::std::str::from_utf8(&token).unwrap();
+            ::std::os::unix::fs::symlink(encoded, trash.join(encoded))?;
+
+            let _map = IdentityMap::new(&directory, &trash, &api)?;
+            for _ in 0..100 {
+                if ::std::fs::read_dir(&trash)?.next().is_none() {
+                    break
+                }
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+            }
+            assert!(::std::fs::read_dir(&trash)?.next().is_none());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn get_unknown_identity_fails() {
+        run(async {
+            let api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client(FakeSandstormApi::default());
+            let (_base, directory, trash) = temp_dirs("unknown");
+            let mut map = IdentityMap::new(&directory, &trash, &api)?;
+            assert!(map.get(&IdentityId::from_bytes(&[4; 32])?).await.is_err());
+            Ok(())
+        });
+    }
 }
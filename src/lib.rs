@@ -0,0 +1,43 @@
+// Copyright (c) 2014-2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! The server behind each Collections grain, along with the storage code that the
+//! `collections-storage` tool shares with it.
+
+#[macro_use] extern crate capnp_rpc;
+
+pub mod collections_capnp {
+  include!(concat!(env!("OUT_DIR"), "/collections_capnp.rs"));
+}
+
+#[cfg(feature = "dev")]
+pub mod dev;
+pub mod durable;
+pub mod entry_store;
+#[cfg(any(test, feature = "dev"))]
+pub mod fake;
+pub mod identity_map;
+pub mod migrations;
+pub mod web_socket;
+pub mod server;
+
+#[cfg(test)]
+mod test_util;
@@ -19,17 +19,6 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-#[macro_use] extern crate capnp_rpc;
-
-pub mod collections_capnp {
-  include!(concat!(env!("OUT_DIR"), "/collections_capnp.rs"));
-}
-
-pub mod identity_map;
-pub mod web_socket;
-pub mod server;
-
 fn main() {
-    server::main().expect("top level error");
+    collections::server::main().expect("top level error");
 }
-
@@ -0,0 +1,373 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Upgrades the layout of a grain's storage directory, normally `/var`, at startup.
+//!
+//! The directory's `layout-version` file holds the number of migrations in `MIGRATIONS` that
+//! have been applied to it. Grains from before that file existed are at version 0. To change
+//! the layout, append a migration to `MIGRATIONS`; never change or remove one that has shipped.
+//!
+//! Before each migration, the files and directories that it touches are copied into
+//! `backups/`, which keeps the `MAX_BACKUPS` most recent copies. A migration that fails or gets
+//! interrupted is run again on the next startup, so each one must cope with finding its own
+//! work partly done.
+
+use std::path::{Path, PathBuf};
+
+use crate::collections_capnp::ui_view_metadata;
+use crate::durable;
+use crate::entry_store::{self, EntryStore};
+
+struct Migration {
+    description: &'static str,
+
+    /// The files and directories under the root that `run` may change. Only these are backed
+    /// up, so that uploaded files and the like don't get copied over and over.
+    touches: &'static [&'static str],
+
+    run: fn(&Path) -> ::capnp::Result<()>,
+}
+
+/// `MIGRATIONS[i]` upgrades the layout from version `i` to version `i + 1`.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "delete temporary files left in sturdyrefs/",
+        touches: &["sturdyrefs"],
+        run: delete_uploading_sturdyrefs,
+    },
+    Migration {
+        description: "move entries from sturdyrefs/ into entries/",
+        touches: &["sturdyrefs", "entries", "quarantine"],
+        run: move_sturdyrefs_into_entry_store,
+    },
+];
+
+pub const CURRENT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Directories under the root that are neither backed up nor touched by migrations.
+const BACKUP_DIR: &str = "backups";
+const TMP_DIR: &str = "tmp";
+
+/// How many backups to keep. Older ones are deleted once a new one is complete.
+const MAX_BACKUPS: usize = 3;
+
+pub fn read_version(root: &Path) -> ::capnp::Result<u32> {
+    match ::std::fs::read_to_string(root.join("layout-version")) {
+        Ok(s) => match s.trim().parse() {
+            Ok(v) => Ok(v),
+            Err(e) => Err(::capnp::Error::failed(format!("malformed layout-version {:?}: {}", s, e))),
+        },
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_version(root: &Path, version: u32) -> ::capnp::Result<()> {
+    durable::write_bytes_atomically(&root.join(TMP_DIR), &root.join("layout-version"),
+                                    format!("{}\n", version).as_bytes())?;
+    Ok(())
+}
+
+/// Brings `root` up to `CURRENT_VERSION`. Fails if it was written by a newer version of the app.
+pub fn run(root: &Path) -> ::capnp::Result<()> {
+    ::std::fs::create_dir_all(root.join(TMP_DIR))?;
+    let version = read_version(root)?;
+    if version > CURRENT_VERSION {
+        return Err(::capnp::Error::failed(format!(
+            "storage layout version {} is newer than this app's ({})", version, CURRENT_VERSION)));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let from = from as u32;
+        let backup = back_up(root, from, migration.touches)?;
+        println!("migrating storage from layout version {} to {}: {} (backup in {:?})",
+                 from, from + 1, migration.description, backup);
+        (migration.run)(root)?;
+        write_version(root, from + 1)?;
+    }
+    Ok(())
+}
+
+/// Copies the files and directories named in `touches` that exist in `root` into a new
+/// directory under `backups/`, and returns its path. The copy only appears there once it is
+/// complete. Then deletes all but the `MAX_BACKUPS` most recent backups.
+fn back_up(root: &Path, version: u32, touches: &[&str]) -> ::capnp::Result<PathBuf> {
+    let timestamp = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map_err(|e| ::capnp::Error::failed(format!("{}", e)))?
+        .as_millis();
+    let name = format!("v{}-{}", version, timestamp);
+    let temp = root.join(TMP_DIR).join(format!("{}.backup", name));
+    ::std::fs::create_dir_all(&temp)?;
+    for &name in touches {
+        match ::std::fs::symlink_metadata(root.join(name)) {
+            Ok(_) => copy_entry(&root.join(name), &temp.join(name))?,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let backups = root.join(BACKUP_DIR);
+    ::std::fs::create_dir_all(&backups)?;
+    let path = backups.join(name);
+    durable::rename(&temp, &path)?;
+    prune_backups(&backups)?;
+    Ok(path)
+}
+
+/// Deletes all but the `MAX_BACKUPS` most recent backups in `backups`.
+fn prune_backups(backups: &Path) -> ::capnp::Result<()> {
+    // Names are "v<version>-<millis>", and versions only go up.
+    let mut names: Vec<((u32, u128), ::std::ffi::OsString)> = Vec::new();
+    for dir_entry in ::std::fs::read_dir(backups)? {
+        let file_name = dir_entry?.file_name();
+        let key = file_name.to_str()
+            .and_then(|n| n.strip_prefix('v'))
+            .and_then(|n| n.split_once('-'))
+            .and_then(|(v, t)| Some((v.parse().ok()?, t.parse().ok()?)));
+        match key {
+            Some(key) => names.push((key, file_name)),
+            None => println!("ignoring unexpected file in {:?}: {:?}", backups, file_name),
+        }
+    }
+    names.sort();
+    let excess = names.len().saturating_sub(MAX_BACKUPS);
+    for (_, name) in names.into_iter().take(excess) {
+        ::std::fs::remove_dir_all(backups.join(&name))?;
+    }
+    if excess > 0 {
+        durable::sync_dir(backups)?;
+    }
+    Ok(())
+}
+
+/// Copies the file, symlink or directory tree at `from` to `to`, syncing everything it creates.
+fn copy_entry(from: &Path, to: &Path) -> ::std::io::Result<()> {
+    let file_type = ::std::fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        // `IdentityMap` keeps its tokens in the targets of symlinks.
+        ::std::os::unix::fs::symlink(::std::fs::read_link(from)?, to)?;
+    } else if file_type.is_dir() {
+        ::std::fs::create_dir(to)?;
+        for dir_entry in ::std::fs::read_dir(from)? {
+            let dir_entry = dir_entry?;
+            copy_entry(&dir_entry.path(), &to.join(dir_entry.file_name()))?;
+        }
+        durable::sync_dir(to)?;
+    } else {
+        ::std::fs::copy(from, to)?;
+        ::std::fs::File::open(to)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Version 0 to 1. At one point, temporary files got uploaded directly into `sturdyrefs/`.
+fn delete_uploading_sturdyrefs(root: &Path) -> ::capnp::Result<()> {
+    let token_files = match ::std::fs::read_dir(root.join("sturdyrefs")) {
+        Ok(d) => d,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for token_file in token_files {
+        let dir_entry = token_file?;
+        if dir_entry.file_name().to_string_lossy().ends_with(".uploading") {
+            durable::remove_file(&dir_entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `bytes` is a well-formed `UiViewMetadata` message. Whether its fields make sense
+/// is up to `SavedUiViewSet`, which quarantines the entries that it can't load.
+fn check_entry(bytes: &[u8]) -> ::capnp::Result<()> {
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+    let mut copy = ::capnp::message::Builder::new_default();
+    copy.set_root(message.get_root::<ui_view_metadata::Reader>()?)?;
+    Ok(())
+}
+
+/// Version 1 to 2. Each entry used to have its own file in `sturdyrefs/`, named after its
+/// token. Now they are all in the `EntryStore` in `entries/`. Files that can't be read are put
+/// into `quarantine/`, where `SavedUiViewSet` offers them to editors.
+fn move_sturdyrefs_into_entry_store(root: &Path) -> ::capnp::Result<()> {
+    let directory = root.join("sturdyrefs");
+    let token_files = match ::std::fs::read_dir(&directory) {
+        Ok(d) => d,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let tmp_dir = root.join(TMP_DIR);
+    let quarantine_dir = root.join("quarantine");
+    ::std::fs::create_dir_all(&quarantine_dir)?;
+    let mut changes = Vec::new();
+    for token_file in token_files {
+        let dir_entry = token_file?;
+        let token: String = match dir_entry.file_name().to_str() {
+            None => {
+                println!("malformed token: {:?}", dir_entry.file_name());
+                continue
+            }
+            Some(s) => s.into(),
+        };
+        let bytes = ::std::fs::read(dir_entry.path())?;
+        match check_entry(&bytes) {
+            Ok(()) => changes.push(entry_store::Change::Put { token: token, metadata: bytes }),
+            Err(e) => {
+                println!("quarantining unreadable entry {}: {}", token, e);
+                durable::write_bytes_atomically(&tmp_dir, &quarantine_dir.join(&token), &bytes)?;
+            }
+        }
+    }
+
+    let mut store = EntryStore::open(root.join("entries"), &tmp_dir)?;
+    store.apply(changes)?;
+    store.compact()?;
+    ::std::fs::remove_dir_all(&directory)?;
+    durable::sync_dir(root)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CURRENT_VERSION, MAX_BACKUPS, read_version, run};
+    use std::path::Path;
+
+    use crate::entry_store::EntryStore;
+    use crate::test_util::{TempDir, metadata_bytes};
+
+    /// Lays out `root` the way grains did before `layout-version` existed.
+    fn write_version_0(root: &Path) {
+        let sturdyrefs = root.join("sturdyrefs");
+        ::std::fs::create_dir_all(&sturdyrefs).unwrap();
+        ::std::fs::write(sturdyrefs.join("dG9rZW4x"), metadata_bytes("first")).unwrap();
+        ::std::fs::write(sturdyrefs.join("dG9rZW4y"), metadata_bytes("second")).unwrap();
+        ::std::fs::write(sturdyrefs.join("dG9rZW4z.uploading"), &metadata_bytes("third")[..5]).unwrap();
+        ::std::fs::write(root.join("description"), "hello").unwrap();
+
+        let identities = root.join("identities");
+        ::std::fs::create_dir_all(&identities).unwrap();
+        ::std::os::unix::fs::symlink("c2F2ZWQ", identities.join("0123456789abcdef")).unwrap();
+    }
+
+    fn titles(root: &Path) -> Vec<(String, String)> {
+        let store = EntryStore::open(root.join("entries"), root.join("tmp")).unwrap();
+        crate::test_util::titles(&store)
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut result: Vec<String> = ::std::fs::read_dir(directory).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn delete_uploading_sturdyrefs() {
+        let dir = TempDir::new("migrations-v1");
+        let root = dir.path().to_path_buf();
+        write_version_0(&root);
+        super::delete_uploading_sturdyrefs(&root).unwrap();
+        assert_eq!(file_names(&root.join("sturdyrefs")), vec!["dG9rZW4x", "dG9rZW4y"]);
+
+        // Missing directories are fine.
+        let empty_dir = TempDir::new("migrations-v1-empty");
+        let empty = empty_dir.path().to_path_buf();
+        super::delete_uploading_sturdyrefs(&empty).unwrap();
+    }
+
+    #[test]
+    fn move_sturdyrefs_into_entry_store() {
+        let dir = TempDir::new("migrations-v2");
+        let root = dir.path().to_path_buf();
+        write_version_0(&root);
+        super::delete_uploading_sturdyrefs(&root).unwrap();
+        ::std::fs::write(root.join("sturdyrefs").join("Z2FyYmFnZQ"), b"garbage").unwrap();
+        ::std::fs::create_dir_all(root.join("tmp")).unwrap();
+
+        super::move_sturdyrefs_into_entry_store(&root).unwrap();
+        assert!(!root.join("sturdyrefs").exists());
+        assert_eq!(titles(&root), vec![("dG9rZW4x".to_string(), "first".to_string()),
+                                       ("dG9rZW4y".to_string(), "second".to_string())]);
+        assert_eq!(::std::fs::read(root.join("quarantine").join("Z2FyYmFnZQ")).unwrap(),
+                   b"garbage");
+
+        // If the directory is still there, e.g. because the migration was interrupted before
+        // it could be removed, running again ends up in the same place.
+        ::std::fs::create_dir_all(root.join("sturdyrefs")).unwrap();
+        ::std::fs::write(root.join("sturdyrefs").join("dG9rZW4x"), metadata_bytes("first")).unwrap();
+        super::move_sturdyrefs_into_entry_store(&root).unwrap();
+        assert_eq!(titles(&root).len(), 2);
+    }
+
+    #[test]
+    fn run_upgrades_version_0() {
+        let dir = TempDir::new("migrations-run");
+        let root = dir.path().to_path_buf();
+        write_version_0(&root);
+        run(&root).unwrap();
+
+        assert_eq!(read_version(&root).unwrap(), CURRENT_VERSION);
+        assert_eq!(titles(&root).len(), 2);
+        assert_eq!(::std::fs::read_to_string(root.join("description")).unwrap(), "hello");
+
+        // One backup per migration, each taken just before it ran and holding only what it
+        // touches, of which the most recent are kept.
+        let kept = MAX_BACKUPS.min(CURRENT_VERSION as usize);
+        let backups = file_names(&root.join("backups"));
+        assert_eq!(backups.len(), kept);
+        assert!(backups[0].starts_with(&format!("v{}-", CURRENT_VERSION as usize - kept)));
+        let moved = backups.iter().find(|b| b.starts_with("v1-")).unwrap();
+        let moved = root.join("backups").join(moved);
+        assert_eq!(file_names(&moved), vec!["sturdyrefs"]);
+        assert_eq!(file_names(&moved.join("sturdyrefs")), vec!["dG9rZW4x", "dG9rZW4y"]);
+
+        // Running again does nothing.
+        run(&root).unwrap();
+        assert_eq!(file_names(&root.join("backups")), backups);
+    }
+
+    #[test]
+    fn only_recent_backups_are_kept() {
+        let dir = TempDir::new("migrations-prune");
+        let root = dir.path().to_path_buf();
+        ::std::fs::create_dir_all(root.join("tmp")).unwrap();
+        ::std::fs::write(root.join("description"), "hello").unwrap();
+        for version in 0..MAX_BACKUPS as u32 + 2 {
+            super::back_up(&root, version, &["description", "missing"]).unwrap();
+        }
+
+        let backups = file_names(&root.join("backups"));
+        assert_eq!(backups.len(), MAX_BACKUPS);
+        assert!(backups[0].starts_with("v2-"));
+        let last = root.join("backups").join(&backups[MAX_BACKUPS - 1]);
+        assert_eq!(file_names(&last), vec!["description"]);
+    }
+
+    #[test]
+    fn run_refuses_newer_layout() {
+        let dir = TempDir::new("migrations-newer");
+        let root = dir.path().to_path_buf();
+        ::std::fs::write(root.join("layout-version"), format!("{}\n", CURRENT_VERSION + 1)).unwrap();
+        assert!(run(&root).is_err());
+        assert!(!root.join("backups").exists());
+    }
+}
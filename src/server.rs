@@ -31,14 +31,22 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use futures::{FutureExt, TryFutureExt};
-use crate::collections_capnp::ui_view_metadata;
+use futures::channel::oneshot;
+use crate::collections_capnp::{cached_profile, cached_view_info, collection_session,
+                               file_metadata, history_record, link_metadata, mirror_change,
+                               mirror_listener, note_metadata, settings, titled_view,
+                               tombstone_record, ui_view_metadata};
+use crate::durable;
+use crate::entry_store::{self, EntryStore};
 use crate::web_socket;
-use crate::identity_map::IdentityMap;
+use crate::identity_map::{is_no_such_token, IdentityId, IdentityMap};
+use crate::migrations;
 
+use sandstorm::api_session_capnp::{api_session};
 use sandstorm::powerbox_capnp::powerbox_descriptor;
 use sandstorm::identity_capnp::{user_info};
 use sandstorm::grain_capnp::{session_context, ui_view, ui_session, sandstorm_api};
-use sandstorm::util_capnp::{static_asset};
+use sandstorm::util_capnp::{byte_stream, handle, static_asset};
 use sandstorm::web_session_capnp::{web_session};
 use sandstorm::web_session_capnp::web_session::web_socket_stream;
 
@@ -82,11 +90,22 @@ impl web_socket::MessageHandler for WebSocketStream {
 struct SavedUiViewData {
     title: String,
     date_added: u64,
-    added_by: Option<String>,
+    added_by: Option<IdentityId>,
+
+    /// Shared by all copies of this entry in mirrored collections. Empty only for entries
+    /// loaded from disk that were saved before we had entry IDs.
+    entry_id: String,
+    modified_at: u64,
+
+    /// If true, the title is not replaced by the grain's own title.
+    title_pinned: bool,
+
+    /// Display name of `added_by`, in case their identity can't be restored.
+    added_by_name: Option<String>,
 }
 
 // copied from rustc_serialize
-fn json_escape_str(v: &str) -> String {
+pub fn json_escape_str(v: &str) -> String {
     let mut result: String = "\"".into();
 
     let mut start = 0;
@@ -161,1041 +180,6473 @@ fn optional_string_to_json(optional_string: &Option<String>) -> String {
     }
 }
 
+fn optional_identity_to_json(optional_identity: &Option<IdentityId>) -> String {
+    match optional_identity {
+        &None => "null".into(),
+        &Some(ref id) => format!("\"{}\"", id),
+    }
+}
+
 impl SavedUiViewData {
+    fn from_metadata(metadata: ui_view_metadata::Reader) -> ::capnp::Result<SavedUiViewData> {
+        let added_by = if metadata.has_added_by() {
+            Some(IdentityId::parse(metadata.get_added_by()?.to_str()?)?)
+        } else {
+            None
+        };
+
+        let added_by_name = if metadata.has_added_by_name() {
+            Some(metadata.get_added_by_name()?.to_string()?)
+        } else {
+            None
+        };
+
+        let entry_id = if metadata.has_entry_id() {
+            metadata.get_entry_id()?.to_string()?
+        } else {
+            String::new()
+        };
+
+        Ok(SavedUiViewData {
+            title: metadata.get_title()?.to_string()?,
+            date_added: metadata.get_date_added(),
+            added_by: added_by,
+            entry_id: entry_id,
+            modified_at: ::std::cmp::max(metadata.get_modified_at(), metadata.get_date_added()),
+            title_pinned: metadata.get_title_pinned(),
+            added_by_name: added_by_name,
+        })
+    }
+
+    fn write_metadata(&self, mut metadata: ui_view_metadata::Builder) {
+        metadata.set_title(&self.title);
+        metadata.set_date_added(self.date_added);
+        match self.added_by {
+            Some(ref id) => metadata.set_added_by(&id.to_string()),
+            None => (),
+        }
+        if !self.entry_id.is_empty() {
+            metadata.set_entry_id(&self.entry_id);
+        }
+        metadata.set_modified_at(self.modified_at);
+        metadata.set_title_pinned(self.title_pinned);
+        if let Some(ref name) = self.added_by_name {
+            metadata.set_added_by_name(name);
+        }
+    }
+
+    /// Serializes the metadata as a standalone `UiViewMetadata` message.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut message = ::capnp::message::Builder::new_default();
+        self.write_metadata(message.init_root());
+        ::capnp::serialize::write_message_to_words(&message)
+    }
+
     fn to_json(&self) -> String {
-        format!("{{\"title\":{},\"dateAdded\": \"{}\",\"addedBy\":{}}}",
+        format!("{{\"title\":{},\"dateAdded\": \"{}\",\"addedBy\":{},\"addedByName\":{},\"titlePinned\":{}}}",
                 json_escape_str(&self.title),
                 self.date_added,
-                optional_string_to_json(&self.added_by))
+                optional_identity_to_json(&self.added_by),
+                optional_string_to_json(&self.added_by_name),
+                self.title_pinned)
     }
 }
 
-#[derive(Clone, Debug)]
-struct ViewInfoData {
-    app_title: String,
-    grain_icon_url: String,
+#[derive(Clone)]
+enum HistoryKind {
+    TitleChanged { old_title: String, new_title: String },
+    TitlePinned(bool),
 }
 
-impl ViewInfoData {
+/// A change to an entry, as recorded in the history log.
+#[derive(Clone)]
+struct HistoryRecord {
+    timestamp: u64,
+    token: String,
+
+    /// `None` if the collection made the change by itself.
+    by: Option<IdentityId>,
+    kind: HistoryKind,
+}
+
+impl HistoryRecord {
+    fn read(record: history_record::Reader) -> ::capnp::Result<HistoryRecord> {
+        let by = if record.has_by() {
+            Some(IdentityId::parse(record.get_by()?.to_str()?)?)
+        } else {
+            None
+        };
+        let kind = match record.which()? {
+            history_record::TitleChanged(g) => HistoryKind::TitleChanged {
+                old_title: g.get_old_title()?.to_string()?,
+                new_title: g.get_new_title()?.to_string()?,
+            },
+            history_record::TitlePinned(pinned) => HistoryKind::TitlePinned(pinned),
+        };
+        Ok(HistoryRecord {
+            timestamp: record.get_timestamp(),
+            token: record.get_token()?.to_string()?,
+            by: by,
+            kind: kind,
+        })
+    }
+
+    fn write(&self, mut record: history_record::Builder) {
+        record.set_timestamp(self.timestamp);
+        record.set_token(&self.token);
+        if let Some(ref by) = self.by {
+            record.set_by(&by.to_string());
+        }
+        match self.kind {
+            HistoryKind::TitleChanged { ref old_title, ref new_title } => {
+                let mut g = record.init_title_changed();
+                g.set_old_title(old_title);
+                g.set_new_title(new_title);
+            }
+            HistoryKind::TitlePinned(pinned) => record.set_title_pinned(pinned),
+        }
+    }
+
     fn to_json(&self) -> String {
-        format!("{{\"appTitle\":{},\"grainIconUrl\":\"{}\"}}",
-                json_escape_str(&self.app_title),
-                self.grain_icon_url)
+        let kind = match self.kind {
+            HistoryKind::TitleChanged { ref old_title, ref new_title } => {
+                format!("\"titleChanged\":{{\"oldTitle\":{},\"newTitle\":{}}}",
+                        json_escape_str(old_title), json_escape_str(new_title))
+            }
+            HistoryKind::TitlePinned(pinned) => format!("\"titlePinned\":{}", pinned),
+        };
+        format!("{{\"timestamp\":\"{}\",\"token\":\"{}\",\"by\":{},{}}}",
+                self.timestamp, self.token, optional_identity_to_json(&self.by), kind)
     }
 }
 
-#[derive(Clone, Debug)]
-struct ProfileData {
-    display_name: String,
-    picture_url: String,
+/// Reads the history log at `path`, oldest record first. Like the entry log, a record cut short
+/// by a crash at the end is dropped, and so are damaged records elsewhere, without losing the
+/// records around them; the dropped bytes are kept next to the log.
+fn read_history(path: &::std::path::Path, tmp_dir: &::std::path::Path)
+                -> ::capnp::Result<Vec<HistoryRecord>>
+{
+    let log = match ::std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let records = durable::read_records(&log);
+    let mut history = Vec::new();
+    for &(offset, _) in &records.damaged {
+        println!("dropping the damaged record at byte {} of {:?}", offset, path);
+    }
+    let mut dropped = records.damaged;
+    for (offset, bytes) in records.records {
+        let record = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())
+            .and_then(|message| HistoryRecord::read(message.get_root()?));
+        match record {
+            Ok(r) => history.push(r),
+            Err(e) => {
+                println!("dropping the unreadable record at byte {} of {:?}: {}", offset, path, e);
+                dropped.push((offset, durable::framed_len(bytes)));
+            }
+        }
+    }
+    if let Some(offset) = records.torn {
+        println!("dropping the last {} bytes of {:?}, which were cut short", log.len() - offset, path);
+        dropped.push((offset, log.len() - offset));
+    }
+    if !dropped.is_empty() {
+        dropped.sort();
+        durable::drop_from_log(tmp_dir, path, &log, &dropped)?;
+    }
+    Ok(history)
 }
 
-impl ProfileData {
-    fn to_json(&self) -> String {
-        format!(
-            "{{\"pictureUrl\":{}, \"displayName\":{}}}",
-            json_escape_str(&self.picture_url),
-            json_escape_str(&self.display_name))
+/// Reads the log of removed entries, as written by `Mirror::record_tombstones()`. Drops the
+/// records that can't be read, like `read_history()`.
+fn read_tombstones(path: &::std::path::Path, tmp_dir: &::std::path::Path)
+                   -> ::capnp::Result<HashMap<String, u64>>
+{
+    let log = match ::std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let records = durable::read_records(&log);
+    let mut tombstones = HashMap::new();
+    for &(offset, _) in &records.damaged {
+        println!("dropping the damaged record at byte {} of {:?}", offset, path);
+    }
+    let mut dropped = records.damaged;
+    for (offset, bytes) in records.records {
+        let result = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())
+            .and_then(|message| {
+                let record: tombstone_record::Reader = message.get_root()?;
+                let mut entry_ids = Vec::new();
+                for entry_id in record.get_entry_ids()?.iter() {
+                    entry_ids.push(entry_id?.to_string()?);
+                }
+                Ok((record.get_timestamp(), entry_ids))
+            });
+        match result {
+            Ok((timestamp, entry_ids)) => for entry_id in entry_ids {
+                let t = tombstones.entry(entry_id).or_insert(timestamp);
+                *t = ::std::cmp::max(*t, timestamp);
+            },
+            Err(e) => {
+                println!("dropping the unreadable record at byte {} of {:?}: {}", offset, path, e);
+                dropped.push((offset, durable::framed_len(bytes)));
+            }
+        }
     }
+    if let Some(offset) = records.torn {
+        println!("dropping the last {} bytes of {:?}, which were cut short", log.len() - offset, path);
+        dropped.push((offset, log.len() - offset));
+    }
+    if !dropped.is_empty() {
+        dropped.sort();
+        durable::drop_from_log(tmp_dir, path, &log, &dropped)?;
+    }
+    Ok(tombstones)
 }
 
+/// An entry that is a link to a web page outside of Sandstorm.
 #[derive(Clone)]
-enum Action {
-    Insert { token: String, data: SavedUiViewData },
-    Remove { token: String },
-    ViewInfo { token: String, data: Result<ViewInfoData, Error> },
-    CanWrite(bool),
-    UserId(Option<String>),
-    Description(String),
-    User { id: String, data: ProfileData },
+struct LinkData {
+    title: String,
+    url: String,
+    date_added: u64,
+    added_by: Option<IdentityId>,
 }
 
-impl Action {
-    fn to_json(&self) -> String {
-        match self {
-            &Action::Insert { ref token, ref data } => {
-                format!("{{\"insert\":{{\"token\":\"{}\",\"data\":{} }} }}",
-                        token, data.to_json())
-            }
-            &Action::Remove { ref token } => {
-                format!("{{\"remove\":{{\"token\":\"{}\"}}}}", token)
-            }
-            &Action::ViewInfo { ref token, data: Ok(ref data) } => {
-                format!("{{\"viewInfo\":{{\"token\":\"{}\",\"data\":{} }} }}",
-                        token, data.to_json())
-            }
-            &Action::ViewInfo { ref token, data: Err(ref e) } => {
-                format!("{{\"viewInfo\":{{\"token\":\"{}\",\"failed\": {} }} }}",
-                        token,
-                        json_escape_str(&format!("{}", e)))
-            }
+impl LinkData {
+    fn from_metadata(metadata: link_metadata::Reader) -> ::capnp::Result<LinkData> {
+        let added_by = if metadata.has_added_by() {
+            Some(IdentityId::parse(metadata.get_added_by()?.to_str()?)?)
+        } else {
+            None
+        };
 
-            &Action::CanWrite(b) => {
-                format!("{{\"canWrite\":{}}}", b)
-            }
-            &Action::UserId(ref s) => {
-                format!("{{\"userId\":{}}}", optional_string_to_json(s))
-            }
-            &Action::Description(ref s) => {
-                format!("{{\"description\":{}}}", json_escape_str(s))
-            }
-            &Action::User { ref id, ref data } => {
-                format!(
-                    "{{\"user\":{{\"id\":{}, \"data\":{} }}}}",
-                    json_escape_str(id), data.to_json())
-            }
+        Ok(LinkData {
+            title: metadata.get_title()?.to_string()?,
+            url: metadata.get_url()?.to_string()?,
+            date_added: metadata.get_date_added(),
+            added_by: added_by,
+        })
+    }
+
+    fn write_metadata(&self, mut metadata: link_metadata::Builder) {
+        metadata.set_title(&self.title);
+        metadata.set_url(&self.url);
+        metadata.set_date_added(self.date_added);
+        match self.added_by {
+            Some(ref id) => metadata.set_added_by(&id.to_string()),
+            None => (),
         }
     }
+
+    fn to_json(&self) -> String {
+        format!("{{\"title\":{},\"url\":{},\"dateAdded\": \"{}\",\"addedBy\":{}}}",
+                json_escape_str(&self.title),
+                json_escape_str(&self.url),
+                self.date_added,
+                optional_identity_to_json(&self.added_by))
+    }
 }
 
-fn url_of_static_asset(asset: static_asset::Client) -> Promise<String, Error> {
-    Promise::from_future(asset.get_url_request().send().promise.map(
-        move |r| match r {
-            Ok(response) => {
-                let result = response.get()?;
-                let protocol = match result.get_protocol()? {
-                    static_asset::Protocol::Https => "https".to_string(),
-                    static_asset::Protocol::Http => "http".to_string(),
-                };
+/// Parses the `url` and `title` fields of a form-encoded request body for a link entry.
+/// Only http and https URLs are accepted.
+fn parse_link_form(body: &[u8]) -> Result<(String, String), Error> {
+    let mut url = None;
+    let mut title = None;
+    for (key, value) in ::url::form_urlencoded::parse(body) {
+        if key == "url" {
+            url = Some(value.into_owned());
+        } else if key == "title" {
+            title = Some(value.into_owned());
+        }
+    }
 
-                Ok(format!("{}://{}", protocol, result.get_host_path()?.to_str()?))
-            }
-            Err(e) => Err(e),
+    let url = match url {
+        Some(u) => u,
+        None => return Err(Error::failed("missing url".into())),
+    };
+    match ::url::Url::parse(&url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => (),
+        Ok(parsed) => {
+            return Err(Error::failed(format!("unsupported URL scheme: {}", parsed.scheme())))
         }
-    ))
+        Err(e) => return Err(Error::failed(format!("invalid URL: {}", e))),
+    }
+
+    // Fall back to the URL itself if no title was given.
+    let title = match title {
+        Some(ref t) if !t.is_empty() => t.clone(),
+        _ => url.clone(),
+    };
+    Ok((url, title))
 }
 
-struct Reaper;
+/// A heading, paragraph or divider placed between the entries.
+#[derive(Clone)]
+struct NoteData {
+    kind: note_metadata::Kind,
+    text: String,
+    position: u64,
+    date_added: u64,
+    added_by: Option<IdentityId>,
+}
 
-impl Finisher<Error> for Reaper {
-    fn task_failed(&mut self, error: Error) {
-        // TODO better message.
-        println!("task failed: {}", error);
+fn note_kind_to_str(kind: note_metadata::Kind) -> &'static str {
+    match kind {
+        note_metadata::Kind::Heading => "heading",
+        note_metadata::Kind::Paragraph => "paragraph",
+        note_metadata::Kind::Divider => "divider",
     }
 }
 
-struct SavedUiViewSetInner {
-    tmp_dir: ::std::path::PathBuf,
-    sturdyref_dir: ::std::path::PathBuf,
+fn note_kind_from_str(kind: &str) -> Option<note_metadata::Kind> {
+    match kind {
+        "heading" => Some(note_metadata::Kind::Heading),
+        "paragraph" => Some(note_metadata::Kind::Paragraph),
+        "divider" => Some(note_metadata::Kind::Divider),
+        _ => None,
+    }
+}
 
-    /// Invariant: Every entry in this map has been persisted to the filesystem and has sent
-    /// out Action::Insert messages to each subscriber.
-    views: HashMap<String, SavedUiViewData>,
+impl NoteData {
+    fn from_metadata(metadata: note_metadata::Reader) -> ::capnp::Result<NoteData> {
+        let added_by = if metadata.has_added_by() {
+            Some(IdentityId::parse(metadata.get_added_by()?.to_str()?)?)
+        } else {
+            None
+        };
 
-    view_infos: HashMap<String, Result<ViewInfoData, Error>>,
-    next_id: u64,
-    subscribers: HashMap<u64, web_socket_stream::Client>,
-    tasks: PollerHandle<Error>,
-    description: String,
-    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-    identity_map: IdentityMap,
+        Ok(NoteData {
+            kind: metadata.get_kind()?,
+            text: metadata.get_text()?.to_string()?,
+            position: metadata.get_position(),
+            date_added: metadata.get_date_added(),
+            added_by: added_by,
+        })
+    }
 
-}
+    fn write_metadata(&self, mut metadata: note_metadata::Builder) {
+        metadata.set_kind(self.kind);
+        metadata.set_text(&self.text);
+        metadata.set_position(self.position);
+        metadata.set_date_added(self.date_added);
+        match self.added_by {
+            Some(ref id) => metadata.set_added_by(&id.to_string()),
+            None => (),
+        }
+    }
 
-impl SavedUiViewSetInner {
-    fn get_saved_data<'a>(&'a self, token: &'a String) -> Option<&'a SavedUiViewData> {
-        self.views.get(token)
+    fn to_json(&self) -> String {
+        format!("{{\"kind\":\"{}\",\"text\":{},\"position\": \"{}\",\"dateAdded\": \"{}\",\"addedBy\":{}}}",
+                note_kind_to_str(self.kind),
+                json_escape_str(&self.text),
+                self.position,
+                self.date_added,
+                optional_identity_to_json(&self.added_by))
     }
 }
 
-#[derive(Clone)]
-pub struct SavedUiViewSet {
-    inner: Rc<RefCell<SavedUiViewSetInner>>,
+/// The fields of a form-encoded request body for a note. Missing fields are `None`.
+struct NoteForm {
+    kind: Option<note_metadata::Kind>,
+    text: Option<String>,
+    position: Option<u64>,
 }
 
-impl SavedUiViewSet {
-    pub fn new<P1, P2>(tmp_dir: P1,
-                       sturdyref_dir: P2,
-                       sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
-                       identity_map: IdentityMap,
-    )
-                  -> ::capnp::Result<SavedUiViewSet>
-        where P1: AsRef<::std::path::Path>,
-              P2: AsRef<::std::path::Path>
-    {
-        let description = match ::std::fs::File::open("/var/description") {
-            Ok(mut f) => {
-                use std::io::Read;
-                let mut result = String::new();
-                f.read_to_string(&mut result)?;
-                result
-            }
-            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
-                use std::io::Write;
-                let mut f = ::std::fs::File::create("/var/description")?;
-                let result = "";
-                f.write_all(result.as_bytes())?;
-                result.into()
+fn parse_note_form(body: &[u8]) -> Result<NoteForm, Error> {
+    let mut form = NoteForm { kind: None, text: None, position: None };
+    for (key, value) in ::url::form_urlencoded::parse(body) {
+        if key == "kind" {
+            match note_kind_from_str(&value) {
+                Some(k) => form.kind = Some(k),
+                None => return Err(Error::failed(format!("unknown note kind: {}", value))),
             }
-            Err(e) => {
-                return Err(e.into());
+        } else if key == "text" {
+            form.text = Some(value.into_owned());
+        } else if key == "position" {
+            match value.parse::<u64>() {
+                Ok(p) => form.position = Some(p),
+                Err(e) => return Err(Error::failed(format!("invalid position: {}", e))),
             }
-        };
+        }
+    }
+    Ok(form)
+}
 
-        let (tx, poller) = Poller::new(Box::new(Reaper));
-        tokio::task::spawn_local(poller.map_err(|_|()));
+/// A file uploaded into the collection.
+#[derive(Clone)]
+struct FileData {
+    name: String,
+    mime_type: String,
+    size: u64,
+    date_added: u64,
+    added_by: Option<IdentityId>,
+}
 
-        let result = SavedUiViewSet {
-            inner: Rc::new(RefCell::new(SavedUiViewSetInner {
-                tmp_dir: tmp_dir.as_ref().to_path_buf(),
-                sturdyref_dir: sturdyref_dir.as_ref().to_path_buf(),
-                views: HashMap::new(),
-                view_infos: HashMap::new(),
-                next_id: 0,
-                subscribers: HashMap::new(),
-                tasks: tx,
-                description: description,
-                sandstorm_api: sandstorm_api.clone(),
-                identity_map: identity_map,
-            })),
+impl FileData {
+    fn from_metadata(metadata: file_metadata::Reader) -> ::capnp::Result<FileData> {
+        let added_by = if metadata.has_added_by() {
+            Some(IdentityId::parse(metadata.get_added_by()?.to_str()?)?)
+        } else {
+            None
         };
 
-        // create sturdyref directory if it does not yet exist
-        ::std::fs::create_dir_all(&sturdyref_dir)?;
+        Ok(FileData {
+            name: metadata.get_name()?.to_string()?,
+            mime_type: metadata.get_mime_type()?.to_string()?,
+            size: metadata.get_size(),
+            date_added: metadata.get_date_added(),
+            added_by: added_by,
+        })
+    }
 
-        // clear and create tmp directory
-        match ::std::fs::remove_dir_all(&tmp_dir) {
-            Ok(()) => (),
-            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
-            Err(e) => return Err(e.into()),
+    fn write_metadata(&self, mut metadata: file_metadata::Builder) {
+        metadata.set_name(&self.name);
+        metadata.set_mime_type(&self.mime_type);
+        metadata.set_size(self.size);
+        metadata.set_date_added(self.date_added);
+        match self.added_by {
+            Some(ref id) => metadata.set_added_by(&id.to_string()),
+            None => (),
         }
-        ::std::fs::create_dir_all(&tmp_dir)?;
+    }
 
-        for token_file in ::std::fs::read_dir(&sturdyref_dir)? {
-            let dir_entry = token_file?;
-            let token: String = match dir_entry.file_name().to_str() {
-                None => {
-                    println!("malformed token: {:?}", dir_entry.file_name());
-                    continue
-                }
-                Some(s) => s.into(),
-            };
+    fn to_json(&self) -> String {
+        format!("{{\"name\":{},\"mimeType\":{},\"size\":{},\"dateAdded\": \"{}\",\"addedBy\":{}}}",
+                json_escape_str(&self.name),
+                json_escape_str(&self.mime_type),
+                self.size,
+                self.date_added,
+                optional_identity_to_json(&self.added_by))
+    }
+}
 
-            if token.ends_with(".uploading") {
-                // At one point, these temporary files got uploading directly into this directory.
-                ::std::fs::remove_file(dir_entry.path())?;
-            } else {
-                let mut reader = ::std::fs::File::open(dir_entry.path())?;
-                let message = ::capnp::serialize::read_message(&mut reader,
-                                                               Default::default())?;
-                let metadata: ui_view_metadata::Reader = message.get_root()?;
+/// Largest file that may be uploaded.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
 
-                let added_by = if metadata.has_added_by() {
-                    Some(metadata.get_added_by()?.to_string()?)
-                } else {
-                    None
-                };
+/// Total size of all uploaded files in a collection.
+const FILE_QUOTA: u64 = 100 * 1024 * 1024;
 
-                let entry = SavedUiViewData {
-                    title: metadata.get_title()?.to_string()?,
-                    date_added: metadata.get_date_added(),
-                    added_by: added_by,
-                };
+/// Files of these types are displayed in the browser. Anything else is served as a download, so
+/// that an uploaded HTML or SVG file can't run scripts with the privileges of the grain.
+const INLINE_MIME_TYPES: &[&str] = &[
+    "application/pdf", "image/gif", "image/jpeg", "image/png", "image/webp",
+];
 
-                result.inner.borrow_mut().views.insert(token.clone(), entry);
+#[derive(Clone, Debug)]
+struct ViewInfoData {
+    app_title: String,
+    grain_icon_url: String,
 
-                result.retrieve_view_info(token)?;
-            }
-        }
+    /// Milliseconds since unix epoch when this was retrieved from the grain.
+    fetched_at: u64,
 
-        Ok(result)
-    }
+    /// True if this was loaded from the on-disk cache and has not yet been refreshed, or if the
+    /// most recent attempt to refresh it failed.
+    stale: bool,
+}
 
-    fn retrieve_view_info(&self,
-                          token: String) -> ::capnp::Result<()> {
-        // SandstormApi.restore, then call getViewInfo,
-        // then call get_url() on the grain static asset.
-
-        let self1 = self.clone();
-        let binary_token = match base64::engine::general_purpose::URL_SAFE.decode(&token[..]) {
-            Ok(b) => b,
-            Err(e) => return Err(Error::failed(format!("{}", e))),
-        };
-
-        let mut req = self.inner.borrow().sandstorm_api.restore_request();
-        req.get().set_token(&binary_token);
-        let task = req.send().promise.and_then(move |response| {
-            let view: ui_view::Client =
-                pry!(pry!(response.get()).get_cap().get_as_capability());
-            Promise::from_future(view.get_view_info_request().send().promise.and_then(move |response| {
-                let view_info = pry!(response.get());
-                let app_title = pry!(pry!(pry!(view_info.get_app_title()).get_default_text()).to_string());
-                Promise::from_future(url_of_static_asset(pry!(view_info.get_grain_icon())).map_ok(move |url| {
-                    ViewInfoData {
-                        app_title: app_title,
-                        grain_icon_url: url,
-                    }
-                }))
-            }))
-        }).map(move |result| {
-            self1.inner.borrow_mut().view_infos.insert(token.clone(), result.clone());
-            self1.send_action_to_subscribers(Action::ViewInfo {
-                token: token,
-                data: result,
-            });
-
-            Ok(())
-        });
-
-        self.inner.borrow_mut().tasks.add(task);
-        Ok(())
+impl ViewInfoData {
+    fn from_cache(cached: cached_view_info::Reader) -> ::capnp::Result<ViewInfoData> {
+        Ok(ViewInfoData {
+            app_title: cached.get_app_title()?.to_string()?,
+            grain_icon_url: cached.get_grain_icon_url()?.to_string()?,
+            fetched_at: cached.get_fetched_at(),
+            stale: true,
+        })
     }
 
-    fn get_user_profile(&self,
-                        identity_id: &str) -> Promise<ProfileData, Error> {
-        Promise::from_future(self.inner.borrow_mut().identity_map.get_by_text(identity_id).and_then(move |identity| {
-            identity.get_profile_request().send().promise
-        }).and_then(move |response| {
-            let profile = pry!(pry!(response.get()).get_profile());
-            let display_name = pry!(pry!(pry!(profile.get_display_name()).get_default_text()).to_string());
-            Promise::from_future(url_of_static_asset(pry!(profile.get_picture())).map_ok(move |url| {
-                ProfileData { display_name: display_name, picture_url: url }
-            }))
-        }))
+    fn write_cache(&self, mut cached: cached_view_info::Builder) {
+        cached.set_app_title(&self.app_title);
+        cached.set_grain_icon_url(&self.grain_icon_url);
+        cached.set_fetched_at(self.fetched_at);
     }
 
-    fn update_description(&self, description: &[u8]) -> ::capnp::Result<()> {
-        use std::io::Write;
+    fn to_json(&self) -> String {
+        format!("{{\"appTitle\":{},\"grainIconUrl\":\"{}\",\"fetchedAt\":\"{}\",\"stale\":{}}}",
+                json_escape_str(&self.app_title),
+                self.grain_icon_url,
+                self.fetched_at,
+                self.stale)
+    }
+}
 
-        let desc_string: String = match ::std::str::from_utf8(description) {
-            Err(e) => return Err(::capnp::Error::failed(format!("{}", e))),
-            Ok(d) => d.into(),
-        };
+/// A failed attempt to retrieve an entry's view info.
+#[derive(Clone, Debug)]
+struct ViewInfoError {
+    error: Error,
 
-        let temp_path = format!("/var/description.uploading");
-        ::std::fs::File::create(&temp_path)?.write_all(description)?;
-        ::std::fs::rename(temp_path, "/var/description")?;
+    /// What an earlier attempt retrieved, marked stale, so that the entry can still be shown.
+    cached: Option<ViewInfoData>,
+}
 
-        self.inner.borrow_mut().description = desc_string.clone();
-        self.send_action_to_subscribers(Action::Description(desc_string));
-        Ok(())
+impl ViewInfoError {
+    fn to_json(&self) -> String {
+        format!("{{\"failed\":{},\"data\":{}}}",
+                json_escape_str(&format!("{}", self.error)),
+                self.cached.as_ref().map_or("null".into(), |c| c.to_json()))
     }
+}
 
-    fn insert(&mut self,
-              token: String,
-              title: String,
-              added_by: Option<String>) -> ::capnp::Result<()> {
-        let dur = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
-            .map_err(|e| Error::failed(format!("{}", e)))?;
-        let date_added = dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000000) as u64;
-
-        let mut token_path = ::std::path::PathBuf::new();
-        token_path.push(self.inner.borrow().sturdyref_dir.clone());
-        token_path.push(token.clone());
-
-        let mut temp_path = ::std::path::PathBuf::new();
-        temp_path.push(self.inner.borrow().tmp_dir.clone());
-        temp_path.push(format!("{}.uploading", token));
+/// The view info to display for an entry, even if the latest attempt to retrieve it failed.
+fn last_known_view_info(view_info: &Result<ViewInfoData, ViewInfoError>) -> Option<&ViewInfoData> {
+    match *view_info {
+        Ok(ref data) => Some(data),
+        Err(ref e) => e.cached.as_ref(),
+    }
+}
 
-        let mut writer = ::std::fs::File::create(&temp_path)?;
+/// Outcome of the most recent attempt to retrieve an entry's view info.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HealthStatus {
+    /// Not checked since startup.
+    Unchecked,
+    Ok,
 
-        let mut message = ::capnp::message::Builder::new_default();
-        {
-            let mut metadata: ui_view_metadata::Builder = message.init_root();
-            metadata.set_title(&title);
-            metadata.set_date_added(date_added);
-            match added_by {
-                Some(ref s) => metadata.set_added_by(s),
-                None => (),
-            }
-        }
+    /// The grain could not be reached, e.g. because it or the backend is overloaded or
+    /// restarting. Likely to go away by itself.
+    Disconnected,
 
-        ::capnp::serialize::write_message(&mut writer, &message)?;
-        ::std::fs::rename(temp_path, token_path)?;
-        writer.sync_all()?;
+    /// The grain refused the request, e.g. because it was deleted or its sharing was revoked.
+    /// Unlikely to go away by itself.
+    Failed,
+}
 
-        if !self.inner.borrow().subscribers.is_empty() {
-            if let Some(ref id) = added_by {
-                let self1 = self.clone();
-                let identity_id: String = id.to_string();
-                let task = self.get_user_profile(&identity_id).map_ok(move |profile_data| {
-                    self1.send_action_to_subscribers(
-                        Action::User { id: identity_id, data: profile_data });
-                });
-                self.inner.borrow_mut().tasks.add(task);
-            }
+impl HealthStatus {
+    fn from_result<T>(result: &Result<T, Error>) -> HealthStatus {
+        match result {
+            &Ok(_) => HealthStatus::Ok,
+            &Err(ref e) => HealthStatus::from_error(e),
         }
-
-        let entry = SavedUiViewData {
-            title: title,
-            date_added: date_added,
-            added_by: added_by,
-        };
-
-        self.send_action_to_subscribers(Action::Insert {
-            token: token.clone(),
-            data: entry.clone(),
-        });
-        self.inner.borrow_mut().views.insert(token, entry);
-
-        Ok(())
     }
 
-    fn send_action_to_subscribers(&self, action: Action) {
-        let json_string = action.to_json();
-        let &mut SavedUiViewSetInner { ref subscribers, ref mut tasks, ..} =
-            &mut *self.inner.borrow_mut();
-        for (_, sub) in &*subscribers {
-            let mut req = sub.send_bytes_request();
-            web_socket::encode_text_message(req.get(), &json_string);
-            tasks.add(req.send().promise.map_ok(|_| ()));
+    fn from_error(e: &Error) -> HealthStatus {
+        match e.kind {
+            ::capnp::ErrorKind::Disconnected | ::capnp::ErrorKind::Overloaded =>
+                HealthStatus::Disconnected,
+            _ => HealthStatus::Failed,
         }
     }
 
-    fn remove(&mut self, token: &str) -> Result<(), Error> {
-        let mut path = self.inner.borrow().sturdyref_dir.clone();
-        path.push(token);
-        if let Err(e) = ::std::fs::remove_file(path) {
-            if e.kind() != ::std::io::ErrorKind::NotFound {
-                return Err(e.into())
-            }
+    fn as_str(&self) -> &'static str {
+        match self {
+            &HealthStatus::Unchecked => "unchecked",
+            &HealthStatus::Ok => "ok",
+            &HealthStatus::Disconnected => "disconnected",
+            &HealthStatus::Failed => "failed",
         }
-
-        self.send_action_to_subscribers(Action::Remove { token: token.into() });
-        self.inner.borrow_mut().views.remove(token);
-        Ok(())
     }
+}
 
-    fn new_subscribed_websocket(&self,
-                                client_stream: web_socket_stream::Client,
-                                can_write: bool,
-                                user_id: Option<String>)
-                                 -> web_socket_stream::Client
-    {
-        fn send_action(task: Promise<(), Error>,
-                       client_stream: &web_socket_stream::Client,
-                       action: Action) -> Promise<(), Error> {
-            let json_string = action.to_json();
-            let mut req = client_stream.send_bytes_request();
-            web_socket::encode_text_message(req.get(), &json_string);
-            let promise = req.send().promise.map_ok(|_| ());
-            Promise::from_future(task.and_then(|_| promise))
-        }
+/// How often an entry that is working gets checked again.
+const HEALTH_CHECK_INTERVAL_MILLIS: u64 = 6 * 60 * 60 * 1000;
 
-        let id = self.inner.borrow().next_id;
-        self.inner.borrow_mut().next_id = id + 1;
+/// First retry delay after a transient (`Disconnected`) error. Doubles after each failure.
+const DISCONNECTED_RETRY_MILLIS: u64 = 5 * 60 * 1000;
 
-        self.inner.borrow_mut().subscribers.insert(id, client_stream.clone());
+/// First retry delay after a permanent (`Failed`) error. Doubles after each failure.
+const FAILED_RETRY_MILLIS: u64 = 60 * 60 * 1000;
 
-        let mut task = Promise::ok(());
+/// Upper bound on the retry delay.
+const MAX_RETRY_MILLIS: u64 = 7 * 24 * 60 * 60 * 1000;
 
-        task = send_action(task, &client_stream, Action::CanWrite(can_write));
-        task = send_action(task, &client_stream, Action::UserId(user_id));
-        task = send_action(task, &client_stream,
-                           Action::Description(self.inner.borrow().description.clone()));
+/// How often we drop the identities that nothing refers to anymore.
+const IDENTITY_GC_INTERVAL: ::std::time::Duration = ::std::time::Duration::from_secs(24 * 60 * 60);
 
-        let mut added_by_identities: HashSet<String> = HashSet::new();
+/// How often the health checker looks for entries that are due.
+const HEALTH_CHECK_TICK: ::std::time::Duration = ::std::time::Duration::from_secs(60);
 
-        for (t, v) in &self.inner.borrow().views {
-            if let &Some(ref id) = &v.added_by {
-                added_by_identities.insert(id.clone());
-            }
+#[derive(Clone, Debug)]
+struct EntryHealth {
+    status: HealthStatus,
 
-            task = send_action(
-                task, &client_stream,
-                Action::Insert {
-                    token: t.clone(),
-                    data: v.clone()
-                }
-            );
-        }
+    /// Number of failures in a row.
+    failures: u32,
 
-        for (t, vi) in &self.inner.borrow().view_infos {
-            task = send_action(
-                task, &client_stream,
-                Action::ViewInfo {
-                    token: t.clone(),
-                    data: vi.clone(),
-                }
-            );
-        }
+    /// Milliseconds since unix epoch of the last check, successful or not.
+    last_checked_at: Option<u64>,
 
-        self.inner.borrow_mut().tasks.add(task);
+    /// Milliseconds since unix epoch of the last successful check. Survives restarts through
+    /// the view info cache.
+    last_ok_at: Option<u64>,
 
-        for ref text_id in &added_by_identities {
-            let id = text_id.to_string();
-            let client_stream1 = client_stream.clone();
+    /// Milliseconds since unix epoch of the first of the current run of failures.
+    failing_since: Option<u64>,
 
-            let task = self.get_user_profile(text_id).and_then(move |profile_data| {
-                let action = Action::User { id: id, data: profile_data };
-                let json_string = action.to_json();
-                let mut req = client_stream1.send_bytes_request();
-                web_socket::encode_text_message(req.get(), &json_string);
-                req.send().promise.map_ok(|_| ())
-            });
+    last_error: Option<String>,
 
-            self.inner.borrow_mut().tasks.add(task);
+    next_check_at: u64,
+}
+
+impl EntryHealth {
+    fn new(last_ok_at: Option<u64>) -> EntryHealth {
+        EntryHealth {
+            status: HealthStatus::Unchecked,
+            failures: 0,
+            last_checked_at: None,
+            last_ok_at: last_ok_at,
+            failing_since: None,
+            last_error: None,
+            next_check_at: 0,
         }
+    }
 
-        capnp_rpc::new_client(
-            web_socket::Adapter::new(
-                WebSocketStream::new(id, self.clone()),
-                client_stream,
-                self.inner.borrow().tasks.clone()))
+    fn record<T>(&mut self, result: &Result<T, Error>, now: u64) {
+        self.status = HealthStatus::from_result(result);
+        self.last_checked_at = Some(now);
+        let delay = match self.status {
+            HealthStatus::Ok | HealthStatus::Unchecked => {
+                self.failures = 0;
+                self.last_ok_at = Some(now);
+                self.failing_since = None;
+                self.last_error = None;
+                HEALTH_CHECK_INTERVAL_MILLIS
+            }
+            HealthStatus::Disconnected | HealthStatus::Failed => {
+                if self.failures == 0 {
+                    self.failing_since = Some(now);
+                }
+                if let &Err(ref e) = result {
+                    self.last_error = Some(format!("{}", e));
+                }
+                self.failures += 1;
+                let base = if self.status == HealthStatus::Failed {
+                    FAILED_RETRY_MILLIS
+                } else {
+                    DISCONNECTED_RETRY_MILLIS
+                };
+                let factor = 1u64.checked_shl(self.failures - 1).unwrap_or(u64::MAX);
+                ::std::cmp::min(base.saturating_mul(factor), MAX_RETRY_MILLIS)
+            }
+        };
+        self.next_check_at = now + delay;
+    }
+
+    fn to_json(&self) -> String {
+        fn optional_time(t: Option<u64>) -> String {
+            match t {
+                Some(t) => format!("\"{}\"", t),
+                None => "null".into(),
+            }
+        }
+        format!("{{\"status\":\"{}\",\"failures\":{},\"lastCheckedAt\":{},\"lastOkAt\":{},\"nextCheckAt\":\"{}\"}}",
+                self.status.as_str(),
+                self.failures,
+                optional_time(self.last_checked_at),
+                optional_time(self.last_ok_at),
+                self.next_check_at)
     }
 }
 
-const ADD_GRAIN_ACTIVITY_INDEX: u16 = 0;
-const REMOVE_GRAIN_ACTIVITY_INDEX: u16 = 1;
-const EDIT_DESCRIPTION_ACTIVITY_INDEX: u16 = 2;
+#[derive(Clone, Debug)]
+struct ProfileData {
+    display_name: String,
+    picture_url: String,
 
-pub struct WebSession {
-    can_write: bool,
-    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-    context: session_context::Client,
-    saved_ui_views: SavedUiViewSet,
-    identity_id: Option<String>,
+    /// Milliseconds since unix epoch when this was retrieved from the identity.
+    fetched_at: u64,
 }
 
-impl WebSession {
-    pub fn new(user_info: user_info::Reader,
-               context: session_context::Client,
-               _params: web_session::params::Reader,
-               sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-               saved_ui_views: SavedUiViewSet)
-               -> ::capnp::Result<WebSession>
-    {
-        // Permission #0 is "write". Check if bit 0 in the PermissionSet is set.
-        let permissions = user_info.get_permissions()?;
-        let can_write = permissions.len() > 0 && permissions.get(0);
-        let identity_id = if user_info.has_identity_id() {
-            Some(::hex::encode(user_info.get_identity_id()?))
-        } else {
-            None
-        };
+/// How long a cached profile is used before we try to fetch it again.
+const PROFILE_TTL_MILLIS: u64 = 24 * 60 * 60 * 1000;
 
-        Ok(WebSession {
-            can_write: can_write,
-            sandstorm_api: sandstorm_api,
-            context: context,
-            saved_ui_views: saved_ui_views,
-            identity_id: identity_id,
+impl ProfileData {
+    fn from_cache(cached: cached_profile::Reader) -> ::capnp::Result<ProfileData> {
+        Ok(ProfileData {
+            display_name: cached.get_display_name()?.to_string()?,
+            picture_url: cached.get_picture_url()?.to_string()?,
+            fetched_at: cached.get_fetched_at(),
         })
+    }
 
-        // `UserInfo` is defined in `sandstorm/grain.capnp` and contains info like:
-        // - A stable ID for the user, so you can correlate sessions from the same user.
-        // - The user's display name, e.g. "Mark Miller", useful for identifying the user to other
-        //   users.
-        // - The user's permissions (seen above).
+    fn write_cache(&self, mut cached: cached_profile::Builder) {
+        cached.set_display_name(&self.display_name);
+        cached.set_picture_url(&self.picture_url);
+        cached.set_fetched_at(self.fetched_at);
+    }
 
-        // `WebSession::Params` is defined in `sandstorm/web-session.capnp` and contains info like:
-        // - The hostname where the grain was mapped for this user. Every time a user opens a grain,
-        //   it is mapped at a new random hostname for security reasons.
-        // - The user's User-Agent and Accept-Languages headers.
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"pictureUrl\":{}, \"displayName\":{}}}",
+            json_escape_str(&self.picture_url),
+            json_escape_str(&self.display_name))
+    }
+}
 
-        // `SessionContext` is defined in `sandstorm/grain.capnp` and implements callbacks for
-        // sharing/access control and service publishing/discovery.
+/// An entry of a collection that is nested inside this one.
+#[derive(Clone)]
+struct NestedEntry {
+    data: SavedUiViewData,
+    view_info: Result<ViewInfoData, Error>,
+
+    /// `None` if the entry is not itself a collection, or is nested more than
+    /// MAX_NESTING_DEPTH levels deep, so that we didn't look.
+    nested: Option<NestedCollection>,
+}
+
+impl NestedEntry {
+    fn to_json(&self) -> String {
+        format!("{{\"data\":{},\"viewInfo\":{},\"nested\":{}}}",
+                self.data.to_json(),
+                view_info_to_json(Some(&self.view_info)),
+                match self.nested {
+                    None => "null".into(),
+                    Some(ref n) => n.to_json(),
+                })
     }
 }
 
-impl ui_session::Server for WebSession {}
+/// The contents of an entry that is itself a collection.
+#[derive(Clone)]
+enum NestedCollection {
+    Entries(Vec<NestedEntry>),
 
-impl web_session::Server for WebSession {
-    async fn get(self: Rc<Self>,
-           params: web_session::GetParams,
-           mut results: web_session::GetResults)
-	-> Result<(), Error>
-    {
-        // HTTP GET request.
-        let path = params.get()?.get_path()?.to_str()?;
-        self.require_canonical_path(path)?;
+    /// The collection is one of its own ancestors, so we don't expand it again.
+    Cycle,
+}
 
-        if path == "" {
-            let text = "<!DOCTYPE html>\
-                       <html><head>\
-                       <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\">\
-                       <script type=\"text/javascript\" src=\"script.js\" async></script>
-                       </head><body><div id=\"main\"></div></body></html>";
-            let mut content = results.get().init_content();
-            content.set_mime_type("text/html; charset=UTF-8");
-            content.init_body().set_bytes(text.as_bytes());
-            Ok(())
-        } else if path == "script.js" {
-            self.read_file("/script.js.gz", results, "text/javascript; charset=UTF-8", Some("gzip"))
-        } else if path == "style.css" {
-            self.read_file("/style.css.gz", results, "text/css; charset=UTF-8", Some("gzip"))
-        } else {
-            let mut error = results.get().init_client_error();
-            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-            Ok(())
+impl NestedCollection {
+    fn to_json(&self) -> String {
+        match self {
+            &NestedCollection::Entries(ref entries) => {
+                let entries: Vec<String> = entries.iter().map(|e| e.to_json()).collect();
+                format!("{{\"entries\":[{}]}}", entries.join(","))
+            }
+            &NestedCollection::Cycle => "{\"cycle\":true}".into(),
         }
     }
+}
 
-    async fn post(self: Rc<Self>,
-            params: web_session::PostParams,
-            mut results: web_session::PostResults)
-            -> Result<(), Error>
-    {
-        let path = {
-            let path = params.get()?.get_path()?.to_str()?;
-            self.require_canonical_path(path)?;
-            path.to_string()
-        };
+fn view_info_to_json(view_info: Option<&Result<ViewInfoData, ViewInfoError>>) -> String {
+    match view_info {
+        None => "null".into(),
+        Some(&Ok(ref data)) => data.to_json(),
+        Some(&Err(ref e)) => e.to_json(),
+    }
+}
+
+#[derive(Clone)]
+enum Action {
+    Insert { token: String, data: SavedUiViewData },
+    Remove { token: String },
+    ViewInfo { token: String, data: Result<ViewInfoData, ViewInfoError> },
+    Health { token: String, data: EntryHealth },
+    Nested { token: String, data: NestedCollection },
+    Transferred { id: u64, token: String, result: Result<(), Error> },
+    TransferDone { id: u64 },
+    BulkResult { id: u64, token: String, total: usize, result: Result<(), Error> },
+    BulkDone { id: u64 },
+    Quarantined { token: String, error: String },
+    Unquarantined { token: String },
+    InsertLink { id: String, data: LinkData },
+    RemoveLink { id: String },
+    InsertNote { id: String, data: NoteData },
+    RemoveNote { id: String },
+    InsertFile { id: String, data: FileData },
+    RemoveFile { id: String },
+
+    /// JSON describing the mirroring links, as rendered by `Mirror::to_json()`.
+    MirrorStatus(String),
+    CanWrite(bool),
+    UserId(Option<IdentityId>),
+    Description(String),
+    History(HistoryRecord),
+    User { id: IdentityId, data: ProfileData },
+}
+
+impl Action {
+    fn to_json(&self) -> String {
+        match self {
+            &Action::Insert { ref token, ref data } => {
+                format!("{{\"insert\":{{\"token\":\"{}\",\"data\":{} }} }}",
+                        token, data.to_json())
+            }
+            &Action::Remove { ref token } => {
+                format!("{{\"remove\":{{\"token\":\"{}\"}}}}", token)
+            }
+            &Action::ViewInfo { ref token, data: Ok(ref data) } => {
+                format!("{{\"viewInfo\":{{\"token\":\"{}\",\"data\":{} }} }}",
+                        token, data.to_json())
+            }
+            &Action::ViewInfo { ref token, data: Err(ref e) } => {
+                // `data` holds what we retrieved before, if anything, for display.
+                format!("{{\"viewInfo\":{{\"token\":\"{}\",\"failed\":{},\"data\":{} }} }}",
+                        token,
+                        json_escape_str(&format!("{}", e.error)),
+                        e.cached.as_ref().map_or("null".into(), |c| c.to_json()))
+            }
+            &Action::Health { ref token, ref data } => {
+                format!("{{\"health\":{{\"token\":\"{}\",\"data\":{} }} }}",
+                        token, data.to_json())
+            }
+            &Action::Nested { ref token, ref data } => {
+                format!("{{\"nested\":{{\"token\":\"{}\",\"data\":{} }} }}",
+                        token, data.to_json())
+            }
+            &Action::Transferred { id, ref token, result: Ok(()) } => {
+                format!("{{\"transferred\":{{\"id\":{},\"token\":\"{}\"}}}}", id, token)
+            }
+            &Action::Transferred { id, ref token, result: Err(ref e) } => {
+                format!("{{\"transferred\":{{\"id\":{},\"token\":\"{}\",\"failed\":{}}}}}",
+                        id, token, json_escape_str(&format!("{}", e)))
+            }
+            &Action::BulkResult { id, ref token, total, result: Ok(()) } => {
+                format!("{{\"bulkResult\":{{\"id\":{},\"token\":\"{}\",\"total\":{}}}}}",
+                        id, token, total)
+            }
+            &Action::BulkResult { id, ref token, total, result: Err(ref e) } => {
+                format!("{{\"bulkResult\":{{\"id\":{},\"token\":\"{}\",\"total\":{},\"failed\":{}}}}}",
+                        id, token, total, json_escape_str(&format!("{}", e)))
+            }
+            &Action::BulkDone { id } => {
+                format!("{{\"bulkDone\":{{\"id\":{}}}}}", id)
+            }
+            &Action::Quarantined { ref token, ref error } => {
+                format!("{{\"quarantined\":{{\"token\":\"{}\",\"error\":{}}}}}",
+                        token, json_escape_str(error))
+            }
+            &Action::Unquarantined { ref token } => {
+                format!("{{\"unquarantined\":{{\"token\":\"{}\"}}}}", token)
+            }
+            &Action::TransferDone { id } => {
+                format!("{{\"transferDone\":{{\"id\":{}}}}}", id)
+            }
+            &Action::InsertLink { ref id, ref data } => {
+                format!("{{\"insertLink\":{{\"id\":\"{}\",\"data\":{} }} }}",
+                        id, data.to_json())
+            }
+            &Action::RemoveLink { ref id } => {
+                format!("{{\"removeLink\":{{\"id\":\"{}\"}}}}", id)
+            }
+            &Action::InsertNote { ref id, ref data } => {
+                format!("{{\"insertNote\":{{\"id\":\"{}\",\"data\":{} }} }}",
+                        id, data.to_json())
+            }
+            &Action::RemoveNote { ref id } => {
+                format!("{{\"removeNote\":{{\"id\":\"{}\"}}}}", id)
+            }
+            &Action::InsertFile { ref id, ref data } => {
+                format!("{{\"insertFile\":{{\"id\":\"{}\",\"data\":{} }} }}",
+                        id, data.to_json())
+            }
+            &Action::RemoveFile { ref id } => {
+                format!("{{\"removeFile\":{{\"id\":\"{}\"}}}}", id)
+            }
+            &Action::MirrorStatus(ref json) => {
+                format!("{{\"mirror\":{}}}", json)
+            }
+
+            &Action::CanWrite(b) => {
+                format!("{{\"canWrite\":{}}}", b)
+            }
+            &Action::UserId(ref id) => {
+                format!("{{\"userId\":{}}}", optional_identity_to_json(id))
+            }
+            &Action::Description(ref s) => {
+                format!("{{\"description\":{}}}", json_escape_str(s))
+            }
+            &Action::History(ref record) => {
+                format!("{{\"history\":{}}}", record.to_json())
+            }
+            &Action::User { ref id, ref data } => {
+                format!(
+                    "{{\"user\":{{\"id\":{}, \"data\":{} }}}}",
+                    json_escape_str(&id.to_string()), data.to_json())
+            }
+        }
+    }
+}
+
+fn url_of_static_asset(asset: static_asset::Client) -> Promise<String, Error> {
+    Promise::from_future(asset.get_url_request().send().promise.map(
+        move |r| match r {
+            Ok(response) => {
+                let result = response.get()?;
+                let protocol = match result.get_protocol()? {
+                    static_asset::Protocol::Https => "https".to_string(),
+                    static_asset::Protocol::Http => "http".to_string(),
+                };
+
+                Ok(format!("{}://{}", protocol, result.get_host_path()?.to_str()?))
+            }
+            Err(e) => Err(e),
+        }
+    ))
+}
+
+fn decode_token(token: &str) -> Result<Vec<u8>, Error> {
+    match base64::engine::general_purpose::URL_SAFE.decode(token) {
+        Ok(b) => Ok(b),
+        Err(e) => Err(Error::failed(format!("{}", e))),
+    }
+}
+
+/// Reads the metadata of an entry, as serialized by `SavedUiViewData::to_bytes()`.
+fn read_entry(bytes: &[u8]) -> ::capnp::Result<SavedUiViewData> {
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default())?;
+    SavedUiViewData::from_metadata(message.get_root()?)
+}
+
+fn read_entry_file(path: &::std::path::Path) -> ::capnp::Result<SavedUiViewData> {
+    read_entry(&::std::fs::read(path)?)
+}
+
+fn read_message_file(path: &::std::path::Path)
+                     -> ::capnp::Result<::capnp::message::Reader<::capnp::serialize::OwnedSegments>>
+{
+    let mut reader = ::std::fs::File::open(path)?;
+    ::capnp::serialize::read_message(&mut reader, Default::default())
+}
+
+/// The `addedBy` of the entry in `path`, if that much of it can be read, even if the rest of
+/// it can't.
+fn added_by_of_entry_file(path: &::std::path::Path) -> Option<IdentityId> {
+    let bytes = ::std::fs::read(path).ok()?;
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default()).ok()?;
+    let metadata: ui_view_metadata::Reader = message.get_root().ok()?;
+    if !metadata.has_added_by() {
+        return None
+    }
+    IdentityId::parse(metadata.get_added_by().ok()?.to_str().ok()?).ok()
+}
+
+/// Moves the file at `path`, which can't be loaded, into `root`/set-aside/`kind`, so that it
+/// doesn't keep the collection from starting and can still be recovered by hand.
+fn set_aside(root: &::std::path::Path, kind: &str, path: &::std::path::Path, error: &Error)
+             -> ::capnp::Result<()>
+{
+    println!("setting aside unreadable {:?}: {}", path, error);
+    let directory = root.join("set-aside").join(kind);
+    ::std::fs::create_dir_all(&directory)?;
+    let name = match path.file_name() {
+        Some(n) => n,
+        None => return Err(Error::failed(format!("not a file path: {:?}", path))),
+    };
+    durable::rename(path, &directory.join(name))?;
+    Ok(())
+}
+
+/// Calls getViewInfo() on `view`, then get_url() on the grain static asset.
+fn get_view_info(view: ui_view::Client) -> Promise<ViewInfoData, Error> {
+    Promise::from_future(view.get_view_info_request().send().promise.and_then(move |response| {
+        let view_info = pry!(response.get());
+        let app_title = pry!(pry!(pry!(view_info.get_app_title()).get_default_text()).to_string());
+        Promise::from_future(url_of_static_asset(pry!(view_info.get_grain_icon())).map(move |url| {
+            Ok(ViewInfoData {
+                app_title: app_title,
+                grain_icon_url: url?,
+                fetched_at: now_millis()?,
+                stale: false,
+            })
+        }))
+    }))
+}
+
+/// How many levels of collections we expand below the top-level one.
+const MAX_NESTING_DEPTH: u32 = 4;
+
+/// Lists the entries of `view` through a `CollectionSession`, recursing into entries that are
+/// themselves collections. `ancestors` holds the IDs of the collections on the path from the top
+/// level down to, but not including, `view`, which is `depth` levels down. Resolves to `None` if
+/// `view` turns out not to be a collection, which we can only tell from its refusal to open the
+/// session, or if it is too deep to look into. Calls on the entries wait for a slot from
+/// `saved_ui_views`, since they may wake up grains.
+fn fetch_nested_collection(saved_ui_views: SavedUiViewSet,
+                           view: ui_view::Client,
+                           mut ancestors: Vec<String>,
+                           depth: u32)
+                           -> Promise<Option<NestedCollection>, Error>
+{
+    Promise::from_future(async move {
+        if depth > MAX_NESTING_DEPTH {
+            return Ok(None)
+        }
+        let null_context: session_context::Client = capnp_rpc::new_client(NullSessionContext);
+        let session = match open_collection_session(view, null_context).await {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+        // Listing restores every entry of the collection, so rule out a cycle first.
+        let response = session.get_id_request().send().promise.await?;
+        let id = response.get()?.get_id()?.to_string()?;
+        if ancestors.contains(&id) {
+            return Ok(Some(NestedCollection::Cycle))
+        }
+        ancestors.push(id);
+
+        let response = session.list_entries_request().send().promise.await?;
+        let results = response.get()?;
+        let mut children = Vec::new();
+        for entry in results.get_entries()?.iter() {
+            let data = SavedUiViewData::from_metadata(entry.get_metadata()?)?;
+            let view: Result<ui_view::Client, Error> = entry.get_view();
+            let ancestors = ancestors.clone();
+            let saved_ui_views = saved_ui_views.clone();
+            children.push(async move {
+                let view = match view {
+                    Ok(v) => v,
+                    Err(e) => return NestedEntry { data: data, view_info: Err(e), nested: None },
+                };
+                let view_info = match saved_ui_views.restore_slot().await {
+                    Ok(_slot) => get_view_info(view.clone()).await,
+                    Err(e) => Err(e),
+                };
+                let nested = if view_info.is_ok() {
+                    // Failing to expand a grandchild shouldn't hide the rest of the tree.
+                    fetch_nested_collection(saved_ui_views, view, ancestors, depth + 1)
+                        .await.unwrap_or(None)
+                } else {
+                    None
+                };
+                NestedEntry { data: data, view_info: view_info, nested: nested }
+            });
+        }
+
+        Ok(Some(NestedCollection::Entries(::futures::future::join_all(children).await)))
+    })
+}
+
+/// Returns 128 random bits, encoded in hexadecimal format.
+fn random_id() -> ::capnp::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 16];
+    ::std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(::hex::encode(&bytes))
+}
+
+/// Milliseconds since unix epoch.
+fn now_millis() -> ::capnp::Result<u64> {
+    let dur = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map_err(|e| Error::failed(format!("{}", e)))?;
+    Ok(dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000000) as u64)
+}
+
+/// Reads this grain's collection ID, choosing a new random one if there isn't one yet.
+fn read_or_create_collection_id<P>(tmp_dir: &::std::path::Path, path: P) -> ::capnp::Result<String>
+    where P: AsRef<::std::path::Path>
+{
+    use std::io::Read;
+    match ::std::fs::File::open(&path) {
+        Ok(mut f) => {
+            let mut result = String::new();
+            f.read_to_string(&mut result)?;
+            Ok(result)
+        }
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+            let result = random_id()?;
+            durable::write_bytes_atomically(tmp_dir, path.as_ref(), result.as_bytes())?;
+            Ok(result)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Clone)]
+enum MirrorChangeKind {
+    Upsert { data: SavedUiViewData, view: Option<ui_view::Client> },
+    Remove,
+}
+
+/// A change to one entry of a mirrored collection. See `MirrorChange` in collections.capnp.
+#[derive(Clone)]
+struct MirrorChange {
+    entry_id: String,
+    timestamp: u64,
+    kind: MirrorChangeKind,
+}
+
+impl MirrorChange {
+    fn read(change: mirror_change::Reader) -> ::capnp::Result<MirrorChange> {
+        let kind = match change.which()? {
+            mirror_change::Upsert(upsert) => MirrorChangeKind::Upsert {
+                data: SavedUiViewData::from_metadata(upsert.get_metadata()?)?,
+                view: upsert.get_view().ok(),
+            },
+            mirror_change::Remove(()) => MirrorChangeKind::Remove,
+        };
+        Ok(MirrorChange {
+            entry_id: change.get_entry_id()?.to_string()?,
+            timestamp: change.get_timestamp(),
+            kind: kind,
+        })
+    }
+
+    fn read_list(changes: ::capnp::struct_list::Reader<'_, mirror_change::Owned>)
+                 -> ::capnp::Result<Vec<MirrorChange>>
+    {
+        let mut result = Vec::new();
+        for change in changes.iter() {
+            result.push(MirrorChange::read(change)?);
+        }
+        Ok(result)
+    }
+
+    fn write(&self, mut change: mirror_change::Builder) {
+        change.set_entry_id(&self.entry_id);
+        change.set_timestamp(self.timestamp);
+        match self.kind {
+            MirrorChangeKind::Upsert { ref data, ref view } => {
+                let mut upsert = change.init_upsert();
+                data.write_metadata(upsert.reborrow().init_metadata());
+                if let Some(ref v) = *view {
+                    upsert.set_view(v.clone());
+                }
+            }
+            MirrorChangeKind::Remove => change.set_remove(()),
+        }
+    }
+
+    fn write_list(changes: &[MirrorChange],
+                  mut list: ::capnp::struct_list::Builder<'_, mirror_change::Owned>)
+    {
+        for (idx, change) in changes.iter().enumerate() {
+            change.write(list.reborrow().get(idx as u32));
+        }
+    }
+}
+
+#[derive(Clone)]
+enum MirrorSyncState {
+    Connecting,
+    Synced { at: u64 },
+    Failed(String),
+}
+
+/// State of the mirroring links of this collection.
+struct Mirror {
+    /// Where we keep the sturdyref of the collection that we initiated a link to.
+    peer_token_path: ::std::path::PathBuf,
+
+    /// Log of `TombstoneRecord`s, one per batch of removed entries.
+    tombstone_log: ::std::path::PathBuf,
+
+    /// When each removed entry was removed, by entry ID.
+    tombstones: HashMap<String, u64>,
+
+    /// Listeners of the collections that we are currently linked with. Only the link in
+    /// `outbound` is persisted: links that other collections initiated with us live in memory,
+    /// and resume when the initiator reconnects after either side restarts.
+    peers: HashMap<u64, mirror_listener::Client>,
+    next_peer_id: u64,
+
+    /// State of the link that we initiated, if any.
+    outbound: Option<(u64, MirrorSyncState)>,
+}
+
+impl Mirror {
+    /// Whether we accept changes from `peer_id`: it is still linked, or it is the collection
+    /// that we are connecting to, which may send changes before `link()` returns.
+    fn accepts(&self, peer_id: u64) -> bool {
+        self.peers.contains_key(&peer_id) ||
+            matches!(self.outbound, Some((id, MirrorSyncState::Connecting)) if id == peer_id)
+    }
+
+    fn new<P>(directory: P, tmp_dir: &::std::path::Path) -> ::capnp::Result<Mirror>
+        where P: AsRef<::std::path::Path>
+    {
+        ::std::fs::create_dir_all(&directory)?;
+        let tombstone_log = directory.as_ref().join("tombstones");
+        let tombstones = read_tombstones(&tombstone_log, tmp_dir)?;
+
+        let mut peer_token_path = directory.as_ref().to_path_buf();
+        peer_token_path.push("peer");
+
+        Ok(Mirror {
+            peer_token_path: peer_token_path,
+            tombstone_log: tombstone_log,
+            tombstones: tombstones,
+            peers: HashMap::new(),
+            next_peer_id: 0,
+            outbound: None,
+        })
+    }
+
+    fn record_tombstone(&mut self, entry_id: &str, timestamp: u64) -> ::capnp::Result<()> {
+        self.record_tombstones(&[entry_id.to_string()], timestamp)
+    }
+
+    /// Remembers that the entries `entry_ids` were removed at `timestamp`, with a single write.
+    fn record_tombstones(&mut self, entry_ids: &[String], timestamp: u64) -> ::capnp::Result<()> {
+        let entry_ids: Vec<&String> = entry_ids.iter()
+            .filter(|id| !id.is_empty() && self.tombstones.get(*id).map_or(true, |&t| t < timestamp))
+            .collect();
+        if entry_ids.is_empty() {
+            return Ok(())
+        }
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut record: tombstone_record::Builder = message.init_root();
+            record.set_timestamp(timestamp);
+            let mut list = record.init_entry_ids(entry_ids.len() as u32);
+            for (idx, entry_id) in entry_ids.iter().enumerate() {
+                list.set(idx as u32, &entry_id[..]);
+            }
+        }
+        durable::append_record(&self.tombstone_log, &::capnp::serialize::write_message_to_words(&message))?;
+        for entry_id in entry_ids {
+            self.tombstones.insert(entry_id.clone(), timestamp);
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> String {
+        let outbound = match self.outbound {
+            None => "null".into(),
+            Some((_, MirrorSyncState::Connecting)) => "{\"state\":\"connecting\"}".into(),
+            Some((_, MirrorSyncState::Synced { at })) => {
+                format!("{{\"state\":\"synced\",\"lastSynced\":\"{}\"}}", at)
+            }
+            Some((_, MirrorSyncState::Failed(ref e))) => {
+                format!("{{\"state\":\"failed\",\"error\":{}}}", json_escape_str(e))
+            }
+        };
+        format!("{{\"peers\":{},\"outbound\":{}}}", self.peers.len(), outbound)
+    }
+}
+
+/// Version of the document served at export.json. Bump it when a change to the format could
+/// break readers, and describe the change in README.md.
+const EXPORT_VERSION: u32 = 1;
+
+/// How many entries go into each chunk of a streamed export.
+const EXPORT_ENTRIES_PER_CHUNK: usize = 100;
+
+/// How many entries we restore at once, unless overridden by the
+/// `COLLECTIONS_MAX_CONCURRENT_RESTORES` environment variable.
+const DEFAULT_MAX_CONCURRENT_RESTORES: usize = 8;
+
+struct Reaper;
+
+impl Finisher<Error> for Reaper {
+    fn task_failed(&mut self, error: Error) {
+        // TODO better message.
+        println!("task failed: {}", error);
+    }
+}
+
+struct SavedUiViewSetInner {
+    /// Where the collection is stored; /var in a grain.
+    root: ::std::path::PathBuf,
+    tmp_dir: ::std::path::PathBuf,
+
+    /// Metadata of the entries in `views`.
+    entry_store: EntryStore,
+
+    link_dir: ::std::path::PathBuf,
+    note_dir: ::std::path::PathBuf,
+
+    /// Holds `content` and `metadata` subdirectories, each with one file per uploaded file.
+    file_dir: ::std::path::PathBuf,
+
+    /// Holds the last successfully retrieved view info of each entry, keyed by token.
+    view_info_dir: ::std::path::PathBuf,
+
+    /// Holds the metadata of the entries that could not be read, keyed by token.
+    quarantine_dir: ::std::path::PathBuf,
+
+    /// Contents of `quarantine_dir`, with the reason each entry could not be loaded.
+    quarantined: HashMap<String, String>,
+
+    /// Entries whose view info was requested explicitly, e.g. because they were just added.
+    /// These are retrieved even if nobody is looking.
+    urgent_view_info_queue: ::std::collections::VecDeque<String>,
+
+    /// Entries whose view info should be refreshed once someone opens the collection.
+    deferred_view_info_queue: ::std::collections::VecDeque<String>,
+
+    /// Callers of `restore_slot()` that are waiting for a slot. Served after the urgent queue
+    /// and before the deferred one.
+    waiting_restores: ::std::collections::VecDeque<oneshot::Sender<RestoreSlot>>,
+
+    /// Number of `retrieve_view_info` chains and `RestoreSlot`s currently alive.
+    restores_in_flight: usize,
+
+    /// Upper bound on `restores_in_flight`, to avoid flooding the Sandstorm backend with
+    /// `restore` calls when a large collection starts up or is listed in full.
+    max_concurrent_restores: usize,
+
+    /// Invariant: Every entry in this map has been persisted to the filesystem and has sent
+    /// out Action::Insert messages to each subscriber.
+    views: HashMap<String, SavedUiViewData>,
+
+    /// The outcome of the latest attempt to retrieve each entry's view info. Entries whose view
+    /// info has only been loaded from the on-disk cache so far have a stale `Ok`.
+    view_infos: HashMap<String, Result<ViewInfoData, ViewInfoError>>,
+
+    /// When each entry was last checked and whether that worked.
+    health: HashMap<String, EntryHealth>,
+
+    /// Entries that are links to web pages, keyed by random IDs. Like `views`, every entry here
+    /// has been persisted and announced to each subscriber.
+    links: HashMap<String, LinkData>,
+
+    /// Headings, paragraphs and dividers, keyed by random IDs. Same invariant as `views`.
+    notes: HashMap<String, NoteData>,
+
+    /// Uploaded files, keyed by random IDs. Same invariant as `views`.
+    files: HashMap<String, FileData>,
+
+    /// Contents of the entries that are themselves collections.
+    nested: HashMap<String, NestedCollection>,
+
+    /// Entries whose contents we have looked at since startup, collections or not. Routine
+    /// refreshes leave them alone, since looking again wakes up the grain, and for a collection,
+    /// every grain in it. See `retrieve_view_info_on_demand()`.
+    nested_known: HashSet<String>,
+
+    /// Random ID that identifies this collection to other collections that contain it.
+    collection_id: String,
+
+    next_id: u64,
+    next_transfer_id: u64,
+    next_bulk_id: u64,
+    subscribers: HashMap<u64, web_socket_stream::Client>,
+    tasks: PollerHandle<Error>,
+    description: String,
+
+    /// Holds the last successfully retrieved profile of each contributor, keyed by identity ID.
+    profile_dir: ::std::path::PathBuf,
+
+    /// Contents of `profile_dir`.
+    profiles: HashMap<IdentityId, ProfileData>,
+
+    /// Identities whose profiles are being fetched right now.
+    profiles_in_flight: HashSet<IdentityId>,
+
+    /// Whether titles follow the grains' own titles. Persisted in `root`/settings. Only grains
+    /// whose views implement `TitledView` report their titles, and none do yet.
+    sync_titles: bool,
+
+    /// Every record in `root`/history, oldest first.
+    history: Vec<HistoryRecord>,
+
+    /// Number of open sessions of each identity. Their identities are kept by `collect_identity_garbage()`.
+    active_sessions: HashMap<IdentityId, usize>,
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    identity_map: IdentityMap,
+    mirror: Mirror,
+}
+
+impl SavedUiViewSetInner {
+    fn get_saved_data<'a>(&'a self, token: &'a String) -> Option<&'a SavedUiViewData> {
+        self.views.get(token)
+    }
+}
+
+/// Permission to run one restore, or one call that may wake up a grain, without exceeding
+/// `max_concurrent_restores`. See `SavedUiViewSet::restore_slot()`.
+struct RestoreSlot {
+    saved_ui_views: SavedUiViewSet,
+}
+
+impl Drop for RestoreSlot {
+    fn drop(&mut self) {
+        self.saved_ui_views.inner.borrow_mut().restores_in_flight -= 1;
+        self.saved_ui_views.start_queued_view_info_retrievals();
+    }
+}
+
+#[derive(Clone)]
+pub struct SavedUiViewSet {
+    inner: Rc<RefCell<SavedUiViewSetInner>>,
+}
+
+impl SavedUiViewSet {
+    /// Opens the collection stored under `root`, which is /var in a grain.
+    pub fn new<P>(root: P,
+                  sandstorm_api: &sandstorm_api::Client<::capnp::any_pointer::Owned>,
+                  identity_map: IdentityMap,
+                  max_concurrent_restores: usize,
+    )
+                  -> ::capnp::Result<SavedUiViewSet>
+        where P: AsRef<::std::path::Path>
+    {
+        let root = root.as_ref();
+        let tmp_dir = root.join("tmp");
+
+        // Clear and create tmp directory. Anything in it was left behind by writes that
+        // didn't finish, so the files they were meant to replace are still intact.
+        match ::std::fs::remove_dir_all(&tmp_dir) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        ::std::fs::create_dir_all(&tmp_dir)?;
+
+        let description = match ::std::fs::File::open(root.join("description")) {
+            Ok(mut f) => {
+                use std::io::Read;
+                let mut result = String::new();
+                f.read_to_string(&mut result)?;
+                result
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+                let result = "";
+                durable::write_bytes_atomically(&tmp_dir,
+                                                &root.join("description"),
+                                                result.as_bytes())?;
+                result.into()
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+
+        let collection_id = read_or_create_collection_id(&tmp_dir, root.join("collection-id"))?;
+
+        let sync_titles = match ::std::fs::File::open(root.join("settings")) {
+            Ok(mut f) => {
+                let message = ::capnp::serialize::read_message(&mut f, Default::default())?;
+                let settings: settings::Reader = message.get_root()?;
+                settings.get_sync_titles()
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        let history = read_history(&root.join("history"), &tmp_dir)?;
+        let mirror = Mirror::new(root.join("mirror"), &tmp_dir)?;
+        let entry_store = EntryStore::open(root.join("entries"), &tmp_dir)?;
+
+        let (tx, poller) = Poller::new(Box::new(Reaper));
+        tokio::task::spawn_local(poller.map_err(|_|()));
+
+        let result = SavedUiViewSet {
+            inner: Rc::new(RefCell::new(SavedUiViewSetInner {
+                root: root.to_path_buf(),
+                tmp_dir: tmp_dir.clone(),
+                entry_store: entry_store,
+                link_dir: root.join("links"),
+                note_dir: root.join("notes"),
+                file_dir: root.join("files"),
+                view_info_dir: root.join("view-info"),
+                quarantine_dir: root.join("quarantine"),
+                quarantined: HashMap::new(),
+                urgent_view_info_queue: ::std::collections::VecDeque::new(),
+                deferred_view_info_queue: ::std::collections::VecDeque::new(),
+                waiting_restores: ::std::collections::VecDeque::new(),
+                restores_in_flight: 0,
+                max_concurrent_restores: ::std::cmp::max(1, max_concurrent_restores),
+                views: HashMap::new(),
+                view_infos: HashMap::new(),
+                health: HashMap::new(),
+                links: HashMap::new(),
+                notes: HashMap::new(),
+                files: HashMap::new(),
+                nested: HashMap::new(),
+                nested_known: HashSet::new(),
+                collection_id: collection_id,
+                next_id: 0,
+                next_transfer_id: 0,
+                next_bulk_id: 0,
+                subscribers: HashMap::new(),
+                tasks: tx,
+                description: description,
+                profile_dir: root.join("profiles"),
+                profiles: HashMap::new(),
+                profiles_in_flight: HashSet::new(),
+                sync_titles: sync_titles,
+                history: history,
+                active_sessions: HashMap::new(),
+                sandstorm_api: sandstorm_api.clone(),
+                identity_map: identity_map,
+                mirror: mirror,
+            })),
+        };
+
+        ::std::fs::create_dir_all(root.join("view-info"))?;
+
+        ::std::fs::create_dir_all(root.join("quarantine"))?;
+        for quarantined_file in ::std::fs::read_dir(root.join("quarantine"))? {
+            let dir_entry = quarantined_file?;
+            let token: String = match dir_entry.file_name().to_str() {
+                None => {
+                    println!("malformed token: {:?}", dir_entry.file_name());
+                    continue
+                }
+                Some(s) => s.into(),
+            };
+            let error = match read_entry_file(&dir_entry.path()) {
+                Ok(_) => "could not be read at an earlier startup".into(),
+                Err(e) => format!("{}", e),
+            };
+            result.inner.borrow_mut().quarantined.insert(token, error);
+        }
+
+        let stored: Vec<(String, Vec<u8>)> = result.inner.borrow().entry_store.entries().iter()
+            .map(|(token, bytes)| (token.clone(), bytes.clone()))
+            .collect();
+        let mut fixes = Vec::new();
+        let unreadable: Vec<(String, String)> = result.inner.borrow().entry_store.unreadable()
+            .iter()
+            .map(|(token, e)| (token.clone(), format!("{}", e)))
+            .collect();
+        for (token, error) in unreadable {
+            // Nothing of its metadata can be salvaged, but editors can still drop its sturdyref.
+            println!("quarantining unreadable entry {}: {}", token, error);
+            result.quarantine(&token, &[], error)?;
+            fixes.push(entry_store::Change::Remove { token: token });
+        }
+        for (token, bytes) in stored {
+            let mut entry = match read_entry(&bytes) {
+                Ok(e) => e,
+                Err(e) => {
+                    // Don't let one bad entry keep the whole collection from starting.
+                    println!("quarantining unreadable entry {}: {}", token, e);
+                    result.quarantine(&token, &bytes, format!("{}", e))?;
+                    fixes.push(entry_store::Change::Remove { token: token });
+                    continue
+                }
+            };
+
+            if entry.entry_id.is_empty() {
+                // Saved before entries had IDs.
+                entry.entry_id = random_id()?;
+                fixes.push(entry_store::Change::Put { token: token.clone(), metadata: entry.to_bytes() });
+            }
+
+            result.inner.borrow_mut().views.insert(token.clone(), entry);
+
+            let mut last_ok_at = None;
+            match result.read_cached_view_info(&token) {
+                Ok(Some(view_info)) => {
+                    last_ok_at = Some(view_info.fetched_at);
+                    result.inner.borrow_mut().view_infos.insert(token.clone(), Ok(view_info));
+                }
+                Ok(None) => (),
+                Err(e) => println!("could not read cached view info for {}: {}", token, e),
+            }
+            result.inner.borrow_mut().health.insert(token.clone(), EntryHealth::new(last_ok_at));
+
+            // Wait until someone opens the collection before restoring the entry.
+            result.inner.borrow_mut().deferred_view_info_queue.push_back(token);
+        }
+        result.inner.borrow_mut().entry_store.apply(fixes)?;
+        result.finish_interrupted_removals();
+
+        ::std::fs::create_dir_all(root.join("links"))?;
+        for link_file in ::std::fs::read_dir(root.join("links"))? {
+            let dir_entry = link_file?;
+            let id: String = match dir_entry.file_name().to_str() {
+                None => {
+                    println!("malformed link ID: {:?}", dir_entry.file_name());
+                    continue
+                }
+                Some(s) => s.into(),
+            };
+            match read_message_file(&dir_entry.path())
+                .and_then(|message| LinkData::from_metadata(message.get_root()?))
+            {
+                Ok(link) => { result.inner.borrow_mut().links.insert(id, link); }
+                Err(e) => set_aside(root, "links", &dir_entry.path(), &e)?,
+            }
+        }
+
+        ::std::fs::create_dir_all(root.join("notes"))?;
+        for note_file in ::std::fs::read_dir(root.join("notes"))? {
+            let dir_entry = note_file?;
+            let id: String = match dir_entry.file_name().to_str() {
+                None => {
+                    println!("malformed note ID: {:?}", dir_entry.file_name());
+                    continue
+                }
+                Some(s) => s.into(),
+            };
+            match read_message_file(&dir_entry.path())
+                .and_then(|message| NoteData::from_metadata(message.get_root()?))
+            {
+                Ok(note) => { result.inner.borrow_mut().notes.insert(id, note); }
+                Err(e) => set_aside(root, "notes", &dir_entry.path(), &e)?,
+            }
+        }
+
+        ::std::fs::create_dir_all(root.join("files/content"))?;
+        ::std::fs::create_dir_all(root.join("files/metadata"))?;
+        for file_metadata_file in ::std::fs::read_dir(root.join("files/metadata"))? {
+            let dir_entry = file_metadata_file?;
+            let id: String = match dir_entry.file_name().to_str() {
+                None => {
+                    println!("malformed file ID: {:?}", dir_entry.file_name());
+                    continue
+                }
+                Some(s) => s.into(),
+            };
+            match read_message_file(&dir_entry.path())
+                .and_then(|message| FileData::from_metadata(message.get_root()?))
+            {
+                Ok(file) => { result.inner.borrow_mut().files.insert(id, file); }
+                Err(e) => {
+                    // The contents go along with the metadata, if there are any.
+                    set_aside(root, "files/metadata", &dir_entry.path(), &e)?;
+                    let content = root.join("files/content").join(&id);
+                    if content.exists() {
+                        set_aside(root, "files/content", &content, &e)?;
+                    }
+                }
+            }
+        }
+
+        ::std::fs::create_dir_all(root.join("profiles"))?;
+        for profile_file in ::std::fs::read_dir(root.join("profiles"))? {
+            let dir_entry = profile_file?;
+            let id = match dir_entry.file_name().to_str().map(IdentityId::parse) {
+                Some(Ok(id)) => id,
+                _ => {
+                    println!("malformed identity ID: {:?}", dir_entry.file_name());
+                    continue
+                }
+            };
+            match read_message_file(&dir_entry.path())
+                .and_then(|message| ProfileData::from_cache(message.get_root()?))
+            {
+                Ok(profile) => { result.inner.borrow_mut().profiles.insert(id, profile); }
+                Err(e) => set_aside(root, "profiles", &dir_entry.path(), &e)?,
+            }
+        }
+
+        let task = result.connect_mirror();
+        result.inner.borrow_mut().tasks.add(task);
+
+        let task = result.run_health_checks();
+        result.inner.borrow_mut().tasks.add(task);
+
+        let task = result.run_identity_garbage_collection();
+        result.inner.borrow_mut().tasks.add(task);
+
+        Ok(result)
+    }
+
+    fn restore(&self, token: &str) -> Promise<ui_view::Client, Error> {
+        let binary_token = pry!(decode_token(token));
+        let mut req = self.inner.borrow().sandstorm_api.restore_request();
+        req.get().set_token(&binary_token);
+        Promise::from_future(req.send().promise.map(|r| match r {
+            Ok(response) => response.get()?.get_cap().get_as_capability(),
+            Err(e) => Err(e),
+        }))
+    }
+
+    /// Schedules retrieval of the view info of `token`, ahead of any entries that are waiting for
+    /// a viewer to show up.
+    fn retrieve_view_info(&self,
+                          token: String) -> ::capnp::Result<()> {
+        if self.inner.borrow().links.contains_key(&token) {
+            // Links point outside of Sandstorm, so there is no view info to retrieve.
+            return Ok(())
+        }
+
+        decode_token(&token)?;
+
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.deferred_view_info_queue.retain(|t| *t != token);
+            if !inner.urgent_view_info_queue.contains(&token) {
+                inner.urgent_view_info_queue.push_back(token);
+            }
+        }
+        self.start_queued_view_info_retrievals();
+        Ok(())
+    }
+
+    /// Like `retrieve_view_info()`, but also looks at the contents of the entry again, for a
+    /// user who asked for it to be refreshed.
+    fn retrieve_view_info_on_demand(&self, token: String) -> ::capnp::Result<()> {
+        self.inner.borrow_mut().nested_known.remove(&token);
+        self.retrieve_view_info(token)
+    }
+
+    /// Moves the deferred entries that a viewer would see without an icon to the front of the
+    /// queue, most recently added first, and starts retrieving them.
+    fn prioritize_deferred_view_infos(&self) {
+        {
+            let mut inner = self.inner.borrow_mut();
+            let mut queue: Vec<String> = inner.deferred_view_info_queue.drain(..).collect();
+            queue.sort_by_key(|t| {
+                (inner.view_infos.contains_key(t),
+                 ::std::cmp::Reverse(inner.views.get(t).map(|v| v.date_added).unwrap_or(0)))
+            });
+            inner.deferred_view_info_queue = queue.into_iter().collect();
+        }
+        self.start_queued_view_info_retrievals();
+    }
+
+    /// Starts as many queued retrievals, and hands out as many waiting restore slots, as the
+    /// concurrency limit allows. Deferred entries are only started while there is at least one
+    /// subscriber.
+    fn start_queued_view_info_retrievals(&self) {
+        loop {
+            let token = {
+                let mut inner = self.inner.borrow_mut();
+                if inner.restores_in_flight >= inner.max_concurrent_restores {
+                    return
+                }
+                let token = match inner.urgent_view_info_queue.pop_front() {
+                    Some(t) => t,
+                    None => match inner.waiting_restores.pop_front() {
+                        Some(waiter) => {
+                            inner.restores_in_flight += 1;
+                            drop(inner);
+                            // If the waiter has given up, dropping the slot gives it back.
+                            let _ = waiter.send(RestoreSlot { saved_ui_views: self.clone() });
+                            continue
+                        }
+                        None if inner.subscribers.is_empty() => return,
+                        None => match inner.deferred_view_info_queue.pop_front() {
+                            Some(t) => t,
+                            None => return,
+                        }
+                    }
+                };
+                if !inner.views.contains_key(&token) {
+                    // Removed while it was queued.
+                    continue
+                }
+                inner.restores_in_flight += 1;
+                token
+            };
+            self.fetch_view_info(token);
+        }
+    }
+
+    /// Waits until fewer than `max_concurrent_restores` restores are running. The slot is held
+    /// until the returned `RestoreSlot` is dropped.
+    fn restore_slot(&self) -> Promise<RestoreSlot, Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.inner.borrow_mut().waiting_restores.push_back(sender);
+        self.start_queued_view_info_retrievals();
+        Promise::from_future(receiver.map_err(|_| Error::failed("restore slot was canceled".into())))
+    }
+
+    /// Like `restore()`, but waits for a slot first.
+    fn restore_in_turn(&self, token: &str) -> Promise<ui_view::Client, Error> {
+        let self1 = self.clone();
+        let token = token.to_string();
+        Promise::from_future(async move {
+            let _slot = self1.restore_slot().await?;
+            self1.restore(&token).await
+        })
+    }
+
+    fn fetch_view_info(&self, token: String) {
+        let self1 = self.clone();
+        let task = self.refresh_view_info(token).map(move |_| {
+            // Failures have already been recorded in `view_infos` and `health`.
+            self1.inner.borrow_mut().restores_in_flight -= 1;
+            self1.start_queued_view_info_retrievals();
+            Ok(())
+        });
+        self.inner.borrow_mut().tasks.add(task);
+    }
+
+    /// Retrieves the view info of `token` right away, bypassing the queue, and records the
+    /// outcome. Fails if the view info could not be retrieved.
+    fn refresh_view_info(&self, token: String) -> Promise<(), Error> {
+        // SandstormApi.restore, then call getViewInfo,
+        // then call get_url() on the grain static asset.
+
+        let self1 = self.clone();
+        Promise::from_future(self.restore(&token).and_then(move |view| {
+            let self2 = self1.clone();
+            let token1 = token.clone();
+            get_view_info(view.clone()).map_ok(move |view_info| {
+                self2.retrieve_title(token1.clone(), view.clone());
+                self2.retrieve_nested(token1, view);
+                view_info
+            })
+        }).map(move |result| {
+            if !self1.inner.borrow().views.contains_key(&token) {
+                // Removed while we were fetching.
+                return Ok(())
+            }
+
+            let health = {
+                let mut inner = self1.inner.borrow_mut();
+                let health = inner.health.entry(token.clone()).or_insert_with(|| EntryHealth::new(None));
+                health.record(&result, now_millis()?);
+                health.clone()
+            };
+            self1.send_action_to_subscribers(Action::Health {
+                token: token.clone(),
+                data: health,
+            });
+
+            let outcome = match result {
+                Ok(_) => Ok(()),
+                Err(ref e) => Err(e.clone()),
+            };
+            let result = match result {
+                Ok(view_info) => {
+                    if let Err(e) = self1.write_cached_view_info(&token, &view_info) {
+                        println!("could not cache view info for {}: {}", token, e);
+                    }
+                    Ok(view_info)
+                }
+                Err(e) => Err(self1.view_info_failure(&token, e)),
+            };
+
+            self1.inner.borrow_mut().view_infos.insert(token.clone(), result.clone());
+            self1.send_action_to_subscribers(Action::ViewInfo {
+                token: token,
+                data: result,
+            });
+
+            outcome
+        }))
+    }
+
+    /// Records that retrieving the view info of `token` failed with `error`, keeping what we
+    /// knew before so that the entry can still be displayed.
+    fn view_info_failure(&self, token: &str, error: Error) -> ViewInfoError {
+        let cached = match self.inner.borrow().view_infos.get(token) {
+            Some(previous) => last_known_view_info(previous).cloned().map(|mut view_info| {
+                view_info.stale = true;
+                view_info
+            }),
+            None => None,
+        };
+        ViewInfoError { error: error, cached: cached }
+    }
+
+    /// Returns the entries that could not be retrieved the last time we tried, as JSON.
+    fn broken_entries_to_json(&self) -> ::capnp::Result<String> {
+        let now = now_millis()?;
+        let mut entries = Vec::new();
+        for token in self.broken_tokens(true) {
+            let inner = self.inner.borrow();
+            let health = inner.health.get(&token);
+            let error = match (health.and_then(|h| h.last_error.clone()), inner.view_infos.get(&token)) {
+                (Some(e), _) => e,
+                (None, Some(&Err(ref e))) => format!("{}", e.error),
+                (None, _) => String::new(),
+            };
+            let failing_since = health.and_then(|h| h.failing_since);
+            entries.push(format!(
+                "{{\"token\":\"{}\",\"data\":{},\"status\":\"{}\",\"error\":{},\"failingSince\":{},\"failingForMillis\":{}}}",
+                token,
+                inner.views[&token].to_json(),
+                health.map(|h| h.status).unwrap_or(HealthStatus::Failed).as_str(),
+                json_escape_str(&error),
+                match failing_since {
+                    Some(t) => format!("\"{}\"", t),
+                    None => "null".into(),
+                },
+                match failing_since {
+                    Some(t) => format!("{}", now.saturating_sub(t)),
+                    None => "null".into(),
+                }));
+        }
+        Ok(format!("[{}]", entries.join(",")))
+    }
+
+    /// Tokens of the entries whose last retrieval failed, most recently added first. Unless
+    /// `include_disconnected`, only those whose grain refused the request.
+    fn broken_tokens(&self, include_disconnected: bool) -> Vec<String> {
+        let inner = self.inner.borrow();
+        self.tokens_by_date().into_iter().filter(|t| {
+            let status = match inner.view_infos.get(t) {
+                Some(&Err(ref e)) => HealthStatus::from_error(&e.error),
+                _ => inner.health.get(t).map(|h| h.status).unwrap_or(HealthStatus::Unchecked),
+            };
+            match status {
+                HealthStatus::Failed => true,
+                HealthStatus::Disconnected => include_disconnected,
+                HealthStatus::Ok | HealthStatus::Unchecked => false,
+            }
+        }).collect()
+    }
+
+    /// Sets aside the unreadable entry `token`, whose metadata is `contents`, and offers it to
+    /// editors, who can retry loading it, delete it, or drop its sturdyref and then delete it.
+    /// The caller is responsible for removing the entry from wherever it was read.
+    fn quarantine(&self, token: &str, contents: &[u8], error: String) -> ::capnp::Result<()> {
+        let (tmp_dir, path) = {
+            let inner = self.inner.borrow();
+            (inner.tmp_dir.clone(), inner.quarantine_dir.join(token))
+        };
+        durable::write_bytes_atomically(&tmp_dir, &path, contents)?;
+        self.send_action_to_subscribers(Action::Quarantined {
+            token: token.to_string(),
+            error: error.clone(),
+        });
+        self.inner.borrow_mut().quarantined.insert(token.to_string(), error);
+        Ok(())
+    }
+
+    fn quarantined_to_json(&self) -> String {
+        let inner = self.inner.borrow();
+        let mut tokens: Vec<&String> = inner.quarantined.keys().collect();
+        tokens.sort();
+        let entries: Vec<String> = tokens.into_iter().map(|t| {
+            format!("{{\"token\":\"{}\",\"error\":{}}}", t, json_escape_str(&inner.quarantined[t]))
+        }).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    fn quarantined_path(&self, token: &str) -> ::capnp::Result<::std::path::PathBuf> {
+        let inner = self.inner.borrow();
+        if !inner.quarantined.contains_key(token) {
+            return Err(Error::failed(format!("no such quarantined entry: {}", token)));
+        }
+        Ok(inner.quarantine_dir.join(token))
+    }
+
+    /// Tries again to load the quarantined entry `token`. On success, it becomes a regular entry.
+    fn retry_quarantined(&self, token: &str) -> ::capnp::Result<()> {
+        let path = self.quarantined_path(token)?;
+        let mut entry = match read_entry_file(&path) {
+            Ok(e) => e,
+            Err(e) => {
+                let error = format!("{}", e);
+                self.send_action_to_subscribers(Action::Quarantined {
+                    token: token.to_string(),
+                    error: error.clone(),
+                });
+                self.inner.borrow_mut().quarantined.insert(token.to_string(), error);
+                return Err(e)
+            }
+        };
+        if self.inner.borrow().views.contains_key(token) {
+            return Err(Error::failed(format!("entry already exists: {}", token)));
+        }
+        if entry.entry_id.is_empty() {
+            entry.entry_id = random_id()?;
+        }
+
+        self.insert_data(token.to_string(), entry)?;
+        durable::remove_file(&path)?;
+        self.inner.borrow_mut().quarantined.remove(token);
+        self.send_action_to_subscribers(Action::Unquarantined { token: token.to_string() });
+
+        self.inner.borrow_mut().health.insert(token.to_string(), EntryHealth::new(None));
+        self.retrieve_view_info(token.to_string())
+    }
+
+    /// Deletes the quarantined entry `token` without dropping its sturdyref.
+    fn remove_quarantined(&self, token: &str) -> ::capnp::Result<()> {
+        let path = self.quarantined_path(token)?;
+        durable::remove_file(&path)?;
+        self.inner.borrow_mut().quarantined.remove(token);
+        self.send_action_to_subscribers(Action::Unquarantined { token: token.to_string() });
+        Ok(())
+    }
+
+    /// Drops the sturdyref behind the quarantined entry `token` and then deletes the entry.
+    async fn drop_and_remove_quarantined(&self, token: &str) -> Result<(), Error> {
+        self.quarantined_path(token)?;
+        self.drop_sturdyref(token).await?;
+        self.remove_quarantined(token)
+    }
+
+    /// Refreshes all broken entries, or removes those whose grain refused the request, reporting
+    /// each outcome to websocket subscribers as `Action::BulkResult`, and each removal to
+    /// `context` as an activity. Returns the ID of the operation. Entries whose grain could not
+    /// be reached are never removed: their sturdyrefs likely still work, and once dropped they
+    /// can't be recovered.
+    ///
+    /// Refreshes happen one at a time. For removals, the sturdyrefs are dropped one at a time,
+    /// and then the entries whose sturdyrefs are gone are removed all at once.
+    ///
+    /// Removals are not mirrored: an entry that is broken here may work in a linked collection,
+    /// which then sends it back the next time the link is established.
+    fn start_bulk_operation(&self, remove: bool, context: session_context::Client) -> u64 {
+        let tokens = self.broken_tokens(!remove);
+        let id = self.inner.borrow().next_bulk_id;
+        self.inner.borrow_mut().next_bulk_id = id + 1;
+
+        let self1 = self.clone();
+        let total = tokens.len();
+        let task = async move {
+            let mut dropped = Vec::new();
+            for token in tokens {
+                let result = if remove {
+                    match self1.drop_sturdyref(&token).await {
+                        Ok(()) => {
+                            dropped.push(token);
+                            continue
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    self1.refresh_view_info(token.clone()).await
+                };
+                self1.send_action_to_subscribers(Action::BulkResult {
+                    id: id,
+                    token: token,
+                    total: total,
+                    result: result,
+                });
+            }
+
+            let removed = self1.remove_all_without_mirroring(&dropped);
+            for token in dropped {
+                let result = match removed {
+                    Ok(()) => {
+                        let mut req = context.activity_request();
+                        req.get().init_event().set_type(REMOVE_GRAIN_ACTIVITY_INDEX);
+                        req.send().promise.await.map(|_| ())
+                    }
+                    Err(ref e) => Err(e.clone()),
+                };
+                self1.send_action_to_subscribers(Action::BulkResult {
+                    id: id,
+                    token: token,
+                    total: total,
+                    result: result,
+                });
+            }
+            self1.send_action_to_subscribers(Action::BulkDone { id: id });
+            Ok::<(), Error>(())
+        };
+        self.inner.borrow_mut().tasks.add(task);
+        id
+    }
+
+    /// Periodically re-checks the entries that are due, while someone is looking at the collection.
+    /// Entries that have not been checked since startup are left to the startup queue.
+    fn run_health_checks(&self) -> Promise<(), Error> {
+        let self1 = self.clone();
+        Promise::from_future(async move {
+            loop {
+                tokio::time::delay_for(HEALTH_CHECK_TICK).await;
+                if self1.inner.borrow().subscribers.is_empty() {
+                    continue
+                }
+
+                let now = now_millis()?;
+                let due: Vec<String> = {
+                    let mut inner = self1.inner.borrow_mut();
+                    let mut due = Vec::new();
+                    for (token, health) in inner.health.iter_mut() {
+                        if health.last_checked_at.is_some() && health.next_check_at <= now {
+                            // Don't pick it up again on the next tick while it's still running.
+                            health.next_check_at = now + HEALTH_CHECK_INTERVAL_MILLIS;
+                            due.push(token.clone());
+                        }
+                    }
+                    due
+                };
+                for token in due {
+                    if let Err(e) = self1.retrieve_view_info(token) {
+                        println!("could not schedule health check: {}", e);
+                    }
+                }
+            }
+        })
+    }
+
+    fn read_cached_view_info(&self, token: &str) -> ::capnp::Result<Option<ViewInfoData>> {
+        let mut path = self.inner.borrow().view_info_dir.clone();
+        path.push(token);
+        let mut reader = match ::std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let message = ::capnp::serialize::read_message(&mut reader, Default::default())?;
+        Ok(Some(ViewInfoData::from_cache(message.get_root()?)?))
+    }
+
+    fn write_cached_view_info(&self, token: &str, view_info: &ViewInfoData) -> ::capnp::Result<()> {
+        let mut message = ::capnp::message::Builder::new_default();
+        view_info.write_cache(message.init_root());
+        let view_info_dir = self.inner.borrow().view_info_dir.clone();
+        self.write_message_file(&view_info_dir, token, &message)
+    }
+
+    /// If titles are being kept in sync and `view` reports its own title, updates the title of
+    /// `token` to match.
+    fn retrieve_title(&self, token: String, view: ui_view::Client) {
+        if !self.inner.borrow().sync_titles {
+            return
+        }
+        match self.inner.borrow().views.get(&token) {
+            Some(e) if !e.title_pinned => (),
+            _ => return,
+        }
+
+        let self1 = self.clone();
+        let titled = titled_view::Client { client: view.client };
+        let task = titled.get_title_request().send().promise.map(move |r| {
+            let response = match r {
+                Ok(r) => r,
+                Err(ref e) if e.kind == ::capnp::ErrorKind::Unimplemented => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let title = response.get()?.get_title()?.to_string()?;
+            let pinned = match self1.inner.borrow().views.get(&token) {
+                Some(e) => e.title_pinned || e.title == title,
+                None => true, // Removed while we were fetching.
+            };
+            if !title.is_empty() && !pinned {
+                self1.update_title(&token, title, None)?;
+            }
+            Ok(())
+        });
+        self.inner.borrow_mut().tasks.add(task);
+    }
+
+    /// Checks whether `view` is itself a collection, and if so, fetches its contents. Does
+    /// nothing if we already know.
+    fn retrieve_nested(&self, token: String, view: ui_view::Client) {
+        if self.inner.borrow().nested_known.contains(&token) {
+            return
+        }
+        let self1 = self.clone();
+        let ancestors = vec![self.inner.borrow().collection_id.clone()];
+        let task = fetch_nested_collection(self.clone(), view, ancestors, 1)
+            .map_ok(move |nested| {
+                if !self1.inner.borrow().views.contains_key(&token) {
+                    // Removed while we were fetching.
+                    return
+                }
+                self1.inner.borrow_mut().nested_known.insert(token.clone());
+                if let Some(nested) = nested {
+                    self1.inner.borrow_mut().nested.insert(token.clone(), nested.clone());
+                    self1.send_action_to_subscribers(Action::Nested {
+                        token: token,
+                        data: nested,
+                    });
+                }
+            });
+        self.inner.borrow_mut().tasks.add(task);
+    }
+
+    fn get_user_profile(&self,
+                        identity_id: &IdentityId) -> Promise<ProfileData, Error> {
+        Promise::from_future(self.inner.borrow_mut().identity_map.get(identity_id).and_then(move |identity| {
+            identity.get_profile_request().send().promise
+        }).and_then(move |response| {
+            let profile = pry!(pry!(response.get()).get_profile());
+            let display_name = pry!(pry!(pry!(profile.get_display_name()).get_default_text()).to_string());
+            Promise::from_future(url_of_static_asset(pry!(profile.get_picture())).map(move |url| {
+                Ok(ProfileData {
+                    display_name: display_name,
+                    picture_url: url?,
+                    fetched_at: now_millis()?,
+                })
+            }))
+        }))
+    }
+
+    /// Fetches the profile of `identity_id` in the background, unless we have a cached copy that
+    /// is younger than PROFILE_TTL_MILLIS, and sends it to every subscriber. If the identity
+    /// can't be restored, the cached copy, if any, stays in place.
+    fn refresh_user_profile(&self, identity_id: &IdentityId) -> ::capnp::Result<()> {
+        let now = now_millis()?;
+        let cached = self.inner.borrow().profiles.get(identity_id).cloned();
+        if let Some(p) = cached {
+            if p.fetched_at + PROFILE_TTL_MILLIS > now {
+                return self.backfill_added_by_name(identity_id, &p.display_name)
+            }
+        }
+        if !self.inner.borrow_mut().profiles_in_flight.insert(*identity_id) {
+            return Ok(())
+        }
+
+        let self1 = self.clone();
+        let id = *identity_id;
+        let task = self.get_user_profile(identity_id).map(move |r| {
+            self1.inner.borrow_mut().profiles_in_flight.remove(&id);
+            let profile = match r {
+                Ok(p) => p,
+                Err(e) => {
+                    if self1.inner.borrow().profiles.contains_key(&id) {
+                        println!("could not refresh profile of {}; keeping the cached one: {}", id, e);
+                        return Ok(())
+                    }
+                    if let Some(profile) = self1.fallback_profile(&id) {
+                        println!("could not fetch profile of {}; using the recorded name: {}", id, e);
+                        self1.send_action_to_subscribers(Action::User { id: id, data: profile });
+                        return Ok(())
+                    }
+                    return Err(e)
+                }
+            };
+
+            self1.backfill_added_by_name(&id, &profile.display_name)?;
+
+            let mut message = ::capnp::message::Builder::new_default();
+            profile.write_cache(message.init_root());
+            let profile_dir = self1.inner.borrow().profile_dir.clone();
+            if let Err(e) = self1.write_message_file(&profile_dir, &id.to_string(), &message) {
+                println!("could not cache profile of {}: {}", id, e);
+            }
+
+            self1.inner.borrow_mut().profiles.insert(id, profile.clone());
+            self1.send_action_to_subscribers(Action::User { id: id, data: profile });
+            Ok(())
+        });
+        self.inner.borrow_mut().tasks.add(task);
+        Ok(())
+    }
+
+    /// Builds a profile for `identity_id` out of the display name recorded in its entries.
+    fn fallback_profile(&self, identity_id: &IdentityId) -> Option<ProfileData> {
+        let inner = self.inner.borrow();
+        inner.views.values()
+            .filter(|v| v.added_by.as_ref() == Some(identity_id))
+            .filter_map(|v| v.added_by_name.clone())
+            .next()
+            .map(|name| ProfileData {
+                display_name: name,
+                picture_url: String::new(),
+                // Never fresh, so that we keep trying to fetch the real profile.
+                fetched_at: 0,
+            })
+    }
+
+    /// Records `display_name` in the entries added by `identity_id` that were saved without it.
+    fn backfill_added_by_name(&self, identity_id: &IdentityId, display_name: &str) -> ::capnp::Result<()> {
+        let tokens: Vec<String> = self.inner.borrow().views.iter()
+            .filter(|&(_, v)| v.added_by.as_ref() == Some(identity_id) && v.added_by_name.is_none())
+            .map(|(t, _)| t.clone())
+            .collect();
+        for token in tokens {
+            let mut entry = self.inner.borrow().views[&token].clone();
+            entry.added_by_name = Some(display_name.to_string());
+            // Not an edit, so `modified_at` stays the same and mirrors don't hear about it.
+            self.update_entry(token, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Identities that some entry, quarantined entry, history record or open session refers to.
+    /// Quarantined entries count so that retrying one doesn't find its `addedBy` gone.
+    fn referenced_identities(&self) -> HashSet<IdentityId> {
+        let inner = self.inner.borrow();
+        let quarantined: Vec<IdentityId> = inner.quarantined.keys()
+            .filter_map(|t| added_by_of_entry_file(&inner.quarantine_dir.join(t)))
+            .collect();
+        inner.views.values().filter_map(|v| v.added_by)
+            .chain(quarantined)
+            .chain(inner.links.values().filter_map(|l| l.added_by))
+            .chain(inner.notes.values().filter_map(|n| n.added_by))
+            .chain(inner.files.values().filter_map(|f| f.added_by))
+            .chain(inner.history.iter().filter_map(|r| r.by))
+            .chain(inner.active_sessions.keys().cloned())
+            .collect()
+    }
+
+    /// Drops the saved identities that nothing refers to anymore, and their cached profiles.
+    fn collect_identity_garbage(&self) -> ::capnp::Result<()> {
+        let keep = self.referenced_identities();
+        let report = self.inner.borrow_mut().identity_map.collect_garbage(&keep)?;
+        println!("identity garbage collection: kept {}, reclaimed {}, failed {}",
+                 report.kept, report.reclaimed, report.failed);
+
+        // Identities that could not be dropped are still saved, so their profiles stay.
+        let reclaimed: Vec<IdentityId> = {
+            let inner = self.inner.borrow();
+            inner.profiles.keys()
+                .filter(|&id| !keep.contains(id) && !inner.identity_map.contains(id))
+                .cloned()
+                .collect()
+        };
+        for id in reclaimed {
+            let path = self.inner.borrow().profile_dir.join(id.to_string());
+            if let Err(e) = durable::remove_file(&path) {
+                if e.kind() != ::std::io::ErrorKind::NotFound {
+                    println!("could not remove cached profile of {}: {}", id, e);
+                    continue
+                }
+            }
+            self.inner.borrow_mut().profiles.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Collects identity garbage now and then every IDENTITY_GC_INTERVAL.
+    fn run_identity_garbage_collection(&self) -> Promise<(), Error> {
+        let self1 = self.clone();
+        Promise::from_future(async move {
+            loop {
+                if let Err(e) = self1.collect_identity_garbage() {
+                    println!("identity garbage collection failed: {}", e);
+                }
+                tokio::time::delay_for(IDENTITY_GC_INTERVAL).await;
+            }
+        })
+    }
+
+    fn session_opened(&self, identity_id: IdentityId) {
+        *self.inner.borrow_mut().active_sessions.entry(identity_id).or_insert(0) += 1;
+    }
+
+    fn session_closed(&self, identity_id: IdentityId) {
+        let mut inner = self.inner.borrow_mut();
+        let remaining = match inner.active_sessions.get_mut(&identity_id) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            inner.active_sessions.remove(&identity_id);
+        }
+    }
+
+    fn update_description(&self, description: &[u8]) -> ::capnp::Result<()> {
+        let desc_string: String = match ::std::str::from_utf8(description) {
+            Err(e) => return Err(::capnp::Error::failed(format!("{}", e))),
+            Ok(d) => d.into(),
+        };
+
+        let (tmp_dir, path) = {
+            let inner = self.inner.borrow();
+            (inner.tmp_dir.clone(), inner.root.join("description"))
+        };
+        durable::write_bytes_atomically(&tmp_dir, &path, description)?;
+
+        self.inner.borrow_mut().description = desc_string.clone();
+        self.send_action_to_subscribers(Action::Description(desc_string));
+        Ok(())
+    }
+
+    /// Writes `message` to a temporary file and then moves it to `directory`/`name`.
+    fn write_message_file<A>(&self,
+                             directory: &::std::path::Path,
+                             name: &str,
+                             message: &::capnp::message::Builder<A>) -> ::capnp::Result<()>
+        where A: ::capnp::message::Allocator
+    {
+        let mut path = directory.to_path_buf();
+        path.push(name);
+
+        let tmp_dir = self.inner.borrow().tmp_dir.clone();
+        let bytes = ::capnp::serialize::write_message_to_words(message);
+        durable::write_bytes_atomically(&tmp_dir, &path, &bytes)?;
+        Ok(())
+    }
+
+    fn write_entry(&self, token: &str, entry: &SavedUiViewData) -> ::capnp::Result<()> {
+        self.inner.borrow_mut().entry_store.apply(vec![entry_store::Change::Put {
+            token: token.to_string(),
+            metadata: entry.to_bytes(),
+        }])
+    }
+
+    fn write_link(&self, id: &str, link: &LinkData) -> ::capnp::Result<()> {
+        let mut message = ::capnp::message::Builder::new_default();
+        link.write_metadata(message.init_root());
+        let link_dir = self.inner.borrow().link_dir.clone();
+        self.write_message_file(&link_dir, id, &message)
+    }
+
+    /// Adds a link entry and returns its ID.
+    fn insert_link(&self, url: String, title: String, added_by: Option<IdentityId>)
+                   -> ::capnp::Result<String>
+    {
+        let id = random_id()?;
+        let link = LinkData {
+            title: title,
+            url: url,
+            date_added: now_millis()?,
+            added_by: added_by,
+        };
+        self.write_link(&id, &link)?;
+        self.send_action_to_subscribers(Action::InsertLink { id: id.clone(), data: link.clone() });
+        self.inner.borrow_mut().links.insert(id.clone(), link);
+        Ok(id)
+    }
+
+    fn update_link(&self, id: &str, url: String, title: String) -> ::capnp::Result<()> {
+        let mut link = match self.inner.borrow().links.get(id) {
+            Some(l) => l.clone(),
+            None => return Err(Error::failed(format!("no such link: {}", id))),
+        };
+        link.url = url;
+        link.title = title;
+        self.write_link(id, &link)?;
+        self.send_action_to_subscribers(Action::InsertLink { id: id.to_string(), data: link.clone() });
+        self.inner.borrow_mut().links.insert(id.to_string(), link);
+        Ok(())
+    }
+
+    fn write_note(&self, id: &str, note: &NoteData) -> ::capnp::Result<()> {
+        let mut message = ::capnp::message::Builder::new_default();
+        note.write_metadata(message.init_root());
+        let note_dir = self.inner.borrow().note_dir.clone();
+        self.write_message_file(&note_dir, id, &message)
+    }
+
+    /// Adds a note and returns its ID. Without a position, the note goes above every entry.
+    fn insert_note(&self, form: NoteForm, added_by: Option<IdentityId>) -> ::capnp::Result<String> {
+        let id = random_id()?;
+        let date_added = now_millis()?;
+        let note = NoteData {
+            kind: form.kind.unwrap_or(note_metadata::Kind::Paragraph),
+            text: form.text.unwrap_or_default(),
+            position: form.position.unwrap_or(date_added),
+            date_added: date_added,
+            added_by: added_by,
+        };
+        self.write_note(&id, &note)?;
+        self.send_action_to_subscribers(Action::InsertNote { id: id.clone(), data: note.clone() });
+        self.inner.borrow_mut().notes.insert(id.clone(), note);
+        Ok(id)
+    }
+
+    fn update_note(&self, id: &str, form: NoteForm) -> ::capnp::Result<()> {
+        let mut note = match self.inner.borrow().notes.get(id) {
+            Some(n) => n.clone(),
+            None => return Err(Error::failed(format!("no such note: {}", id))),
+        };
+        if let Some(kind) = form.kind { note.kind = kind; }
+        if let Some(text) = form.text { note.text = text; }
+        if let Some(position) = form.position { note.position = position; }
+        self.write_note(id, &note)?;
+        self.send_action_to_subscribers(Action::InsertNote { id: id.to_string(), data: note.clone() });
+        self.inner.borrow_mut().notes.insert(id.to_string(), note);
+        Ok(())
+    }
+
+    fn remove_note(&self, id: &str) -> ::capnp::Result<()> {
+        if !self.inner.borrow().notes.contains_key(id) {
+            return Err(Error::failed(format!("no such note: {}", id)));
+        }
+        let mut path = self.inner.borrow().note_dir.clone();
+        path.push(id);
+        durable::remove_file(&path)?;
+        self.send_action_to_subscribers(Action::RemoveNote { id: id.to_string() });
+        self.inner.borrow_mut().notes.remove(id);
+        Ok(())
+    }
+
+    fn file_path(&self, subdirectory: &str, id: &str) -> ::std::path::PathBuf {
+        let mut path = self.inner.borrow().file_dir.clone();
+        path.push(subdirectory);
+        path.push(id);
+        path
+    }
+
+    /// Returns an error with a `RequestEntityTooLarge` description if storing `size` more bytes,
+    /// in place of the file `replacing` if any, would exceed the limits.
+    fn check_file_quota(&self, size: u64, replacing: Option<&str>) -> Result<(), String> {
+        if size > MAX_FILE_SIZE {
+            return Err(format!("files may be at most {} bytes", MAX_FILE_SIZE));
+        }
+        let inner = self.inner.borrow();
+        let used: u64 = inner.files.iter()
+            .filter(|&(id, _)| Some(&id[..]) != replacing)
+            .map(|(_, f)| f.size)
+            .sum();
+        if used + size > FILE_QUOTA {
+            return Err(format!("this collection's files may take up at most {} bytes", FILE_QUOTA));
+        }
+        Ok(())
+    }
+
+    fn write_file(&self, id: &str, file: &FileData, content: &[u8]) -> ::capnp::Result<()> {
+        let tmp_dir = self.inner.borrow().tmp_dir.clone();
+        durable::write_bytes_atomically(&tmp_dir, &self.file_path("content", id), content)?;
+
+        let mut message = ::capnp::message::Builder::new_default();
+        file.write_metadata(message.init_root());
+        let metadata_dir = self.file_path("metadata", "");
+        self.write_message_file(&metadata_dir, id, &message)
+    }
+
+    /// Stores an uploaded file and returns its ID. The caller checks the quota.
+    fn insert_file(&self, name: String, mime_type: String, content: &[u8],
+                   added_by: Option<IdentityId>) -> ::capnp::Result<String>
+    {
+        let id = random_id()?;
+        let file = FileData {
+            name: name,
+            mime_type: mime_type,
+            size: content.len() as u64,
+            date_added: now_millis()?,
+            added_by: added_by,
+        };
+        self.write_file(&id, &file, content)?;
+        self.send_action_to_subscribers(Action::InsertFile { id: id.clone(), data: file.clone() });
+        self.inner.borrow_mut().files.insert(id.clone(), file);
+        Ok(id)
+    }
+
+    /// Replaces the contents of an uploaded file. The caller checks the quota.
+    fn update_file(&self, id: &str, mime_type: String, content: &[u8]) -> ::capnp::Result<()> {
+        let mut file = match self.inner.borrow().files.get(id) {
+            Some(f) => f.clone(),
+            None => return Err(Error::failed(format!("no such file: {}", id))),
+        };
+        file.mime_type = mime_type;
+        file.size = content.len() as u64;
+        self.write_file(id, &file, content)?;
+        self.send_action_to_subscribers(Action::InsertFile { id: id.to_string(), data: file.clone() });
+        self.inner.borrow_mut().files.insert(id.to_string(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, id: &str) -> ::capnp::Result<()> {
+        if !self.inner.borrow().files.contains_key(id) {
+            return Err(Error::failed(format!("no such file: {}", id)));
+        }
+        // Remove the metadata first, so that a crash in between leaves at worst an orphaned
+        // content file rather than an entry without contents.
+        durable::remove_file(&self.file_path("metadata", id))?;
+        durable::remove_file(&self.file_path("content", id))?;
+        self.send_action_to_subscribers(Action::RemoveFile { id: id.to_string() });
+        self.inner.borrow_mut().files.remove(id);
+        Ok(())
+    }
+
+    fn remove_link(&self, id: &str) -> ::capnp::Result<()> {
+        if !self.inner.borrow().links.contains_key(id) {
+            return Err(Error::failed(format!("no such link: {}", id)));
+        }
+        let mut path = self.inner.borrow().link_dir.clone();
+        path.push(id);
+        durable::remove_file(&path)?;
+        self.send_action_to_subscribers(Action::RemoveLink { id: id.to_string() });
+        self.inner.borrow_mut().links.remove(id);
+        Ok(())
+    }
+
+    fn insert(&mut self,
+              token: String,
+              title: String,
+              added_by: Option<IdentityId>,
+              added_by_name: Option<String>) -> ::capnp::Result<()> {
+        let date_added = now_millis()?;
+        let entry = SavedUiViewData {
+            title: title,
+            date_added: date_added,
+            added_by: added_by,
+            entry_id: random_id()?,
+            modified_at: date_added,
+            title_pinned: false,
+            added_by_name: added_by_name,
+        };
+
+        self.insert_data(token.clone(), entry)?;
+        self.send_mirror_upsert(&token);
+        Ok(())
+    }
+
+    fn insert_data(&self, token: String, entry: SavedUiViewData) -> ::capnp::Result<()> {
+        self.write_entry(&token, &entry)?;
+
+        if !self.inner.borrow().subscribers.is_empty() {
+            if let Some(ref id) = entry.added_by {
+                let cached = self.inner.borrow().profiles.get(id).cloned();
+                if let Some(profile_data) = cached {
+                    self.send_action_to_subscribers(
+                        Action::User { id: *id, data: profile_data });
+                }
+                self.refresh_user_profile(id)?;
+            }
+        }
+
+        self.send_action_to_subscribers(Action::Insert {
+            token: token.clone(),
+            data: entry.clone(),
+        });
+        self.inner.borrow_mut().views.insert(token, entry);
+
+        Ok(())
+    }
+
+    /// Replaces the metadata of an existing entry.
+    fn update_entry(&self, token: String, entry: SavedUiViewData) -> ::capnp::Result<()> {
+        self.write_entry(&token, &entry)?;
+
+        // The UI treats an insert of an existing token as a replacement.
+        self.send_action_to_subscribers(Action::Insert {
+            token: token.clone(),
+            data: entry.clone(),
+        });
+        self.inner.borrow_mut().views.insert(token, entry);
+        Ok(())
+    }
+
+    /// Changes the title of `token` on behalf of the identity `by`, or of the collection itself
+    /// if `by` is `None`.
+    fn update_title(&self, token: &str, title: String, by: Option<IdentityId>) -> ::capnp::Result<()> {
+        let mut entry = match self.inner.borrow().views.get(token) {
+            Some(e) => e.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        let old_title = ::std::mem::replace(&mut entry.title, title.clone());
+        entry.modified_at = now_millis()?;
+        let timestamp = entry.modified_at;
+        self.update_entry(token.to_string(), entry)?;
+        self.send_mirror_upsert(token);
+        self.append_history(HistoryRecord {
+            timestamp: timestamp,
+            token: token.to_string(),
+            by: by,
+            kind: HistoryKind::TitleChanged { old_title: old_title, new_title: title },
+        })
+    }
+
+    fn set_title_pinned(&self, token: &str, pinned: bool, by: Option<IdentityId>) -> ::capnp::Result<()> {
+        let mut entry = match self.inner.borrow().views.get(token) {
+            Some(e) => e.clone(),
+            None => return Err(Error::failed(format!("no such entry: {}", token))),
+        };
+        entry.title_pinned = pinned;
+        entry.modified_at = now_millis()?;
+        let timestamp = entry.modified_at;
+        self.update_entry(token.to_string(), entry)?;
+        self.send_mirror_upsert(token);
+        self.append_history(HistoryRecord {
+            timestamp: timestamp,
+            token: token.to_string(),
+            by: by,
+            kind: HistoryKind::TitlePinned(pinned),
+        })
+    }
+
+    fn set_sync_titles(&self, sync_titles: bool) -> ::capnp::Result<()> {
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let mut root: settings::Builder = message.init_root();
+            root.set_sync_titles(sync_titles);
+        }
+        let root = self.inner.borrow().root.clone();
+        self.write_message_file(&root, "settings", &message)?;
+        self.inner.borrow_mut().sync_titles = sync_titles;
+        Ok(())
+    }
+
+    /// Appends `record` to `root`/history.
+    fn append_history(&self, record: HistoryRecord) -> ::capnp::Result<()> {
+        let mut message = ::capnp::message::Builder::new_default();
+        record.write(message.init_root());
+        let path = self.inner.borrow().root.join("history");
+        durable::append_record(&path, &::capnp::serialize::write_message_to_words(&message))?;
+
+        self.send_action_to_subscribers(Action::History(record.clone()));
+        self.inner.borrow_mut().history.push(record);
+        Ok(())
+    }
+
+    fn history_to_json(&self) -> String {
+        let records: Vec<String> = self.inner.borrow().history.iter().map(|r| r.to_json()).collect();
+        format!("[{}]", records.join(","))
+    }
+
+    fn send_action_to_subscribers(&self, action: Action) {
+        let json_string = action.to_json();
+        let &mut SavedUiViewSetInner { ref subscribers, ref mut tasks, ..} =
+            &mut *self.inner.borrow_mut();
+        for (_, sub) in &*subscribers {
+            let mut req = sub.send_bytes_request();
+            web_socket::encode_text_message(req.get(), &json_string);
+            tasks.add(req.send().promise.map_ok(|_| ()));
+        }
+    }
+
+    /// Drops the sturdyref behind `token` and then removes the entry.
+    async fn drop_and_remove(&self, token: &str) -> Result<(), Error> {
+        self.drop_sturdyref(token).await?;
+        self.clone().remove(token)
+    }
+
+    /// Drops the sturdyref behind `token`. One that is already gone counts as dropped, so that
+    /// a removal interrupted after the drop can be finished.
+    async fn drop_sturdyref(&self, token: &str) -> Result<(), Error> {
+        let binary_token = decode_token(token)?;
+        let mut req = self.inner.borrow().sandstorm_api.drop_request();
+        req.get().set_token(&binary_token);
+        match req.send().promise.await {
+            Ok(_) => Ok(()),
+            Err(ref e) if is_no_such_token(e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&mut self, token: &str) -> Result<(), Error> {
+        self.remove_all(&[token.to_string()])
+    }
+
+    /// Removes the entries `tokens` all at once: if the grain dies partway through, either all
+    /// of them are still there afterwards or none are. Their tombstones are recorded first, in
+    /// one write, so that a removal cut short after that is finished on the next startup rather
+    /// than undone by a linked collection.
+    fn remove_all(&self, tokens: &[String]) -> Result<(), Error> {
+        let entry_ids: Vec<String> = tokens.iter()
+            .filter_map(|t| self.inner.borrow().views.get(t).map(|e| e.entry_id.clone()))
+            .collect();
+        let timestamp = now_millis()?;
+        self.inner.borrow_mut().mirror.record_tombstones(&entry_ids, timestamp)?;
+        self.remove_all_without_mirroring(tokens)?;
+
+        let mut changes = Vec::new();
+        for entry_id in entry_ids {
+            changes.push(MirrorChange {
+                entry_id: entry_id,
+                timestamp: timestamp,
+                kind: MirrorChangeKind::Remove,
+            });
+        }
+        if !changes.is_empty() {
+            self.send_mirror_changes(&changes, None);
+        }
+        Ok(())
+    }
+
+    /// Finishes the removals that were cut short after their tombstones were recorded: drops
+    /// the sturdyrefs of the entries that have a tombstone at least as recent as their last
+    /// change, and then removes them.
+    fn finish_interrupted_removals(&self) {
+        let tokens: Vec<String> = {
+            let inner = self.inner.borrow();
+            inner.views.iter()
+                .filter(|&(_, v)| inner.mirror.tombstones.get(&v.entry_id).map_or(false, |&t| t >= v.modified_at))
+                .map(|(token, _)| token.clone())
+                .collect()
+        };
+        if tokens.is_empty() {
+            return
+        }
+        let self1 = self.clone();
+        self.inner.borrow_mut().tasks.add(async move {
+            let mut dropped = Vec::new();
+            for token in tokens {
+                match self1.drop_sturdyref(&token).await {
+                    Ok(()) => dropped.push(token),
+                    Err(e) => println!("could not finish removing {}: {}", token, e),
+                }
+            }
+            self1.remove_all_without_mirroring(&dropped)
+        });
+    }
+
+    fn remove_without_mirroring(&self, token: &str) -> Result<(), Error> {
+        self.remove_all_without_mirroring(&[token.to_string()])
+    }
+
+    /// Like `remove_all()`, but leaves the entries alone in linked collections.
+    fn remove_all_without_mirroring(&self, tokens: &[String]) -> Result<(), Error> {
+        self.inner.borrow_mut().entry_store.apply(tokens.iter().map(|t| {
+            entry_store::Change::Remove { token: t.clone() }
+        }).collect())?;
+
+        for token in tokens {
+            let mut path = self.inner.borrow().view_info_dir.clone();
+            path.push(token);
+            if let Err(e) = durable::remove_file(&path) {
+                if e.kind() != ::std::io::ErrorKind::NotFound {
+                    return Err(e.into())
+                }
+            }
+
+            self.send_action_to_subscribers(Action::Remove { token: token.clone() });
+            self.inner.borrow_mut().views.remove(token);
+            self.inner.borrow_mut().view_infos.remove(token);
+            self.inner.borrow_mut().health.remove(token);
+            self.inner.borrow_mut().nested.remove(token);
+            self.inner.borrow_mut().nested_known.remove(token);
+        }
+        Ok(())
+    }
+
+    fn entry_to_json(&self, token: &str) -> Option<String> {
+        let inner = self.inner.borrow();
+        inner.views.get(token).map(|data| {
+            format!("{{\"token\":\"{}\",\"data\":{},\"viewInfo\":{},\"health\":{}}}",
+                    token,
+                    data.to_json(),
+                    view_info_to_json(inner.view_infos.get(token)),
+                    match inner.health.get(token) {
+                        Some(h) => h.to_json(),
+                        None => "null".into(),
+                    })
+        })
+    }
+
+    /// Returns the tokens of all entries, most recently added first, to match the order in the UI.
+    fn tokens_by_date(&self) -> Vec<String> {
+        let mut tokens: Vec<(u64, String)> = self.inner.borrow().views.iter()
+            .map(|(t, v)| (v.date_added, t.clone()))
+            .collect();
+        tokens.sort_by(|a, b| b.cmp(a));
+        tokens.into_iter().map(|(_, t)| t).collect()
+    }
+
+    fn entries_to_json(&self) -> String {
+        let entries: Vec<String> = self.tokens_by_date().iter()
+            .filter_map(|t| self.entry_to_json(t))
+            .collect();
+        format!("{{\"entries\":[{}]}}", entries.join(","))
+    }
+
+    /// The beginning of the export document, up to the opening bracket of "entries".
+    fn export_head_json(&self) -> ::capnp::Result<String> {
+        let inner = self.inner.borrow();
+        export_head_json(&inner.collection_id, &inner.description)
+    }
+
+    /// One element of "entries" in the export document, or `None` if there is no such entry.
+    fn export_entry_json(&self, token: &str) -> Option<String> {
+        let inner = self.inner.borrow();
+        Some(export_entry_json(token, inner.views.get(token)?, &inner.profiles,
+                               inner.view_infos.get(token), inner.health.get(token),
+                               inner.nested.get(token)))
+    }
+
+    /// The rest of the export document, after the last entry.
+    fn export_tail_json(&self) -> String {
+        let inner = self.inner.borrow();
+        export_tail_json(&inner.links, &inner.notes, &inner.files)
+    }
+
+    fn send_mirror_status(&self) {
+        let json = self.inner.borrow().mirror.to_json();
+        self.send_action_to_subscribers(Action::MirrorStatus(json));
+    }
+
+    fn set_outbound_mirror_state(&self, peer_id: u64, state: MirrorSyncState) {
+        self.inner.borrow_mut().mirror.outbound = Some((peer_id, state));
+        self.send_mirror_status();
+    }
+
+    fn add_mirror_peer(&self, listener: mirror_listener::Client) -> u64 {
+        let peer_id = {
+            let mut inner = self.inner.borrow_mut();
+            let mirror = &mut inner.mirror;
+            let peer_id = mirror.next_peer_id;
+            mirror.next_peer_id += 1;
+            mirror.peers.insert(peer_id, listener);
+            peer_id
+        };
+        self.send_mirror_status();
+        peer_id
+    }
+
+    fn drop_mirror_peer(&self, peer_id: u64, error: Error) {
+        println!("dropping mirror peer {}: {}", peer_id, error);
+        let is_outbound = {
+            let mut inner = self.inner.borrow_mut();
+            let mirror = &mut inner.mirror;
+            mirror.peers.remove(&peer_id);
+            mirror.outbound.as_ref().map_or(false, |&(id, _)| id == peer_id)
+        };
+        if is_outbound {
+            self.set_outbound_mirror_state(peer_id, MirrorSyncState::Failed(format!("{}", error)));
+        } else {
+            self.send_mirror_status();
+        }
+    }
+
+    /// Sends `changes` to every linked collection except `except`, which is where they came from.
+    fn send_mirror_changes(&self, changes: &[MirrorChange], except: Option<u64>) {
+        let peers: Vec<(u64, mirror_listener::Client)> = self.inner.borrow().mirror.peers.iter()
+            .filter(|&(id, _)| Some(*id) != except)
+            .map(|(id, peer)| (*id, peer.clone()))
+            .collect();
+
+        for (peer_id, peer) in peers {
+            let mut req = peer.apply_changes_request();
+            MirrorChange::write_list(changes, req.get().init_changes(changes.len() as u32));
+            let self1 = self.clone();
+            let task = req.send().promise.map(move |r| {
+                if let Err(e) = r {
+                    self1.drop_mirror_peer(peer_id, e);
+                }
+                Ok(())
+            });
+            self.inner.borrow_mut().tasks.add(task);
+        }
+    }
+
+    /// Sends the current state of a local entry to every linked collection.
+    fn send_mirror_upsert(&self, token: &str) {
+        if self.inner.borrow().mirror.peers.is_empty() {
+            return
+        }
+        let data = match self.inner.borrow().views.get(token) {
+            Some(d) => d.clone(),
+            None => return,
+        };
+        let self1 = self.clone();
+        let task = self.restore(token).map(move |view| {
+            let change = MirrorChange {
+                entry_id: data.entry_id.clone(),
+                timestamp: data.modified_at,
+                kind: MirrorChangeKind::Upsert { data: data, view: view.ok() },
+            };
+            self1.send_mirror_changes(&[change], None);
+            Ok(())
+        });
+        self.inner.borrow_mut().tasks.add(task);
+    }
+
+    /// Describes every entry and every remembered removal, for a linked collection to merge.
+    fn mirror_state(&self) -> Promise<Vec<MirrorChange>, Error> {
+        let tokens = self.tokens_by_date();
+        let views = ::futures::future::join_all(tokens.iter().map(|t| self.restore_in_turn(t)));
+        let self1 = self.clone();
+        Promise::from_future(views.map(move |views| {
+            let inner = self1.inner.borrow();
+            let mut result = Vec::new();
+            for (token, view) in tokens.iter().zip(views.into_iter()) {
+                if let Some(data) = inner.views.get(token) {
+                    result.push(MirrorChange {
+                        entry_id: data.entry_id.clone(),
+                        timestamp: data.modified_at,
+                        kind: MirrorChangeKind::Upsert { data: data.clone(), view: view.ok() },
+                    });
+                }
+            }
+            for (entry_id, &timestamp) in &inner.mirror.tombstones {
+                result.push(MirrorChange {
+                    entry_id: entry_id.clone(),
+                    timestamp: timestamp,
+                    kind: MirrorChangeKind::Remove,
+                });
+            }
+            Ok(result)
+        }))
+    }
+
+    /// Merges changes received from the linked collection `source`, and forwards the ones that
+    /// had an effect to the other linked collections.
+    fn apply_mirror_changes(&self, changes: Vec<MirrorChange>, source: u64) -> Promise<(), Error> {
+        if !self.inner.borrow().mirror.accepts(source) {
+            return Promise::err(Error::failed("this collection is no longer linked".into()));
+        }
+        let self1 = self.clone();
+        Promise::from_future(async move {
+            let mut applied = Vec::new();
+            for change in changes {
+                if self1.apply_mirror_change(&change).await? {
+                    applied.push(change);
+                }
+            }
+            if !applied.is_empty() {
+                self1.send_mirror_changes(&applied, Some(source));
+            }
+            Ok(())
+        })
+    }
+
+    /// Conflicting changes are resolved by keeping the most recent one. A removal wins over an
+    /// edit made at the same millisecond, and between edits, the greater title wins, then a
+    /// pinned title, so that both sides make the same choice. Returns whether the change had
+    /// any effect.
+    async fn apply_mirror_change(&self, change: &MirrorChange) -> Result<bool, Error> {
+        let local = self.inner.borrow().views.iter()
+            .find(|&(_, v)| v.entry_id == change.entry_id)
+            .map(|(t, v)| (t.clone(), v.clone()));
+        let tombstone = self.inner.borrow().mirror.tombstones.get(&change.entry_id).cloned();
+
+        match change.kind {
+            MirrorChangeKind::Remove => {
+                let is_new = tombstone.map_or(true, |t| t < change.timestamp);
+                self.inner.borrow_mut().mirror.record_tombstone(&change.entry_id, change.timestamp)?;
+                match local {
+                    Some((token, data)) if change.timestamp >= data.modified_at => {
+                        self.drop_sturdyref(&token).await?;
+                        self.remove_without_mirroring(&token)?;
+                        Ok(true)
+                    }
+                    _ => Ok(is_new),
+                }
+            }
+            MirrorChangeKind::Upsert { ref data, ref view } => {
+                if tombstone.map_or(false, |t| t >= change.timestamp) {
+                    return Ok(false)
+                }
+                match local {
+                    Some((token, local_data)) => {
+                        if (data.modified_at, &data.title, data.title_pinned) <=
+                            (local_data.modified_at, &local_data.title, local_data.title_pinned)
+                        {
+                            return Ok(false)
+                        }
+                        let mut updated = local_data.clone();
+                        updated.title = data.title.clone();
+                        updated.title_pinned = data.title_pinned;
+                        updated.modified_at = data.modified_at;
+                        self.update_entry(token, updated)?;
+                        Ok(true)
+                    }
+                    None => {
+                        let view = match *view {
+                            Some(ref v) => v.clone(),
+                            None => return Ok(false),
+                        };
+                        let mut req = self.inner.borrow().sandstorm_api.save_request();
+                        req.get().get_cap().set_as_capability(view.client.hook);
+                        req.get().init_label().set_default_text(
+                            &format!("grain with title: {}", data.title));
+                        let response = req.send().promise.await?;
+                        let token = base64::engine::general_purpose::URL_SAFE.encode(
+                            response.get()?.get_token()?);
+                        self.insert_data(token.clone(), data.clone())?;
+                        self.retrieve_view_info(token)?;
+                        Ok(true)
+                    }
+                }
+            }
+        }
+    }
+
+    /// If we have initiated a mirroring link, connects to the linked collection and exchanges
+    /// state with it.
+    fn connect_mirror(&self) -> Promise<(), Error> {
+        let token = match ::std::fs::read(&self.inner.borrow().mirror.peer_token_path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Promise::ok(()),
+            Err(e) => return Promise::err(e.into()),
+        };
+
+        let peer_id = {
+            let mut inner = self.inner.borrow_mut();
+            let mirror = &mut inner.mirror;
+            if let Some((old_peer_id, _)) = mirror.outbound {
+                mirror.peers.remove(&old_peer_id);
+            }
+            let peer_id = mirror.next_peer_id;
+            mirror.next_peer_id += 1;
+            peer_id
+        };
+        self.set_outbound_mirror_state(peer_id, MirrorSyncState::Connecting);
+
+        let self1 = self.clone();
+        Promise::from_future(async move {
+            let state = match self1.exchange_mirror_state(token, peer_id).await {
+                Ok(()) => MirrorSyncState::Synced { at: now_millis()? },
+                Err(e) => {
+                    self1.inner.borrow_mut().mirror.peers.remove(&peer_id);
+                    MirrorSyncState::Failed(format!("{}", e))
+                }
+            };
+            self1.set_outbound_mirror_state(peer_id, state);
+            Ok(())
+        })
+    }
+
+    async fn exchange_mirror_state(&self, token: Vec<u8>, peer_id: u64) -> Result<(), Error> {
+        let mut req = self.inner.borrow().sandstorm_api.restore_request();
+        req.get().set_token(&token);
+        let response = req.send().promise.await?;
+        let view: ui_view::Client = response.get()?.get_cap().get_as_capability()?;
+
+        let null_context: session_context::Client = capnp_rpc::new_client(NullSessionContext);
+        let session = open_collection_session(view, null_context).await?;
+
+        let mut req = session.link_request();
+        req.get().set_listener(capnp_rpc::new_client(MirrorListener {
+            peer_id: peer_id,
+            saved_ui_views: self.clone(),
+        }));
+        req.get().set_id(&self.inner.borrow().collection_id);
+        let response = req.send().promise.await?;
+        let results = response.get()?;
+        if results.get_id()?.to_str()? == self.inner.borrow().collection_id {
+            return Err(Error::failed("cannot mirror a collection with itself".into()));
+        }
+        let remote_state = MirrorChange::read_list(results.get_state()?)?;
+        let remote_listener = results.get_listener()?;
+
+        self.inner.borrow_mut().mirror.peers.insert(peer_id, remote_listener.clone());
+        self.apply_mirror_changes(remote_state, peer_id).await?;
+
+        let local_state = self.mirror_state().await?;
+        let mut req = remote_listener.apply_changes_request();
+        MirrorChange::write_list(&local_state, req.get().init_changes(local_state.len() as u32));
+        req.send().promise.await?;
+        Ok(())
+    }
+
+    /// Starts mirroring with the collection behind `view`, replacing any previous link.
+    fn link_mirror(&self, view: ui_view::Client) -> Promise<(), Error> {
+        let mut req = self.inner.borrow().sandstorm_api.save_request();
+        req.get().get_cap().set_as_capability(view.client.hook);
+        req.get().init_label().set_default_text("mirrored collection");
+        let self1 = self.clone();
+        Promise::from_future(async move {
+            let response = req.send().promise.await?;
+            let token = response.get()?.get_token()?;
+            self1.unlink_mirror().await?;
+            let (tmp_dir, path) = {
+                let inner = self1.inner.borrow();
+                (inner.tmp_dir.clone(), inner.mirror.peer_token_path.clone())
+            };
+            durable::write_bytes_atomically(&tmp_dir, &path, token)?;
+            self1.connect_mirror().await
+        })
+    }
+
+    /// Stops mirroring with the collection that we initiated a link to, if any, and tells it
+    /// to stop sending us its changes.
+    fn unlink_mirror(&self) -> Promise<(), Error> {
+        let path = self.inner.borrow().mirror.peer_token_path.clone();
+        let token = match ::std::fs::read(&path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Promise::ok(()),
+            Err(e) => return Promise::err(e.into()),
+        };
+
+        let outbound = self.inner.borrow_mut().mirror.outbound.take();
+        let peer = match outbound {
+            Some((peer_id, _)) => self.inner.borrow_mut().mirror.peers.remove(&peer_id),
+            None => None,
+        };
+        self.send_mirror_status();
+
+        let mut req = self.inner.borrow().sandstorm_api.drop_request();
+        req.get().set_token(&token);
+        Promise::from_future(async move {
+            if let Some(peer) = peer {
+                // If the peer is unreachable, it has already dropped our listener, or will
+                // find it rejecting its changes.
+                if let Err(e) = peer.unlink_request().send().promise.await {
+                    println!("could not tell the mirror peer to unlink: {}", e);
+                }
+            }
+            req.send().promise.await?;
+            durable::remove_file(&path)?;
+            Ok(())
+        })
+    }
+
+    /// Called when a collection that initiated a link with us unlinks.
+    fn mirror_peer_unlinked(&self, peer_id: u64) {
+        if self.inner.borrow_mut().mirror.peers.remove(&peer_id).is_some() {
+            self.send_mirror_status();
+        }
+    }
+
+    fn new_subscribed_websocket(&self,
+                                client_stream: web_socket_stream::Client,
+                                can_write: bool,
+                                user_id: Option<IdentityId>)
+                                 -> web_socket_stream::Client
+    {
+        fn send_action(task: Promise<(), Error>,
+                       client_stream: &web_socket_stream::Client,
+                       action: Action) -> Promise<(), Error> {
+            let json_string = action.to_json();
+            let mut req = client_stream.send_bytes_request();
+            web_socket::encode_text_message(req.get(), &json_string);
+            let promise = req.send().promise.map_ok(|_| ());
+            Promise::from_future(task.and_then(|_| promise))
+        }
+
+        let id = self.inner.borrow().next_id;
+        self.inner.borrow_mut().next_id = id + 1;
+
+        self.inner.borrow_mut().subscribers.insert(id, client_stream.clone());
+        self.prioritize_deferred_view_infos();
+
+        let mut task = Promise::ok(());
+
+        task = send_action(task, &client_stream, Action::CanWrite(can_write));
+        task = send_action(task, &client_stream, Action::UserId(user_id));
+        task = send_action(task, &client_stream,
+                           Action::Description(self.inner.borrow().description.clone()));
+        task = send_action(task, &client_stream,
+                           Action::MirrorStatus(self.inner.borrow().mirror.to_json()));
+
+        let mut added_by_identities: HashSet<IdentityId> = HashSet::new();
+
+        for (t, v) in &self.inner.borrow().views {
+            if let &Some(ref id) = &v.added_by {
+                added_by_identities.insert(*id);
+            }
+
+            task = send_action(
+                task, &client_stream,
+                Action::Insert {
+                    token: t.clone(),
+                    data: v.clone()
+                }
+            );
+        }
+
+        for (t, vi) in &self.inner.borrow().view_infos {
+            task = send_action(
+                task, &client_stream,
+                Action::ViewInfo {
+                    token: t.clone(),
+                    data: vi.clone(),
+                }
+            );
+        }
+
+        for (t, h) in &self.inner.borrow().health {
+            task = send_action(
+                task, &client_stream,
+                Action::Health {
+                    token: t.clone(),
+                    data: h.clone(),
+                }
+            );
+        }
+
+        for (id, l) in &self.inner.borrow().links {
+            if let Some(ref added_by) = l.added_by {
+                added_by_identities.insert(*added_by);
+            }
+
+            task = send_action(
+                task, &client_stream,
+                Action::InsertLink {
+                    id: id.clone(),
+                    data: l.clone(),
+                }
+            );
+        }
+
+        for (id, n) in &self.inner.borrow().notes {
+            task = send_action(
+                task, &client_stream,
+                Action::InsertNote {
+                    id: id.clone(),
+                    data: n.clone(),
+                }
+            );
+        }
+
+        for (id, f) in &self.inner.borrow().files {
+            if let Some(ref added_by) = f.added_by {
+                added_by_identities.insert(*added_by);
+            }
+
+            task = send_action(
+                task, &client_stream,
+                Action::InsertFile {
+                    id: id.clone(),
+                    data: f.clone(),
+                }
+            );
+        }
+
+        if can_write {
+            for (t, error) in &self.inner.borrow().quarantined {
+                task = send_action(
+                    task, &client_stream,
+                    Action::Quarantined {
+                        token: t.clone(),
+                        error: error.clone(),
+                    }
+                );
+            }
+        }
+
+        for (t, n) in &self.inner.borrow().nested {
+            task = send_action(
+                task, &client_stream,
+                Action::Nested {
+                    token: t.clone(),
+                    data: n.clone(),
+                }
+            );
+        }
+
+        for identity_id in &added_by_identities {
+            let cached = self.inner.borrow().profiles.get(identity_id).cloned();
+            if let Some(profile_data) = cached {
+                task = send_action(
+                    task, &client_stream,
+                    Action::User { id: *identity_id, data: profile_data });
+            }
+        }
+
+        self.inner.borrow_mut().tasks.add(task);
+
+        // Profiles that are missing or out of date get sent to every subscriber once they arrive.
+        for identity_id in &added_by_identities {
+            if let Err(e) = self.refresh_user_profile(identity_id) {
+                println!("could not refresh profile of {}: {}", identity_id, e);
+            }
+        }
+
+        capnp_rpc::new_client(
+            web_socket::Adapter::new(
+                WebSocketStream::new(id, self.clone()),
+                client_stream,
+                self.inner.borrow().tasks.clone()))
+    }
+}
+
+/// The beginning of the export document (see "Exporting" in README.md), up to the opening
+/// bracket of "entries".
+fn export_head_json(collection_id: &str, description: &str) -> ::capnp::Result<String> {
+    Ok(format!("{{\"version\":{},\"exportedAt\":\"{}\",\"collectionId\":{},\"description\":{},\"entries\":[",
+               EXPORT_VERSION,
+               now_millis()?,
+               json_escape_str(collection_id),
+               json_escape_str(description)))
+}
+
+/// One element of "entries" in the export document.
+fn export_entry_json(token: &str,
+                     data: &SavedUiViewData,
+                     profiles: &HashMap<IdentityId, ProfileData>,
+                     view_info: Option<&Result<ViewInfoData, ViewInfoError>>,
+                     health: Option<&EntryHealth>,
+                     nested: Option<&NestedCollection>)
+                     -> String
+{
+    let added_by = match data.added_by {
+        None => "null".into(),
+        Some(ref id) => {
+            let name = match profiles.get(id) {
+                Some(profile) => Some(profile.display_name.clone()),
+                None => data.added_by_name.clone(),
+            };
+            format!("{{\"id\":\"{}\",\"displayName\":{}}}", id, optional_string_to_json(&name))
+        }
+    };
+    let (app_title, grain_icon_url) = match view_info.and_then(last_known_view_info) {
+        Some(view_info) => (json_escape_str(&view_info.app_title),
+                            json_escape_str(&view_info.grain_icon_url)),
+        None => ("null".into(), "null".into()),
+    };
+    format!("{{\"token\":\"{}\",\"entryId\":{},\"title\":{},\"dateAdded\":\"{}\",\"modifiedAt\":\"{}\",\"titlePinned\":{},\"addedBy\":{},\"appTitle\":{},\"grainIconUrl\":{},\"health\":{},\"nested\":{}}}",
+            token,
+            json_escape_str(&data.entry_id),
+            json_escape_str(&data.title),
+            data.date_added,
+            data.modified_at,
+            data.title_pinned,
+            added_by,
+            app_title,
+            grain_icon_url,
+            match health {
+                Some(h) => h.to_json(),
+                None => "null".into(),
+            },
+            match nested {
+                Some(n) => n.to_json(),
+                None => "null".into(),
+            })
+}
+
+/// The rest of the export document, after the last entry.
+fn export_tail_json(links: &HashMap<String, LinkData>,
+                    notes: &HashMap<String, NoteData>,
+                    files: &HashMap<String, FileData>)
+                    -> String
+{
+    fn by_date<T, F>(items: &HashMap<String, T>, date_added: F, to_json: fn(&T) -> String)
+                     -> String
+        where F: Fn(&T) -> u64
+    {
+        let mut sorted: Vec<(&String, &T)> = items.iter().collect();
+        sorted.sort_by_key(|&(id, item)| (::std::cmp::Reverse(date_added(item)), id));
+        let elements: Vec<String> = sorted.into_iter()
+            .map(|(id, item)| format!("{{\"id\":{},\"data\":{}}}", json_escape_str(id), to_json(item)))
+            .collect();
+        format!("[{}]", elements.join(","))
+    }
+
+    format!("],\"links\":{},\"notes\":{},\"files\":{}}}",
+            by_date(links, |l| l.date_added, LinkData::to_json),
+            by_date(notes, |n| n.date_added, NoteData::to_json),
+            by_date(files, |f| f.date_added, FileData::to_json))
+}
+
+/// Reads the files named after their IDs in `directory` with `read`, leaving out the ones that
+/// can't be read, as the grain sets them aside on startup.
+fn read_stored<T, F>(directory: &::std::path::Path, read: F) -> ::capnp::Result<HashMap<String, T>>
+    where F: Fn(::capnp::message::Reader<::capnp::serialize::OwnedSegments>) -> ::capnp::Result<T>
+{
+    let mut result = HashMap::new();
+    let dir_entries = match ::std::fs::read_dir(directory) {
+        Ok(d) => d,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(result),
+        Err(e) => return Err(e.into()),
+    };
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry?;
+        if let (Some(id), Ok(item)) = (dir_entry.file_name().to_str(),
+                                       read_message_file(&dir_entry.path()).and_then(&read)) {
+            result.insert(id.to_string(), item);
+        }
+    }
+    Ok(result)
+}
+
+/// Writes the export document for the collection stored in `root`, a grain's `/var` or a copy
+/// of it, to `out`, without Sandstorm and without changing anything. This is what
+/// `GET /export.json` returns right after the grain starts: no entry has been checked yet or
+/// listed as a collection. Entries and other files that the grain would quarantine or set aside
+/// are left out.
+pub fn export_storage<W>(root: &::std::path::Path, out: &mut W) -> ::capnp::Result<()>
+    where W: ::std::io::Write
+{
+    fn read_text(path: ::std::path::PathBuf) -> ::capnp::Result<String> {
+        match ::std::fs::read_to_string(path) {
+            Ok(s) => Ok(s),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    let contents = entry_store::read(root.join("entries"))?;
+    let mut views: Vec<(String, SavedUiViewData)> = contents.entries.iter()
+        .filter_map(|(token, bytes)| Some((token.clone(), read_entry(bytes).ok()?)))
+        .collect();
+    views.sort_by(|a, b| (b.1.date_added, &b.0).cmp(&(a.1.date_added, &a.0)));
+
+    let mut profiles = HashMap::new();
+    for (id, profile) in read_stored(&root.join("profiles"), |m| ProfileData::from_cache(m.get_root()?))? {
+        if let Ok(id) = IdentityId::parse(&id) {
+            profiles.insert(id, profile);
+        }
+    }
+
+    write!(out, "{}", export_head_json(&read_text(root.join("collection-id"))?,
+                                      &read_text(root.join("description"))?)?)?;
+    for (i, (token, data)) in views.iter().enumerate() {
+        let view_info = read_message_file(&root.join("view-info").join(token))
+            .and_then(|m| ViewInfoData::from_cache(m.get_root()?))
+            .ok();
+        let health = EntryHealth::new(view_info.as_ref().map(|v| v.fetched_at));
+        write!(out, "{}{}", if i > 0 { "," } else { "" },
+               export_entry_json(token, data, &profiles, view_info.map(Ok).as_ref(), Some(&health),
+                                 None))?;
+    }
+    writeln!(out, "{}", export_tail_json(
+        &read_stored(&root.join("links"), |m| LinkData::from_metadata(m.get_root()?))?,
+        &read_stored(&root.join("notes"), |m| NoteData::from_metadata(m.get_root()?))?,
+        &read_stored(&root.join("files").join("metadata"), |m| FileData::from_metadata(m.get_root()?))?))?;
+    Ok(())
+}
+
+/// The export document (see "Exporting" in README.md) in pieces, so that a large collection
+/// can be streamed. Entries are rendered when their piece comes up; ones that have been removed
+/// by then are left out.
+struct ExportChunks {
+    saved_ui_views: SavedUiViewSet,
+    head: Option<String>,
+    tokens: ::std::vec::IntoIter<String>,
+    wrote_entry: bool,
+    finished: bool,
+}
+
+impl ExportChunks {
+    fn new(saved_ui_views: SavedUiViewSet) -> ::capnp::Result<ExportChunks> {
+        Ok(ExportChunks {
+            head: Some(saved_ui_views.export_head_json()?),
+            tokens: saved_ui_views.tokens_by_date().into_iter(),
+            saved_ui_views: saved_ui_views,
+            wrote_entry: false,
+            finished: false,
+        })
+    }
+}
+
+impl Iterator for ExportChunks {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if let Some(head) = self.head.take() {
+            return Some(head)
+        }
+
+        let mut chunk = String::new();
+        let mut count = 0;
+        while count < EXPORT_ENTRIES_PER_CHUNK {
+            let token = match self.tokens.next() {
+                Some(t) => t,
+                None => break,
+            };
+            if let Some(json) = self.saved_ui_views.export_entry_json(&token) {
+                if self.wrote_entry {
+                    chunk.push(',');
+                }
+                chunk.push_str(&json);
+                self.wrote_entry = true;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            return Some(chunk)
+        }
+
+        if self.finished {
+            None
+        } else {
+            self.finished = true;
+            Some(self.saved_ui_views.export_tail_json())
+        }
+    }
+}
+
+/// Writes `chunks` to `stream`, waiting for each write to be acknowledged before rendering the
+/// next chunk.
+async fn write_export(chunks: ExportChunks, stream: byte_stream::Client) -> Result<(), Error> {
+    for chunk in chunks {
+        let mut req = stream.write_request();
+        req.get().set_data(chunk.as_bytes());
+        req.send().promise.await?;
+    }
+    stream.done_request().send().promise.await?;
+    Ok(())
+}
+
+/// Returned in place of the body of a streamed response. Sandstorm drops it when the client
+/// goes away, after which the writes to the response stream fail.
+struct ResponseHandle;
+
+impl handle::Server for ResponseHandle {}
+
+const ADD_GRAIN_ACTIVITY_INDEX: u16 = 0;
+const REMOVE_GRAIN_ACTIVITY_INDEX: u16 = 1;
+const EDIT_DESCRIPTION_ACTIVITY_INDEX: u16 = 2;
+
+const API_ONLY_ERROR: &str = "API tokens can only be used for paths under /api/.";
+
+pub struct WebSession {
+    can_write: bool,
+    asset_dir: ::std::path::PathBuf,
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    context: session_context::Client,
+    saved_ui_views: SavedUiViewSet,
+    identity_id: Option<IdentityId>,
+
+    /// The user's display name, recorded in the entries they add.
+    display_name: Option<String>,
+
+    /// Set for sessions opened with an API token, which may only reach paths under "api/".
+    api_only: bool,
+}
+
+impl Drop for WebSession {
+    fn drop(&mut self) {
+        if let Some(identity_id) = self.identity_id {
+            self.saved_ui_views.session_closed(identity_id);
+        }
+    }
+}
+
+/// Opens a `CollectionSession` on another collection grain. Sandstorm replaces the `UserInfo`
+/// we pass here with one describing the permissions that this grain holds on the target.
+fn open_collection_session(target: ui_view::Client,
+                           context: session_context::Client)
+                           -> Promise<collection_session::Client, Error>
+{
+    let mut req = target.new_session_request();
+    {
+        use capnp::traits::HasTypeId;
+        req.get().set_session_type(collection_session::Client::TYPE_ID);
+        req.get().init_user_info();
+        req.get().set_context(context);
+    }
+    Promise::from_future(req.send().promise.map(|r| match r {
+        Ok(response) => {
+            Ok(collection_session::Client { client: response.get()?.get_session()?.client })
+        }
+        Err(e) => Err(e),
+    }))
+}
+
+/// For sessions that we open on other grains when there is no user session to pass along.
+struct NullSessionContext;
+
+impl session_context::Server for NullSessionContext {}
+
+fn has_write_permission(user_info: user_info::Reader) -> ::capnp::Result<bool> {
+    // Permission #0 is "write". Check if bit 0 in the PermissionSet is set.
+    let permissions = user_info.get_permissions()?;
+    Ok(permissions.len() > 0 && permissions.get(0))
+}
+
+/// The identity ID and display name that Sandstorm reports for a session, if any.
+fn identity_of(user_info: user_info::Reader)
+               -> ::capnp::Result<(Option<IdentityId>, Option<String>)>
+{
+    let identity_id = if user_info.has_identity_id() {
+        Some(IdentityId::from_bytes(user_info.get_identity_id()?)?)
+    } else {
+        None
+    };
+    let display_name = if user_info.has_display_name() {
+        Some(user_info.get_display_name()?.get_default_text()?.to_string()?)
+    } else {
+        None
+    };
+    Ok((identity_id, display_name))
+}
+
+impl WebSession {
+    pub fn new(user_info: user_info::Reader,
+               context: session_context::Client,
+               sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+               saved_ui_views: SavedUiViewSet,
+               asset_dir: ::std::path::PathBuf,
+               api_only: bool)
+               -> ::capnp::Result<WebSession>
+    {
+        let can_write = has_write_permission(user_info)?;
+        let (identity_id, display_name) = identity_of(user_info)?;
+
+        if let Some(identity_id) = identity_id {
+            saved_ui_views.session_opened(identity_id);
+        }
+
+        Ok(WebSession {
+            can_write: can_write,
+            asset_dir: asset_dir,
+            sandstorm_api: sandstorm_api,
+            context: context,
+            saved_ui_views: saved_ui_views,
+            identity_id: identity_id,
+            display_name: display_name,
+            api_only: api_only,
+        })
+
+        // `UserInfo` is defined in `sandstorm/grain.capnp` and contains info like:
+        // - A stable ID for the user, so you can correlate sessions from the same user.
+        // - The user's display name, e.g. "Mark Miller", useful for identifying the user to other
+        //   users.
+        // - The user's permissions (seen above).
+
+        // `WebSession::Params` is defined in `sandstorm/web-session.capnp` and contains info like:
+        // - The hostname where the grain was mapped for this user. Every time a user opens a grain,
+        //   it is mapped at a new random hostname for security reasons.
+        // - The user's User-Agent and Accept-Languages headers.
+        // We don't use any of it, and sessions opened with an API token get `ApiSession::Params`
+        // instead, so we don't take it as an argument.
+
+        // `SessionContext` is defined in `sandstorm/grain.capnp` and implements callbacks for
+        // sharing/access control and service publishing/discovery.
+    }
+}
+
+impl ui_session::Server for WebSession {}
+
+impl web_session::Server for WebSession {
+    async fn get(self: Rc<Self>,
+           params: web_session::GetParams,
+           mut results: web_session::GetResults)
+	-> Result<(), Error>
+    {
+        // HTTP GET request.
+        let path = params.get()?.get_path()?.to_str()?;
+        self.require_canonical_path(path)?;
+        if !self.is_reachable(path) {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::Forbidden,
+                             API_ONLY_ERROR);
+            return Ok(())
+        }
+
+        if path == "" {
+            let text = "<!DOCTYPE html>\
+                       <html><head>\
+                       <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\">\
+                       <script type=\"text/javascript\" src=\"script.js\" async></script>
+                       </head><body><div id=\"main\"></div></body></html>";
+            let mut content = results.get().init_content();
+            content.set_mime_type("text/html; charset=UTF-8");
+            content.init_body().set_bytes(text.as_bytes());
+            Ok(())
+        } else if path == "script.js" {
+            self.read_file("script.js.gz", results, "text/javascript; charset=UTF-8", Some("gzip"))
+        } else if path == "style.css" {
+            self.read_file("style.css.gz", results, "text/css; charset=UTF-8", Some("gzip"))
+        } else if path == "export.json" || path == "api/export.json" {
+            let response_stream = params.get()?.get_context()?.get_response_stream().ok();
+            self.export(response_stream, results)
+        } else if path.starts_with("api/") {
+            self.api_get(&path[4..], results.get());
+            Ok(())
+        } else if path.starts_with("files/") {
+            self.read_attachment(&path[6..], results)
+        } else if path == "history" {
+            set_json_content(results.get(), &self.saved_ui_views.history_to_json());
+            Ok(())
+        } else if path == "broken" {
+            let json = self.saved_ui_views.broken_entries_to_json()?;
+            set_json_content(results.get(), &json);
+            Ok(())
+        } else if path == "quarantine" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            set_json_content(results.get(), &self.saved_ui_views.quarantined_to_json());
+            Ok(())
+        } else {
+            let mut error = results.get().init_client_error();
+            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+            Ok(())
+        }
+    }
+
+    async fn post(self: Rc<Self>,
+            params: web_session::PostParams,
+            mut results: web_session::PostResults)
+            -> Result<(), Error>
+    {
+        let path = {
+            let path = params.get()?.get_path()?.to_str()?;
+            self.require_canonical_path(path)?;
+            path.to_string()
+        };
+        if !self.is_reachable(&path) {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::Forbidden,
+                             API_ONLY_ERROR);
+            return Ok(())
+        }
+
+        if path.starts_with("token/") {
+            self.receive_request_token(path[6..].to_string(), params, results).await
+        } else if path.starts_with("offer/") {
+            let token = path[6..].to_string();
+            let title = match self.saved_ui_views.inner.borrow().get_saved_data(&token) {
+                None => {
+                    let mut error = results.get().init_client_error();
+                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                    return Ok(())
+                }
+                Some(saved_ui_view) => saved_ui_view.title.to_string(),
+            };
+
+            self.offer_ui_view(token, title, params, results).await
+        } else if let Some(token) = path.strip_prefix("api/entries/")
+            .and_then(|p| p.strip_suffix("/refresh"))
+        {
+            if self.saved_ui_views.inner.borrow().get_saved_data(&token.to_string()).is_none() {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 "no such entry");
+                return Ok(())
+            }
+            match self.saved_ui_views.retrieve_view_info_on_demand(token.to_string()) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                }
+            }
+            Ok(())
+        } else if path.starts_with("copy/") {
+            self.transfer_entries(path[5..].to_string(), false, params, results).await
+        } else if path.starts_with("move/") {
+            self.transfer_entries(path[5..].to_string(), true, params, results).await
+        } else if path.starts_with("mirror/") {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let view = self.claim_ui_view(&path[7..]).await?;
+            let task = self.saved_ui_views.link_mirror(view);
+            self.saved_ui_views.inner.borrow_mut().tasks.add(task);
+            results.get().init_no_content();
+            Ok(())
+        } else if path == "link" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let content = params.get()?.get_content()?.get_content()?;
+            let (url, title) = match parse_link_form(content) {
+                Ok(r) => r,
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                    return Ok(())
+                }
+            };
+            let id = self.saved_ui_views.insert_link(url, title, self.identity_id)?;
+            let mut content = results.get().init_content();
+            content.set_status_code(web_session::response::SuccessCode::Created);
+            content.set_mime_type("application/json; charset=UTF-8");
+            content.init_body().set_bytes(format!("{{\"id\":\"{}\"}}", id).as_bytes());
+            Ok(())
+        } else if path == "note" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let content = params.get()?.get_content()?.get_content()?;
+            let form = match parse_note_form(content) {
+                Ok(f) => f,
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                    return Ok(())
+                }
+            };
+            let id = self.saved_ui_views.insert_note(form, self.identity_id)?;
+            let mut content = results.get().init_content();
+            content.set_status_code(web_session::response::SuccessCode::Created);
+            content.set_mime_type("application/json; charset=UTF-8");
+            content.init_body().set_bytes(format!("{{\"id\":\"{}\"}}", id).as_bytes());
+            Ok(())
+        } else if path.starts_with("files/") {
+            // The last path component is the percent-encoded file name.
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let name = match ::url::percent_encoding::percent_decode(path[6..].as_bytes()).decode_utf8() {
+                Ok(n) if !n.is_empty() => n.into_owned(),
+                _ => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     "missing or malformed file name");
+                    return Ok(())
+                }
+            };
+            let content = params.get()?.get_content()?;
+            let mime_type = content.get_mime_type()?.to_str()?.to_string();
+            let bytes = content.get_content()?;
+            if let Err(e) = self.saved_ui_views.check_file_quota(bytes.len() as u64, None) {
+                set_client_error(results.get(),
+                                 web_session::response::ClientErrorCode::RequestEntityTooLarge, &e);
+                return Ok(())
+            }
+            let id = self.saved_ui_views.insert_file(name, mime_type, bytes, self.identity_id)?;
+            let mut content = results.get().init_content();
+            content.set_status_code(web_session::response::SuccessCode::Created);
+            content.set_mime_type("application/json; charset=UTF-8");
+            content.init_body().set_bytes(format!("{{\"id\":\"{}\"}}", id).as_bytes());
+            Ok(())
+        } else if path == "broken/refresh" || path == "broken/remove" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let id = self.saved_ui_views.start_bulk_operation(path == "broken/remove",
+                                                              self.context.clone());
+            let mut content = results.get().init_content();
+            content.set_status_code(web_session::response::SuccessCode::Accepted);
+            content.set_mime_type("application/json; charset=UTF-8");
+            content.init_body().set_bytes(format!("{{\"bulkId\":{}}}", id).as_bytes());
+            Ok(())
+        } else if let Some(token) = path.strip_prefix("quarantine/")
+            .and_then(|p| p.strip_suffix("/retry"))
+        {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            if let Err(e) = self.saved_ui_views.quarantined_path(token) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+            } else if let Err(e) = self.saved_ui_views.retry_quarantined(token) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                 &format!("{}", e));
+            } else {
+                results.get().init_no_content();
+            }
+            Ok(())
+        } else if let Some(token) = path.strip_prefix("quarantine/")
+            .and_then(|p| p.strip_suffix("/drop"))
+        {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            if let Err(e) = self.saved_ui_views.quarantined_path(token) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+            self.saved_ui_views.drop_and_remove_quarantined(token).await?;
+            results.get().init_no_content();
+            Ok(())
+        } else if path == "mirror-sync" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+                return Ok(())
+            }
+            let task = self.saved_ui_views.connect_mirror();
+            self.saved_ui_views.inner.borrow_mut().tasks.add(task);
+            results.get().init_no_content();
+            Ok(())
+        } else if path.starts_with("refresh/") {
+            let token = path[8..].to_string();
+            match self.saved_ui_views.retrieve_view_info_on_demand(token) {
+                Ok(()) => {
+                    results.get().init_no_content();
+                }
+                Err(e) => {
+                    fill_in_client_error(results, e);
+                }
+            }
+            Ok(())
+        } else {
+            let mut error = results.get().init_client_error();
+            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+            Ok(())
+        }
+    }
+
+    async fn put(self: Rc<Self>,
+           params: web_session::PutParams,
+           mut results: web_session::PutResults)
+	-> Result<(), Error>
+    {
+        // HTTP PUT request.
+
+        let params = params.get()?;
+        let path = params.get_path()?.to_str()?;
+        self.require_canonical_path(path)?;
+        if !self.is_reachable(path) {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::Forbidden,
+                             API_ONLY_ERROR);
+            return Ok(())
+        }
+
+        if !self.can_write {
+            results.get().init_client_error()
+                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            Ok(())
+        } else if path.starts_with("link/") {
+            let content = params.get_content()?.get_content()?;
+            let (url, title) = match parse_link_form(content) {
+                Ok(r) => r,
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                    return Ok(())
+                }
+            };
+            if let Err(e) = self.saved_ui_views.update_link(&path[5..], url, title) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+            results.get().init_no_content();
+            Ok(())
+        } else if path.starts_with("note/") {
+            let content = params.get_content()?.get_content()?;
+            let form = match parse_note_form(content) {
+                Ok(f) => f,
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                    return Ok(())
+                }
+            };
+            if let Err(e) = self.saved_ui_views.update_note(&path[5..], form) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+            results.get().init_no_content();
+            Ok(())
+        } else if path.starts_with("files/") {
+            let id = &path[6..];
+            if !self.saved_ui_views.inner.borrow().files.contains_key(id) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 "no such file");
+                return Ok(())
+            }
+            let content = params.get_content()?;
+            let mime_type = content.get_mime_type()?.to_str()?.to_string();
+            let bytes = content.get_content()?;
+            if let Err(e) = self.saved_ui_views.check_file_quota(bytes.len() as u64, Some(id)) {
+                set_client_error(results.get(),
+                                 web_session::response::ClientErrorCode::RequestEntityTooLarge, &e);
+                return Ok(())
+            }
+            self.saved_ui_views.update_file(id, mime_type, bytes)?;
+            results.get().init_no_content();
+            Ok(())
+        } else if path.starts_with("title/") {
+            let content = params.get_content()?.get_content()?;
+            let title = match ::std::str::from_utf8(content) {
+                Ok(t) => t.to_string(),
+                Err(e) => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     &format!("{}", e));
+                    return Ok(())
+                }
+            };
+            if !self.saved_ui_views.inner.borrow().views.contains_key(&path[6..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 "no such entry");
+                return Ok(())
+            }
+            self.saved_ui_views.update_title(&path[6..], title, self.identity_id)?;
+            results.get().init_no_content();
+            Ok(())
+        } else if path.starts_with("pin/") || path == "settings/sync-titles" {
+            if path.starts_with("pin/") && !self.saved_ui_views.inner.borrow().views.contains_key(&path[4..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 "no such entry");
+                return Ok(())
+            }
+            // The body is "true" or "false".
+            let value = match params.get_content()?.get_content()? {
+                b"true" => true,
+                b"false" => false,
+                _ => {
+                    set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                     "expected true or false");
+                    return Ok(())
+                }
+            };
+            if path.starts_with("pin/") {
+                self.saved_ui_views.set_title_pinned(&path[4..], value, self.identity_id)?;
+            } else {
+                self.saved_ui_views.set_sync_titles(value)?;
+            }
+            results.get().init_no_content();
+            Ok(())
+        } else if path == "description" || path == "api/description" {
+            let content = params.get_content()?.get_content()?;
+            if let Err(e) = self.saved_ui_views.update_description(content) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+            let mut req = self.context.activity_request();
+            req.get().init_event().set_type(EDIT_DESCRIPTION_ACTIVITY_INDEX);
+            req.send().promise.await?;
+            results.get().init_no_content();
+            Ok(())
+        } else {
+            results.get().init_client_error()
+                .set_status_code(web_session::response::ClientErrorCode::NotFound);
+            Ok(())
+        }
+    }
+
+    async fn delete(self: Rc<Self>,
+                    params: web_session::DeleteParams,
+                    mut results: web_session::DeleteResults)
+	-> Result<(), Error>
+    {
+        // HTTP DELETE request.
+
+        let path = params.get()?.get_path()?.to_str()?;
+        self.require_canonical_path(path)?;
+        if !self.is_reachable(path) {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::Forbidden,
+                             API_ONLY_ERROR);
+            return Ok(())
+        }
+
+        if path.starts_with("link/") {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            } else if let Err(e) = self.saved_ui_views.remove_link(&path[5..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+            } else {
+                results.get().init_no_content();
+            }
+            return Ok(())
+        }
+
+        if path.starts_with("files/") {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            } else if let Err(e) = self.saved_ui_views.remove_file(&path[6..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+            } else {
+                results.get().init_no_content();
+            }
+            return Ok(())
+        }
+
+        if path.starts_with("note/") {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            } else if let Err(e) = self.saved_ui_views.remove_note(&path[5..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+            } else {
+                results.get().init_no_content();
+            }
+            return Ok(())
+        }
+
+        if path.starts_with("quarantine/") {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            } else if let Err(e) = self.saved_ui_views.remove_quarantined(&path[11..]) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                                 &format!("{}", e));
+            } else {
+                results.get().init_no_content();
+            }
+            return Ok(())
+        }
+
+        if path == "mirror" {
+            if !self.can_write {
+                results.get().init_client_error()
+                    .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            } else {
+                self.saved_ui_views.unlink_mirror().await?;
+                results.get().init_no_content();
+            }
+            return Ok(())
+        }
+
+        let (token_string, is_api) = if path.starts_with("sturdyref/") {
+            (path[10..].to_string(), false)
+        } else if path.starts_with("api/entries/") {
+            (path[12..].to_string(), true)
+        } else {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                             "DELETE only supported under sturdyref/ and api/entries/");
+            return Ok(())
+        };
+
+        if !self.can_write {
+            results.get().init_client_error()
+                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            Ok(())
+        } else if is_api &&
+            self.saved_ui_views.inner.borrow().get_saved_data(&token_string).is_none()
+        {
+            set_client_error(results.get(), web_session::response::ClientErrorCode::NotFound,
+                             "no such entry");
+            Ok(())
+        } else {
+            if let Err(e) = decode_token(&token_string) {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+
+            let context = self.context.clone();
+            self.saved_ui_views.drop_and_remove(&token_string).await?;
+            let mut req = context.activity_request();
+            req.get().init_event().set_type(REMOVE_GRAIN_ACTIVITY_INDEX);
+            req.send().promise.await?;
+            results.get().init_no_content();
+            Ok(())
+        }
+    }
+
+    async fn open_web_socket(self: Rc<Self>,
+                             params: web_session::OpenWebSocketParams,
+                             mut results: web_session::OpenWebSocketResults)
+                             -> Result<(), Error>
+    {
+        if self.api_only {
+            return Err(Error::failed(API_ONLY_ERROR.to_string()));
+        }
+        let client_stream = params.get()?.get_client_stream()?;
+
+        results.get().set_server_stream(
+            self.saved_ui_views.new_subscribed_websocket(
+                client_stream,
+                self.can_write,
+                self.identity_id));
+
+        Ok(())
+    }
+}
+
+/// Escapes `text` for use in HTML, e.g. an error message that quotes the request.
+fn html_escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn fill_in_client_error(mut results: web_session::PostResults, e: Error)
+{
+    let mut client_error = results.get().init_client_error();
+    client_error.set_description_html(&html_escape(&format!("{}", e)));
+}
+
+/// `description` is plain text.
+fn set_client_error(response: web_session::response::Builder,
+                    status_code: web_session::response::ClientErrorCode,
+                    description: &str)
+{
+    let mut client_error = response.init_client_error();
+    client_error.set_status_code(status_code);
+    client_error.set_description_html(&html_escape(description));
+}
+
+fn set_json_content(response: web_session::response::Builder, json: &str)
+{
+    let mut content = response.init_content();
+    content.set_status_code(web_session::response::SuccessCode::Ok);
+    content.set_mime_type("application/json; charset=UTF-8");
+    content.init_body().set_bytes(json.as_bytes());
+}
+
+impl WebSession {
+    fn offer_ui_view(&self,
+                     text_token: String,
+                     title: String,
+                     _params: web_session::PostParams,
+                     mut results: web_session::PostResults)
+                     -> Promise<(), Error>
+    {
+        let token = match base64::engine::general_purpose::URL_SAFE.decode(&text_token[..]) {
+            Ok(b) => b,
+            Err(e) => return Promise::err(Error::failed(format!("{}", e))),
+        };
+
+        let session_context = self.context.clone();
+        let set = self.saved_ui_views.clone();
+        let mut req = self.sandstorm_api.restore_request();
+        req.get().set_token(&token);
+        Promise::from_future(req.send().promise.then(move |response| match response {
+            Ok(v) => {
+                let sealed_ui_view: ui_view::Client =
+                    pry!(pry!(v.get()).get_cap().get_as_capability());
+                let mut req = session_context.offer_request();
+                req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
+                {
+                    use capnp::traits::HasTypeId;
+                    let tags = req.get().init_descriptor().init_tags(1);
+                    let mut tag = tags.get(0);
+                    tag.set_id(ui_view::Client::TYPE_ID);
+                    let mut value: ui_view::powerbox_tag::Builder = tag.get_value().init_as();
+                    value.set_title(&title);
+                }
+
+                Promise::from_future(req.send().promise.map_ok(|_| ()))
+            }
+            Err(e) => {
+                let failure = set.view_info_failure(&text_token, e);
+                set.inner.borrow_mut().view_infos.insert(text_token.clone(), Err(failure.clone()));
+                set.send_action_to_subscribers(Action::ViewInfo {
+                    token: text_token,
+                    data: Err(failure),
+                });
+                Promise::ok(())
+            }
+        }).then(move |r| match r {
+            Ok(_) => {
+                results.get().init_no_content();
+                Promise::ok(())
+            }
+            Err(e) => {
+                fill_in_client_error(results, e);
+                Promise::ok(())
+            }
+        }))
+    }
+
+    /// Handles GET requests under "api/", the JSON surface that is reachable with an API token.
+    fn api_get(&self, path: &str, response: web_session::response::Builder) {
+        if path == "entries" {
+            set_json_content(response, &self.saved_ui_views.entries_to_json());
+        } else if path.starts_with("entries/") {
+            match self.saved_ui_views.entry_to_json(&path[8..]) {
+                Some(json) => set_json_content(response, &json),
+                None => set_client_error(response, web_session::response::ClientErrorCode::NotFound,
+                                         "no such entry"),
+            }
+        } else if path == "description" {
+            let json = format!("{{\"description\":{}}}",
+                               json_escape_str(&self.saved_ui_views.inner.borrow().description));
+            set_json_content(response, &json);
+        } else {
+            set_client_error(response, web_session::response::ClientErrorCode::NotFound,
+                             "unknown API path");
+        }
+    }
+
+    /// Copies (or moves, if `is_move` is set) the entries whose tokens are listed, one per line,
+    /// in the request body to the collection that the user picked in the powerbox request
+    /// identified by `request_token`. Responds with a transfer ID right away; the outcome for
+    /// each entry is reported to websocket subscribers as `Action::Transferred`.
+    async fn transfer_entries(&self,
+                              request_token: String,
+                              is_move: bool,
+                              params: web_session::PostParams,
+                              mut results: web_session::PostResults)
+                              -> Result<(), Error>
+    {
+        if !self.can_write {
+            results.get().init_client_error()
+                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
+            return Ok(())
+        }
+
+        let content = params.get()?.get_content()?.get_content()?;
+        let tokens: Vec<String> = match ::std::str::from_utf8(content) {
+            Ok(s) => s.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect(),
+            Err(e) => {
+                set_client_error(results.get(), web_session::response::ClientErrorCode::BadRequest,
+                                 &format!("{}", e));
+                return Ok(())
+            }
+        };
+
+        let id = self.saved_ui_views.inner.borrow().next_transfer_id;
+        self.saved_ui_views.inner.borrow_mut().next_transfer_id = id + 1;
+
+        // If the target can't be reached, every entry reports that, like any other failure to
+        // transfer it.
+        let session = self.claim_ui_view(&request_token).and_then({
+            let context = self.context.clone();
+            move |target| open_collection_session(target, context)
+        });
+        let saved_ui_views = self.saved_ui_views.clone();
+        let context = self.context.clone();
+        let task = async move {
+            let session = session.await;
+            let mut moved = Vec::new();
+            for token in tokens {
+                let result = async {
+                    let session = session.as_ref().map_err(|e| e.clone())?;
+                    let data = match saved_ui_views.inner.borrow().views.get(&token) {
+                        Some(d) => d.clone(),
+                        None => return Err(Error::failed(format!("no such entry: {}", token))),
+                    };
+                    let view = saved_ui_views.restore(&token).await?;
+                    let mut req = session.add_entry_request();
+                    data.write_metadata(req.get().init_metadata());
+                    req.get().set_view(view);
+                    req.send().promise.await?;
+
+                    if is_move {
+                        saved_ui_views.drop_sturdyref(&token).await?;
+                    }
+                    Ok(())
+                }.await;
+
+                if is_move && result.is_ok() {
+                    moved.push(token);
+                    continue
+                }
+                saved_ui_views.send_action_to_subscribers(Action::Transferred {
+                    id: id,
+                    token: token,
+                    result: result,
+                });
+            }
+
+            // The moved entries leave this collection all at once.
+            let removed = saved_ui_views.remove_all(&moved);
+            for token in moved {
+                let result = match removed {
+                    Ok(()) => {
+                        let mut req = context.activity_request();
+                        req.get().init_event().set_type(REMOVE_GRAIN_ACTIVITY_INDEX);
+                        req.send().promise.await.map(|_| ())
+                    }
+                    Err(ref e) => Err(e.clone()),
+                };
+                saved_ui_views.send_action_to_subscribers(Action::Transferred {
+                    id: id,
+                    token: token,
+                    result: result,
+                });
+            }
+            saved_ui_views.send_action_to_subscribers(Action::TransferDone { id: id });
+            Ok::<(), Error>(())
+        };
+        self.saved_ui_views.inner.borrow_mut().tasks.add(task);
+
+        let mut content = results.get().init_content();
+        content.set_status_code(web_session::response::SuccessCode::Accepted);
+        content.set_mime_type("application/json; charset=UTF-8");
+        content.init_body().set_bytes(format!("{{\"transferId\":{}}}", id).as_bytes());
+        Ok(())
+    }
+
+    /// Claims the `UiView` that the user picked in the powerbox request `request_token`.
+    fn claim_ui_view(&self, request_token: &str) -> Promise<ui_view::Client, Error> {
+        let mut req = self.context.claim_request_request();
+        req.get().set_request_token(request_token);
+        Promise::from_future(req.send().promise.map(|r| match r {
+            Ok(response) => response.get()?.get_cap().get_as_capability(),
+            Err(e) => Err(e),
+        }))
+    }
+
+    fn read_powerbox_tag(&self, decoded_content: Vec<u8>) -> ::capnp::Result<String>
+    {
+        let mut cursor = ::std::io::Cursor::new(decoded_content);
+        let message = ::capnp::serialize_packed::read_message(&mut cursor,
+                                                              Default::default())?;
+        let desc: powerbox_descriptor::Reader = message.get_root()?;
+        let tags = desc.get_tags()?;
+        if tags.len() == 0 {
+            Err(Error::failed("no powerbox tag".into()))
+        } else {
+            let value: ui_view::powerbox_tag::Reader = tags.get(0).get_value().get_as()?;
+            Ok(value.get_title()?.to_string()?)
+        }
+    }
+
+    fn receive_request_token(&self,
+                             token: String,
+                             params: web_session::PostParams,
+                             mut results: web_session::PostResults)
+                             -> Promise<(), Error>
+    {
+        let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+
+        let decoded_content = match base64::engine::general_purpose::URL_SAFE.decode(content) {
+            Ok(c) => c,
+            Err(_) => {
+                fill_in_client_error(results, Error::failed("failed to convert from base64".into()));
+                return Promise::ok(())
+            }
+        };
+        let grain_title: String = match self.read_powerbox_tag(decoded_content) {
+            Ok(t) => t,
+            Err(e) => {
+                fill_in_client_error(results, e);
+                return Promise::ok(());
+            }
+        };
+
+        // now let's save this thing into an actual uiview sturdyref
+        let mut req = self.context.claim_request_request();
+        let sandstorm_api = self.sandstorm_api.clone();
+        req.get().set_request_token(&token);
+        let mut saved_ui_views = self.saved_ui_views.clone();
+        let identity_id = self.identity_id;
+        let display_name = self.display_name.clone();
+
+        let do_stuff = req.send().promise.and_then(move |response| {
+            let sealed_ui_view: ui_view::Client =
+                pry!(pry!(response.get()).get_cap().get_as_capability());
+            let mut req = sandstorm_api.save_request();
+            req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
+            {
+                let mut save_label = req.get().init_label();
+                save_label.set_default_text(&format!("grain with title: {}", grain_title));
+            }
+            Promise::from_future(req.send().promise.map(move |r| {
+                let response = r?;
+                let binary_token = response.get()?.get_token()?;
+                let token = base64::engine::general_purpose::URL_SAFE.encode(binary_token);
+
+                saved_ui_views.insert(token.clone(), grain_title, identity_id, display_name)?;
+
+                SavedUiViewSet::retrieve_view_info(&saved_ui_views, token)?;
+                Ok(())
+            }))
+        });
+
+        let context = self.context.clone();
+        Promise::from_future(do_stuff.then(move |r| match r {
+            Ok(()) => {
+                let mut req = context.activity_request();
+                req.get().init_event().set_type(ADD_GRAIN_ACTIVITY_INDEX);
+                Promise::from_future(req.send().promise.and_then(move |_| {
+                    let mut _content = results.get().init_content();
+                    Promise::ok(())
+                }))
+            }
+            Err(e) => {
+                let mut error = results.get().init_client_error();
+                error.set_description_html(&format!("error: {:?}", e));
+                Promise::ok(())
+            }
+        }))
+    }
+
+    /// Whether this session may request `path`. Sessions opened with an API token are
+    /// confined to the HTTP API, so that a leaked token can't be used to drive the web UI.
+    fn is_reachable(&self, path: &str) -> bool {
+        !self.api_only || path.starts_with("api/")
+    }
+
+    fn require_canonical_path(&self, path: &str) -> Result<(), Error> {
+        // Require that the path doesn't contain "." or ".." or consecutive slashes, to prevent path
+        // injection attacks.
+        //
+        // Note that such attacks wouldn't actually accomplish much since everything outside /var
+        // is a read-only filesystem anyway, containing the app package contents which are non-secret.
+
+        for (idx, component) in path.split_terminator("/").enumerate() {
+            if component == "." || component == ".." || (component == "" && idx > 0) {
+                return Err(Error::failed(format!("non-canonical path: {:?}", path)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves an uploaded file.
+    fn read_attachment(&self, id: &str, mut results: web_session::GetResults) -> Result<(), Error> {
+        let file = match self.saved_ui_views.inner.borrow().files.get(id) {
+            Some(f) => f.clone(),
+            None => {
+                let mut error = results.get().init_client_error();
+                error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                return Ok(())
+            }
+        };
+
+        let mut f = ::std::fs::File::open(self.saved_ui_views.file_path("content", id))?;
+        let size = f.metadata()?.len();
+        let mut content = results.get().init_content();
+        content.set_status_code(web_session::response::SuccessCode::Ok);
+        if INLINE_MIME_TYPES.contains(&&file.mime_type[..]) {
+            content.set_mime_type(&file.mime_type);
+        } else {
+            if file.mime_type.is_empty() {
+                content.set_mime_type("application/octet-stream");
+            } else {
+                content.set_mime_type(&file.mime_type);
+            }
+            content.reborrow().init_disposition().set_download(&file.name);
+        }
+
+        let mut body = content.init_body().init_bytes(size as u32);
+        ::std::io::copy(&mut f, &mut body)?;
+        Ok(())
+    }
+
+    /// Responds with the export document. It's streamed if Sandstorm gave us a stream to write
+    /// it to.
+    fn export(&self,
+              response_stream: Option<byte_stream::Client>,
+              mut results: web_session::GetResults)
+              -> Result<(), Error>
+    {
+        let chunks = ExportChunks::new(self.saved_ui_views.clone())?;
+        let stream = match response_stream {
+            Some(s) => s,
+            None => {
+                let json: String = chunks.collect();
+                set_json_content(results.get(), &json);
+                return Ok(())
+            }
+        };
+
+        let mut content = results.get().init_content();
+        content.set_status_code(web_session::response::SuccessCode::Ok);
+        content.set_mime_type("application/json; charset=UTF-8");
+        content.init_body().set_stream(capnp_rpc::new_client(ResponseHandle));
+        let task = Promise::from_future(write_export(chunks, stream));
+        self.saved_ui_views.inner.borrow_mut().tasks.add(task);
+        Ok(())
+    }
+
+    /// Responds with the contents of `filename` in the asset directory.
+    fn read_file(&self,
+                 filename: &str,
+                 mut results: web_session::GetResults,
+                 content_type: &str,
+                 encoding: Option<&str>)
+                 -> Result<(), Error>
+    {
+        match ::std::fs::File::open(self.asset_dir.join(filename)) {
+            Ok(mut f) => {
+                let size = f.metadata()?.len();
+                let mut content = results.get().init_content();
+                content.set_status_code(web_session::response::SuccessCode::Ok);
+                content.set_mime_type(content_type);
+                encoding.map(|enc| content.set_encoding(enc));
 
-        if path.starts_with("token/") {
-            self.receive_request_token(path[6..].to_string(), params, results).await
-        } else if path.starts_with("offer/") {
-            let token = path[6..].to_string();
-            let title = match self.saved_ui_views.inner.borrow().get_saved_data(&token) {
-                None => {
-                    let mut error = results.get().init_client_error();
-                    error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-                    return Ok(())
-                }
-                Some(saved_ui_view) => saved_ui_view.title.to_string(),
+                let mut body = content.init_body().init_bytes(size as u32);
+                ::std::io::copy(&mut f, &mut body)?;
+                Ok(())
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+                let mut error = results.get().init_client_error();
+                error.set_status_code(web_session::response::ClientErrorCode::NotFound);
+                Ok(())
+            }
+            Err(e) => {
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// A session opened on this collection by another collection grain.
+/// See `CollectionSession` in collections.capnp.
+pub struct CollectionSession {
+    can_write: bool,
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    context: session_context::Client,
+    saved_ui_views: SavedUiViewSet,
+
+    /// Who Sandstorm says is calling. Entries added through this session are attributed to
+    /// them, whatever the caller claims in the metadata.
+    identity_id: Option<IdentityId>,
+    display_name: Option<String>,
+}
+
+impl ui_session::Server for CollectionSession {}
+
+impl collection_session::Server for CollectionSession {
+    async fn add_entry(self: Rc<Self>,
+                       params: collection_session::AddEntryParams,
+                       _results: collection_session::AddEntryResults)
+                       -> Result<(), Error>
+    {
+        if !self.can_write {
+            return Err(Error::failed("adding an entry requires the \"write\" permission".into()));
+        }
+
+        let params = params.get()?;
+        let data = SavedUiViewData::from_metadata(params.get_metadata()?)?;
+        let view = params.get_view()?;
+
+        let mut req = self.sandstorm_api.save_request();
+        req.get().get_cap().set_as_capability(view.client.hook);
+        req.get().init_label().set_default_text(&format!("grain with title: {}", data.title));
+        let response = req.send().promise.await?;
+        let token = base64::engine::general_purpose::URL_SAFE.encode(response.get()?.get_token()?);
+
+        let mut saved_ui_views = self.saved_ui_views.clone();
+        saved_ui_views.insert(token.clone(), data.title, self.identity_id,
+                              self.display_name.clone())?;
+        saved_ui_views.retrieve_view_info(token)?;
+
+        let mut req = self.context.activity_request();
+        req.get().init_event().set_type(ADD_GRAIN_ACTIVITY_INDEX);
+        req.send().promise.await?;
+        Ok(())
+    }
+
+    async fn link(self: Rc<Self>,
+                  params: collection_session::LinkParams,
+                  mut results: collection_session::LinkResults)
+                  -> Result<(), Error>
+    {
+        if !self.can_write {
+            return Err(Error::failed("mirroring requires the \"write\" permission".into()));
+        }
+
+        let params = params.get()?;
+        if params.get_id()?.to_str()? == self.saved_ui_views.inner.borrow().collection_id {
+            return Err(Error::failed("cannot mirror a collection with itself".into()));
+        }
+        let listener = params.get_listener()?;
+        let peer_id = self.saved_ui_views.add_mirror_peer(listener);
+        let state = match self.saved_ui_views.mirror_state().await {
+            Ok(s) => s,
+            Err(e) => {
+                self.saved_ui_views.drop_mirror_peer(peer_id, e.clone());
+                return Err(e)
+            }
+        };
+
+        let mut results = results.get();
+        results.set_id(&self.saved_ui_views.inner.borrow().collection_id);
+        MirrorChange::write_list(&state, results.reborrow().init_state(state.len() as u32));
+        results.set_listener(capnp_rpc::new_client(MirrorListener {
+            peer_id: peer_id,
+            saved_ui_views: self.saved_ui_views.clone(),
+        }));
+        Ok(())
+    }
+
+    async fn list_entries(self: Rc<Self>,
+                          _params: collection_session::ListEntriesParams,
+                          mut results: collection_session::ListEntriesResults)
+                          -> Result<(), Error>
+    {
+        let tokens = self.saved_ui_views.tokens_by_date();
+        let views = ::futures::future::join_all(
+            tokens.iter().map(|t| self.saved_ui_views.restore_in_turn(t))).await;
+
+        let inner = self.saved_ui_views.inner.borrow();
+        let mut entries = results.get().init_entries(tokens.len() as u32);
+        for (idx, (token, view)) in tokens.iter().zip(views.into_iter()).enumerate() {
+            let mut entry = entries.reborrow().get(idx as u32);
+            if let Some(data) = inner.views.get(token) {
+                data.write_metadata(entry.reborrow().init_metadata());
+            }
+            if let Ok(view) = view {
+                entry.set_view(view);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_id(self: Rc<Self>,
+                    _params: collection_session::GetIdParams,
+                    mut results: collection_session::GetIdResults)
+                    -> Result<(), Error>
+    {
+        results.get().set_id(&self.saved_ui_views.inner.borrow().collection_id);
+        Ok(())
+    }
+}
+
+/// Receives the changes made in a linked collection.
+pub struct MirrorListener {
+    /// Which of our `Mirror::peers` the changes come from.
+    peer_id: u64,
+    saved_ui_views: SavedUiViewSet,
+}
+
+impl mirror_listener::Server for MirrorListener {
+    async fn apply_changes(self: Rc<Self>,
+                           params: mirror_listener::ApplyChangesParams,
+                           _results: mirror_listener::ApplyChangesResults)
+                           -> Result<(), Error>
+    {
+        let changes = MirrorChange::read_list(params.get()?.get_changes()?)?;
+        self.saved_ui_views.apply_mirror_changes(changes, self.peer_id).await
+    }
+
+    async fn unlink(self: Rc<Self>,
+                    _params: mirror_listener::UnlinkParams,
+                    _results: mirror_listener::UnlinkResults)
+                    -> Result<(), Error>
+    {
+        self.saved_ui_views.mirror_peer_unlinked(self.peer_id);
+        Ok(())
+    }
+}
+
+pub struct UiView {
+    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+    saved_ui_views: SavedUiViewSet,
+
+    /// Where script.js.gz and style.css.gz are.
+    asset_dir: ::std::path::PathBuf,
+}
+
+impl UiView {
+    fn new(client: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+           saved_ui_views: SavedUiViewSet,
+           asset_dir: ::std::path::PathBuf)
+           -> UiView
+    {
+        UiView {
+            sandstorm_api: client,
+            saved_ui_views: saved_ui_views,
+            asset_dir: asset_dir,
+        }
+    }
+}
+
+impl ui_view::Server for UiView {
+    async fn get_view_info(self: Rc<Self>,
+                     _params: ui_view::GetViewInfoParams,
+                     mut results: ui_view::GetViewInfoResults)
+                     -> Result<(), Error>
+    {
+        let mut view_info = results.get();
+
+        // Define a "write" permission, and then define roles "editor" and "viewer" where only
+        // "editor" has the "write" permission. This will allow people to share read-only.
+        {
+            let perms = view_info.reborrow().init_permissions(1);
+            let mut write = perms.get(0);
+            write.set_name("write");
+            write.init_title().set_default_text("write");
+        }
+
+        {
+            let mut roles = view_info.reborrow().init_roles(2);
+            {
+                let mut editor = roles.reborrow().get(0);
+                editor.reborrow().init_title().set_default_text("editor");
+                editor.reborrow().init_verb_phrase().set_default_text("can edit");
+                editor.init_permissions(1).set(0, true);   // has "write" permission
+            }
+            {
+                let mut viewer = roles.get(1);
+                viewer.set_default(true);
+                viewer.reborrow().init_title().set_default_text("viewer");
+                viewer.reborrow().init_verb_phrase().set_default_text("can view");
+                viewer.init_permissions(1).set(0, false);  // does not have "write" permission
+            }
+        }
+
+        {
+            let mut event_types = view_info.init_event_types(3);
+            {
+                let mut added = event_types.reborrow().get(ADD_GRAIN_ACTIVITY_INDEX as u32);
+                added.set_name("add");
+                added.reborrow().init_verb_phrase().set_default_text("added grain");
+            }
+            {
+                let mut removed = event_types.reborrow().get(REMOVE_GRAIN_ACTIVITY_INDEX as u32);
+                removed.set_name("remove");
+                removed.reborrow().init_verb_phrase().set_default_text("removed grain");
+            }
+            {
+                let mut removed = event_types.reborrow().get(EDIT_DESCRIPTION_ACTIVITY_INDEX as u32);
+                removed.set_name("description");
+                removed.reborrow().init_verb_phrase().set_default_text("edited description");
+            }
+        }
+
+        Ok(())
+    }
+
+
+    async fn new_session(self: Rc<Self>,
+                   params: ui_view::NewSessionParams,
+                   mut results: ui_view::NewSessionResults)
+                   -> Result<(), Error>
+    {
+        use ::capnp::traits::HasTypeId;
+        let params = params.get()?;
+
+        let user_info = params.get_user_info()?;
+
+        // Requests made with an API token arrive as `ApiSession`s, which extend `WebSession`.
+        let session_type = params.get_session_type();
+        if session_type == web_session::Client::TYPE_ID ||
+            session_type == api_session::Client::TYPE_ID
+        {
+            let session = WebSession::new(
+                user_info.clone(),
+                params.get_context()?,
+                self.sandstorm_api.clone(),
+                self.saved_ui_views.clone(),
+                self.asset_dir.clone(),
+                session_type == api_session::Client::TYPE_ID)?;
+            let client: web_session::Client = capnp_rpc::new_client(session);
+
+            // We need to do this silly dance to upcast.
+            results.get().set_session(ui_session::Client { client : client.client});
+        } else if session_type == collection_session::Client::TYPE_ID {
+            let (identity_id, display_name) = identity_of(user_info.clone())?;
+            let session = CollectionSession {
+                can_write: has_write_permission(user_info.clone())?,
+                sandstorm_api: self.sandstorm_api.clone(),
+                context: params.get_context()?,
+                saved_ui_views: self.saved_ui_views.clone(),
+                identity_id: identity_id,
+                display_name: display_name,
             };
+            let client: collection_session::Client = capnp_rpc::new_client(session);
+            results.get().set_session(ui_session::Client { client : client.client});
+        } else {
+            return Err(Error::failed("unsupported session type".to_string()));
+        }
+
+        if user_info.has_identity_id() {
+            let identity = user_info.get_identity()?;
+
+            // TODO(cleanup)
+            let identity_id = IdentityId::from_bytes(user_info.get_identity_id()?)?;
+            self.saved_ui_views.inner.borrow_mut().identity_map.put(&identity_id, identity)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where a collection keeps its data and finds its static assets.
+pub struct Config {
+    /// /var in a grain. Overridden by `COLLECTIONS_STORAGE_ROOT`.
+    pub storage_root: ::std::path::PathBuf,
+
+    /// / in a grain. Overridden by `COLLECTIONS_ASSET_DIR`.
+    pub asset_dir: ::std::path::PathBuf,
+
+    /// Overridden by `COLLECTIONS_MAX_CONCURRENT_RESTORES`.
+    pub max_concurrent_restores: usize,
+}
+
+impl Config {
+    /// Reads the configuration from the environment, falling back to the given directories.
+    pub fn from_env(default_storage_root: &str, default_asset_dir: &str)
+                    -> Result<Config, Box<dyn std::error::Error>>
+    {
+        let max_concurrent_restores = match ::std::env::var("COLLECTIONS_MAX_CONCURRENT_RESTORES") {
+            Ok(s) => match s.parse() {
+                Ok(n) => n,
+                Err(e) => {
+                    println!("ignoring COLLECTIONS_MAX_CONCURRENT_RESTORES={:?}: {}; using {}",
+                             s, e, DEFAULT_MAX_CONCURRENT_RESTORES);
+                    DEFAULT_MAX_CONCURRENT_RESTORES
+                }
+            },
+            Err(_) => DEFAULT_MAX_CONCURRENT_RESTORES,
+        };
+        Ok(Config {
+            storage_root: ::std::env::var_os("COLLECTIONS_STORAGE_ROOT")
+                .map(::std::path::PathBuf::from)
+                .unwrap_or_else(|| default_storage_root.into()),
+            asset_dir: ::std::env::var_os("COLLECTIONS_ASSET_DIR")
+                .map(::std::path::PathBuf::from)
+                .unwrap_or_else(|| default_asset_dir.into()),
+            max_concurrent_restores: max_concurrent_restores,
+        })
+    }
+}
+
+/// Brings the storage under `config.storage_root` up to date and opens the collection kept
+/// there. Must be called from within a `LocalSet`.
+pub fn open_collection(config: &Config,
+                       sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>)
+                       -> Result<ui_view::Client, Box<dyn std::error::Error>>
+{
+    let root = &config.storage_root;
+    migrations::run(root)?;
+
+    let identity_map = IdentityMap::new(
+        root.join("identities"),
+        root.join("trash"),
+        &sandstorm_api)?;
+    let saved_uiviews = SavedUiViewSet::new(
+        root,
+        &sandstorm_api,
+        identity_map,
+        config.max_concurrent_restores)?;
+
+    let uiview = UiView::new(
+        sandstorm_api,
+        saved_uiviews,
+        config.asset_dir.clone());
+    Ok(capnp_rpc::new_client(uiview))
+}
+
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use ::std::os::unix::io::{FromRawFd};
+    use futures::io::AsyncReadExt;
+
+    #[cfg(feature = "dev")]
+    {
+        if let Ok(address) = ::std::env::var("COLLECTIONS_DEV_ADDRESS") {
+            // Not in a grain. See dev.rs.
+            return crate::dev::main(&address);
+        }
+    }
+    let config = Config::from_env("/var", "/")?;
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let local = tokio::task::LocalSet::new();
+
+    local.block_on(&mut rt, async move {
+        let stream: ::std::os::unix::net::UnixStream = unsafe { FromRawFd::from_raw_fd(3) };
+        let stream = tokio::net::UnixStream::from_std(stream)?;
+        let (read_half, write_half) =
+            tokio_util::compat::Tokio02AsyncReadCompatExt::compat(stream).split();
+
+        let network =
+            Box::new(twoparty::VatNetwork::new(read_half, write_half,
+                                               rpc_twoparty_capnp::Side::Client,
+                                               Default::default()));
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+            ::capnp_rpc::new_future_client(rx.map_err(|_e| capnp::Error::failed(format!("oneshot was canceled"))));
+
+        let client = open_collection(&config, sandstorm_api)?;
+        let mut rpc_system = RpcSystem::new(network, Some(client.client));
+
+        let _ = tx.send(rpc_system.bootstrap::<sandstorm_api::Client<::capnp::any_pointer::Owned>>(
+            ::capnp_rpc::rpc_twoparty_capnp::Side::Server));
+
+        Ok::<_,  Box<dyn std::error::Error>>(rpc_system.await?)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, DEFAULT_MAX_CONCURRENT_RESTORES, ADD_GRAIN_ACTIVITY_INDEX,
+                EDIT_DESCRIPTION_ACTIVITY_INDEX, REMOVE_GRAIN_ACTIVITY_INDEX,
+                SavedUiViewSet, UiView};
+    use crate::entry_store;
+    use crate::fake::{FakeSandstormApi, FakeSessionContext, FakeUiView, new_icon, new_identity};
+    use crate::identity_map::{IdentityId, IdentityMap};
+    use crate::migrations;
+    use crate::test_util::run;
+    use base64::Engine;
+    use capnp::Error;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    use crate::collections_capnp::{collection_session, link_metadata, mirror_listener,
+                                   ui_view_metadata};
+    use sandstorm::api_session_capnp::{api_session};
+    use sandstorm::identity_capnp::{user_info};
+    use sandstorm::powerbox_capnp::powerbox_descriptor;
+    use sandstorm::util_capnp::{byte_stream};
+    use sandstorm::grain_capnp::{ui_session, ui_view, sandstorm_api};
+    use sandstorm::web_session_capnp::{web_session};
+    use sandstorm::web_session_capnp::web_session::{response, web_socket_stream};
+
+    type Response = ::capnp::capability::Response<response::Owned>;
+
+    /// A collection whose storage is in a fresh temporary directory, with fakes standing in
+    /// for Sandstorm.
+    struct Harness {
+        root: ::std::path::PathBuf,
+        sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
+        saved_ui_views: SavedUiViewSet,
+        view: ui_view::Client,
+    }
+
+    impl Harness {
+        fn new(name: &str) -> Result<Harness, Error> {
+            let mut root = ::std::env::temp_dir();
+            root.push(format!("collections-server-test-{}-{}", ::std::process::id(), name));
+            let _ = ::std::fs::remove_dir_all(&root);
+            Harness::open(root)
+        }
+
+        /// Starts the collection stored in `root`, as when its grain starts up. Capabilities
+        /// saved by an earlier `Harness` can't be restored.
+        fn open(root: ::std::path::PathBuf) -> Result<Harness, Error> {
+            Harness::open_with(root, Rc::new(FakeSandstormApi::default()),
+                               DEFAULT_MAX_CONCURRENT_RESTORES)
+        }
+
+        /// Like `open()`, but on `api`, which can restore what was saved through it before.
+        fn open_with(root: ::std::path::PathBuf, api: Rc<FakeSandstormApi>,
+                     max_concurrent_restores: usize)
+                     -> Result<Harness, Error>
+        {
+            let sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
+                capnp_rpc::new_client_from_rc(api);
+            migrations::run(&root)?;
+            let identity_map = IdentityMap::new(root.join("identities"), root.join("trash"),
+                                                &sandstorm_api)?;
+            let saved_ui_views = SavedUiViewSet::new(&root, &sandstorm_api, identity_map,
+                                                     max_concurrent_restores)?;
+            let view: ui_view::Client = capnp_rpc::new_client(
+                UiView::new(sandstorm_api.clone(), saved_ui_views.clone(), root.join("assets")));
+            Ok(Harness {
+                root: root,
+                sandstorm_api: sandstorm_api,
+                saved_ui_views: saved_ui_views,
+                view: view,
+            })
+        }
+
+        /// Opens a `WebSession` for the user whose identity ID is `user` repeated.
+        async fn open_session(&self, user: u8, can_write: bool) -> Result<Session, Error> {
+            use capnp::traits::HasTypeId;
+            self.open_session_of_type(user, can_write, web_session::Client::TYPE_ID).await
+        }
+
+        /// Like `open_session()`, but as if the user had used an API token.
+        async fn open_api_session(&self, user: u8, can_write: bool) -> Result<Session, Error> {
+            use capnp::traits::HasTypeId;
+            self.open_session_of_type(user, can_write, api_session::Client::TYPE_ID).await
+        }
+
+        async fn open_session_of_type(&self, user: u8, can_write: bool, session_type: u64)
+                                      -> Result<Session, Error>
+        {
+            let context = Rc::new(FakeSessionContext::default());
+            let mut req = self.view.new_session_request();
+            {
+                let mut params = req.get();
+                params.set_session_type(session_type);
+                params.set_context(capnp_rpc::new_client_from_rc(context.clone()));
+                let mut user_info = params.init_user_info();
+                user_info.reborrow().init_display_name()
+                    .set_default_text(&format!("User {}", user));
+                user_info.set_identity_id(&[user; 32][..]);
+                user_info.set_identity(new_identity(&format!("User {}", user)));
+                user_info.init_permissions(1).set(0, can_write);
+            }
+            let response = req.send().promise.await?;
+            Ok(Session {
+                client: web_session::Client { client: response.get()?.get_session()?.client },
+                context: context,
+            })
+        }
+
+        fn entry_tokens(&self) -> HashSet<String> {
+            self.saved_ui_views.inner.borrow().views.keys().cloned().collect()
+        }
 
-            self.offer_ui_view(token, title, params, results).await
-        } else if path.starts_with("refresh/") {
-            let token = path[8..].to_string();
-            match SavedUiViewSet::retrieve_view_info(&self.saved_ui_views, token) {
-                Ok(()) => {
-                    results.get().init_no_content();
-                }
-                Err(e) => {
-                    fill_in_client_error(results, e);
+        /// What the websocket snapshot and `insert` actions say about the entry.
+        fn insert_action(&self, token: &str) -> String {
+            let data = self.saved_ui_views.inner.borrow().views[token].clone();
+            Action::Insert { token: token.into(), data: data }.to_json()
+        }
+    }
+
+    struct Session {
+        client: web_session::Client,
+        context: Rc<FakeSessionContext>,
+    }
+
+    impl Session {
+        async fn get(&self, path: &str) -> Result<Response, Error> {
+            let mut req = self.client.get_request();
+            req.get().set_path(path);
+            req.send().promise.await
+        }
+
+        /// Like `get()`, but offers to take the body as a stream, and waits for the stream
+        /// to be done.
+        async fn get_streamed(&self, path: &str)
+                              -> Result<(Response, Rc<ByteCollector>), Error>
+        {
+            let collector = Rc::new(ByteCollector::default());
+            let mut req = self.client.get_request();
+            req.get().set_path(path);
+            req.get().init_context().set_response_stream(
+                capnp_rpc::new_client_from_rc(collector.clone()));
+            let response = req.send().promise.await?;
+            for _ in 0..200 {
+                if collector.done.get() {
+                    return Ok((response, collector))
                 }
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
             }
-            Ok(())
-        } else {
-            let mut error = results.get().init_client_error();
-            error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-            Ok(())
+            panic!("response stream was never finished");
         }
-    }
 
-    async fn put(self: Rc<Self>,
-           params: web_session::PutParams,
-           mut results: web_session::PutResults)
-	-> Result<(), Error>
-    {
-        // HTTP PUT request.
+        async fn post(&self, path: &str, content: &[u8]) -> Result<Response, Error> {
+            let mut req = self.client.post_request();
+            req.get().set_path(path);
+            req.get().init_content().set_content(content);
+            req.send().promise.await
+        }
 
-        let params = params.get()?;
-        let path = params.get_path()?.to_str()?;
-        self.require_canonical_path(path)?;
+        async fn put(&self, path: &str, content: &[u8]) -> Result<Response, Error> {
+            let mut req = self.client.put_request();
+            req.get().set_path(path);
+            req.get().init_content().set_content(content);
+            req.send().promise.await
+        }
 
-        if !self.can_write {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
-            Ok(())
-        } else if path == "description" {
-            let content = params.get_content()?.get_content()?;
-            self.saved_ui_views.update_description(content)?;
-            let mut req = self.context.activity_request();
-            req.get().init_event().set_type(EDIT_DESCRIPTION_ACTIVITY_INDEX);
-            req.send().promise.await?;
-            results.get().init_no_content();
-            Ok(())
-        } else {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
-            Ok(())
+        /// Like `post()` or `put()`, with a MIME type, as when uploading a file.
+        async fn upload(&self, put: bool, path: &str, mime_type: &str, bytes: &[u8])
+                        -> Result<Response, Error>
+        {
+            if put {
+                let mut req = self.client.put_request();
+                req.get().set_path(path);
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(bytes);
+                req.send().promise.await
+            } else {
+                let mut req = self.client.post_request();
+                req.get().set_path(path);
+                let mut content = req.get().init_content();
+                content.set_mime_type(mime_type);
+                content.set_content(bytes);
+                req.send().promise.await
+            }
+        }
+
+        async fn delete(&self, path: &str) -> Result<Response, Error> {
+            let mut req = self.client.delete_request();
+            req.get().set_path(path);
+            req.send().promise.await
+        }
+
+        async fn open_web_socket(&self) -> Result<Rc<ActionRecorder>, Error> {
+            let recorder = Rc::new(ActionRecorder::default());
+            let mut req = self.client.open_web_socket_request();
+            req.get().set_client_stream(capnp_rpc::new_client_from_rc(recorder.clone()));
+            let response = req.send().promise.await?;
+            *recorder.server_stream.borrow_mut() = Some(response.get()?.get_server_stream()?);
+            Ok(recorder)
+        }
+
+        /// Goes through the powerbox flow that the frontend starts when the user picks `view`,
+        /// and returns the token of the new entry.
+        async fn add_grain(&self, harness: &Harness, request_token: &str, title: &str,
+                           view: &Rc<FakeUiView>)
+                           -> Result<String, Error>
+        {
+            self.add_view(harness, request_token, title, view.client()).await
+        }
+
+        /// Like `add_grain()`, for any view.
+        async fn add_view(&self, harness: &Harness, request_token: &str, title: &str,
+                          view: ui_view::Client)
+                          -> Result<String, Error>
+        {
+            self.context.add_claimable(request_token, view);
+            let before = harness.entry_tokens();
+            let response = self.post(&format!("token/{}", request_token),
+                                     powerbox_descriptor(title)?.as_bytes()).await?;
+            assert!(matches!(response.get()?.which()?, response::Content(_)));
+            let added: Vec<String> = harness.entry_tokens().difference(&before).cloned().collect();
+            assert_eq!(added.len(), 1);
+            Ok(added[0].clone())
         }
     }
 
-    async fn delete(self: Rc<Self>,
-                    params: web_session::DeleteParams,
-                    mut results: web_session::DeleteResults)
-	-> Result<(), Error>
-    {
-        // HTTP DELETE request.
+    /// What the frontend posts to token/<request token>: a packed `PowerboxDescriptor` with
+    /// the title of the grain, in base64.
+    fn powerbox_descriptor(title: &str) -> Result<String, Error> {
+        use capnp::traits::HasTypeId;
+        let mut message = ::capnp::message::Builder::new_default();
+        {
+            let root: powerbox_descriptor::Builder = message.init_root();
+            let mut tags = root.init_tags(1);
+            let mut tag = tags.reborrow().get(0);
+            tag.set_id(ui_view::Client::TYPE_ID);
+            let mut value: ui_view::powerbox_tag::Builder = tag.init_value().init_as();
+            value.set_title(title);
+        }
+        let mut bytes = Vec::new();
+        ::capnp::serialize_packed::write_message(&mut bytes, &message)?;
+        Ok(base64::engine::general_purpose::URL_SAFE.encode(&bytes))
+    }
 
-        let path = params.get()?.get_path()?.to_str()?;
-        self.require_canonical_path(path)?;
+    /// The browser end of a websocket. Records the actions that the collection sends.
+    #[derive(Default)]
+    struct ActionRecorder {
+        actions: RefCell<Vec<String>>,
+
+        /// Kept so that the collection doesn't consider the websocket closed.
+        server_stream: RefCell<Option<web_socket_stream::Client>>,
+    }
 
-        if !path.starts_with("sturdyref/") {
-            return Err(Error::failed("DELETE only supported under sturdyref/".to_string()));
+    impl ActionRecorder {
+        fn actions(&self) -> Vec<String> {
+            self.actions.borrow().clone()
         }
 
-        if !self.can_write {
-            results.get().init_client_error()
-                .set_status_code(web_session::response::ClientErrorCode::Forbidden);
-            Ok(())
-        } else {
-            let token_string = path[10..].to_string();
-            let binary_token = match base64::engine::general_purpose::URL_SAFE.decode(&token_string[..]) {
-                Ok(b) => b,
-                Err(e) => {
-                    results.get().init_client_error().set_description_html(&format!("{}", e));
-                    return Ok(())
+        /// Waits until at least `count` actions have arrived.
+        async fn wait_for_count(&self, count: usize) -> Vec<String> {
+            for _ in 0..200 {
+                if self.actions.borrow().len() >= count {
+                    break
                 }
-            };
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+            }
+            let actions = self.actions();
+            assert!(actions.len() >= count, "expected {} actions, got {:?}", count, actions);
+            actions
+        }
 
-            let mut saved_ui_views = self.saved_ui_views.clone();
-            let context = self.context.clone();
-            let mut req = self.sandstorm_api.drop_request();
-            req.get().set_token(&binary_token);
-            req.send().promise.await?;
-            saved_ui_views.remove(&token_string)?;
-            let mut req = context.activity_request();
-            req.get().init_event().set_type(REMOVE_GRAIN_ACTIVITY_INDEX);
-            req.send().promise.await?;
-            results.get().init_no_content();
+        /// Waits for an action that starts with `prefix` and returns it.
+        async fn wait_for(&self, prefix: &str) -> String {
+            for _ in 0..200 {
+                if let Some(a) = self.actions.borrow().iter().find(|a| a.starts_with(prefix)) {
+                    return a.clone()
+                }
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+            }
+            panic!("no action starting with {} in {:?}", prefix, self.actions());
+        }
+    }
+
+    impl web_socket_stream::Server for ActionRecorder {
+        async fn send_bytes(self: Rc<Self>,
+                            params: web_socket_stream::SendBytesParams,
+                            _results: web_socket_stream::SendBytesResults)
+                            -> Result<(), Error>
+        {
+            // The collection sends one unmasked frame per call.
+            let frame = params.get()?.get_message()?;
+            let opcode = frame[0] & 0x0f;
+            let (length, start) = match frame[1] {
+                126 => (((frame[2] as usize) << 8) | frame[3] as usize, 4),
+                127 => return Err(Error::failed("unexpectedly large frame".into())),
+                n => (n as usize, 2),
+            };
+            assert_eq!(frame.len(), start + length);
+            if opcode == 1 {
+                let text = ::std::str::from_utf8(&frame[start..])?;
+                self.actions.borrow_mut().push(text.into());
+            }
             Ok(())
         }
     }
 
-    async fn open_web_socket(self: Rc<Self>,
-                             params: web_session::OpenWebSocketParams,
-                             mut results: web_session::OpenWebSocketResults)
-                             -> Result<(), Error>
-    {
-        let client_stream = params.get()?.get_client_stream()?;
+    /// Stands in for the response stream that Sandstorm passes with a request.
+    #[derive(Default)]
+    struct ByteCollector {
+        bytes: RefCell<Vec<u8>>,
+        writes: Cell<usize>,
+        done: Cell<bool>,
+    }
 
-        results.get().set_server_stream(
-            self.saved_ui_views.new_subscribed_websocket(
-                client_stream,
-                self.can_write,
-                self.identity_id.clone()));
+    impl byte_stream::Server for ByteCollector {
+        async fn write(self: Rc<Self>,
+                       params: byte_stream::WriteParams,
+                       _results: byte_stream::WriteResults)
+                       -> Result<(), Error>
+        {
+            assert!(!self.done.get());
+            self.bytes.borrow_mut().extend_from_slice(params.get()?.get_data()?);
+            self.writes.set(self.writes.get() + 1);
+            Ok(())
+        }
 
-        Ok(())
+        async fn done(self: Rc<Self>,
+                      _params: byte_stream::DoneParams,
+                      _results: byte_stream::DoneResults)
+                      -> Result<(), Error>
+        {
+            self.done.set(true);
+            Ok(())
+        }
     }
-}
-
-fn fill_in_client_error(mut results: web_session::PostResults, e: Error)
-{
-    let mut client_error = results.get().init_client_error();
-    client_error.set_description_html(&format!("{}", e));
-}
 
-impl WebSession {
-    fn offer_ui_view(&self,
-                     text_token: String,
-                     title: String,
-                     _params: web_session::PostParams,
-                     mut results: web_session::PostResults)
-                     -> Promise<(), Error>
-    {
-        let token = match base64::engine::general_purpose::URL_SAFE.decode(&text_token[..]) {
-            Ok(b) => b,
-            Err(e) => return Promise::err(Error::failed(format!("{}", e))),
-        };
+    fn client_error_code(response: &Response) -> Option<response::ClientErrorCode> {
+        match response.get().unwrap().which().unwrap() {
+            response::ClientError(e) => Some(e.get_status_code().unwrap()),
+            _ => None,
+        }
+    }
 
-        let session_context = self.context.clone();
-        let set = self.saved_ui_views.clone();
-        let mut req = self.sandstorm_api.restore_request();
-        req.get().set_token(&token);
-        Promise::from_future(req.send().promise.then(move |response| match response {
-            Ok(v) => {
-                let sealed_ui_view: ui_view::Client =
-                    pry!(pry!(v.get()).get_cap().get_as_capability());
-                let mut req = session_context.offer_request();
-                req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
-                {
-                    use capnp::traits::HasTypeId;
-                    let tags = req.get().init_descriptor().init_tags(1);
-                    let mut tag = tags.get(0);
-                    tag.set_id(ui_view::Client::TYPE_ID);
-                    let mut value: ui_view::powerbox_tag::Builder = tag.get_value().init_as();
-                    value.set_title(&title);
-                }
+    fn client_error_description(response: &Response) -> String {
+        match response.get().unwrap().which().unwrap() {
+            response::ClientError(e) => e.get_description_html().unwrap().to_string().unwrap(),
+            _ => panic!("not a client error"),
+        }
+    }
 
-                Promise::from_future(req.send().promise.map_ok(|_| ()))
-            }
-            Err(e) => {
-                set.inner.borrow_mut().view_infos.insert(text_token.clone(), Err(e.clone()));
-                set.send_action_to_subscribers(Action::ViewInfo {
-                    token: text_token,
-                    data: Err(e),
-                });
-                Promise::ok(())
-            }
-        }).then(move |r| match r {
-            Ok(_) => {
-                results.get().init_no_content();
-                Promise::ok(())
-            }
-            Err(e) => {
-                fill_in_client_error(results, e);
-                Promise::ok(())
-            }
-        }))
+    fn is_no_content(response: &Response) -> bool {
+        matches!(response.get().unwrap().which().unwrap(), response::NoContent(_))
     }
 
-    fn read_powerbox_tag(&self, decoded_content: Vec<u8>) -> ::capnp::Result<String>
-    {
-        let mut cursor = ::std::io::Cursor::new(decoded_content);
-        let message = ::capnp::serialize_packed::read_message(&mut cursor,
-                                                              Default::default())?;
-        let desc: powerbox_descriptor::Reader = message.get_root()?;
-        let tags = desc.get_tags()?;
-        if tags.len() == 0 {
-            Err(Error::failed("no powerbox tag".into()))
-        } else {
-            let value: ui_view::powerbox_tag::Reader = tags.get(0).get_value().get_as()?;
-            Ok(value.get_title()?.to_string()?)
+    fn body_bytes(response: &Response) -> Vec<u8> {
+        match response.get().unwrap().which().unwrap() {
+            response::Content(content) => match content.get_body().which().unwrap() {
+                response::content::body::Bytes(bytes) => bytes.unwrap().to_vec(),
+                _ => panic!("expected bytes"),
+            },
+            _ => panic!("expected content"),
         }
     }
 
-    fn receive_request_token(&self,
-                             token: String,
-                             params: web_session::PostParams,
-                             mut results: web_session::PostResults)
-                             -> Promise<(), Error>
-    {
-        let content = pry!(pry!(pry!(params.get()).get_content()).get_content());
+    /// Removes `"exportedAt":"<millis>",` so that two exports can be compared.
+    fn without_exported_at(json: &str) -> String {
+        let start = json.find("\"exportedAt\":\"").unwrap();
+        let end = start + json[start..].find("\",").unwrap() + 2;
+        format!("{}{}", &json[..start], &json[end..])
+    }
 
-        let decoded_content = match base64::engine::general_purpose::URL_SAFE.decode(content) {
-            Ok(c) => c,
-            Err(_) => {
-                fill_in_client_error(results, Error::failed("failed to convert from base64".into()));
-                return Promise::ok(())
-            }
-        };
-        let grain_title: String = match self.read_powerbox_tag(decoded_content) {
-            Ok(t) => t,
-            Err(e) => {
-                fill_in_client_error(results, e);
-                return Promise::ok(());
-            }
-        };
+    #[test]
+    fn web_socket_starts_with_snapshot() {
+        run(async {
+            let harness = Harness::new("snapshot")?;
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+
+            let viewer = harness.open_session(2, false).await?;
+            let socket = viewer.open_web_socket().await?;
+            let actions = socket.wait_for_count(5).await;
+            let mirror = harness.saved_ui_views.inner.borrow().mirror.to_json();
+            assert_eq!(actions[..5], [
+                "{\"canWrite\":false}".to_string(),
+                format!("{{\"userId\":\"{}\"}}", IdentityId::from_bytes(&[2; 32])?),
+                "{\"description\":\"\"}".to_string(),
+                format!("{{\"mirror\":{}}}", mirror),
+                harness.insert_action(&token),
+            ]);
+            Ok(())
+        });
+    }
 
-        // now let's save this thing into an actual uiview sturdyref
-        let mut req = self.context.claim_request_request();
-        let sandstorm_api = self.sandstorm_api.clone();
-        req.get().set_request_token(&token);
-        let mut saved_ui_views = self.saved_ui_views.clone();
-        let identity_id = self.identity_id.clone();
+    #[test]
+    fn add_grain_saves_entry() {
+        run(async {
+            let harness = Harness::new("add-grain")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            socket.wait_for("{\"mirror\"").await;
+
+            let view = FakeUiView::new("Etherpad", "Meeting notes");
+            let token = editor.add_grain(&harness, "request-1", "Meeting notes", &view).await?;
+            assert_eq!(editor.context.activities(), vec![ADD_GRAIN_ACTIVITY_INDEX]);
+
+            let insert = harness.insert_action(&token);
+            assert_eq!(socket.wait_for("{\"insert\"").await, insert);
+            assert!(insert.contains("\"title\":\"Meeting notes\""));
+
+            let view_info = socket.wait_for("{\"viewInfo\"").await;
+            let stored = harness.saved_ui_views.inner.borrow().view_infos[&token].clone();
+            assert_eq!(view_info, Action::ViewInfo { token: token.clone(), data: stored }.to_json());
+            assert!(view_info.contains("\"appTitle\":\"Etherpad\""));
+            assert!(view_info.contains("https://icons.example.com/Etherpad.svg"));
+
+            // The grain's capability is saved with Sandstorm, and the entry is on disk.
+            let mut req = harness.sandstorm_api.restore_request();
+            req.get().set_token(&super::decode_token(&token)?[..]);
+            let restored: ui_view::Client = req.send().promise.await?.get()?.get_cap().get_as_capability()?;
+            let title = {
+                let titled = crate::collections_capnp::titled_view::Client { client: restored.client };
+                titled.get_title_request().send().promise.await?.get()?.get_title()?.to_string()?
+            };
+            assert_eq!(title, "Meeting notes");
+            let contents = entry_store::read(harness.root.join("entries"))?;
+            assert!(contents.entries.contains_key(&token));
+            assert!(harness.root.join("view-info").join(&token).exists());
+
+            // The entry survives a restart.
+            let root = harness.root.clone();
+            let restarted = Harness::open(root)?;
+            assert_eq!(restarted.insert_action(&token), insert);
+            Ok(())
+        });
+    }
 
-        let do_stuff = req.send().promise.and_then(move |response| {
-            let sealed_ui_view: ui_view::Client =
-                pry!(pry!(response.get()).get_cap().get_as_capability());
-            let mut req = sandstorm_api.save_request();
-            req.get().get_cap().set_as_capability(sealed_ui_view.client.hook);
+    #[test]
+    fn synced_titles_follow_the_grain_unless_pinned() {
+        run(async {
+            let harness = Harness::new("sync-titles")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+            let title = || harness.saved_ui_views.inner.borrow().views[&token].title.clone();
+
+            // Off by default.
+            view.set_title("Renamed");
+            harness.saved_ui_views.refresh_view_info(token.clone()).await?;
+            assert_eq!(title(), "Notes");
+
+            assert!(is_no_content(&editor.put("settings/sync-titles", b"true").await?));
+            harness.saved_ui_views.refresh_view_info(token.clone()).await?;
+            let history = socket.wait_for("{\"history\"").await;
+            assert!(history.contains("\"by\":null,\"titleChanged\":{\"oldTitle\":\"Notes\",\"newTitle\":\"Renamed\"}"));
+            assert_eq!(title(), "Renamed");
+            assert!(socket.actions().iter().any(|a| *a == harness.insert_action(&token)));
+
+            // A pinned title stays as it is.
+            assert!(is_no_content(&editor.put(&format!("pin/{}", token), b"true").await?));
+            view.set_title("Renamed again");
+            harness.saved_ui_views.refresh_view_info(token.clone()).await?;
+            assert_eq!(title(), "Renamed");
+            assert_eq!(harness.saved_ui_views.inner.borrow().history.len(), 2);
+
+            // The setting survives a restart.
+            let restarted = Harness::open(harness.root.clone())?;
+            assert!(restarted.saved_ui_views.inner.borrow().sync_titles);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn pinning_survives_torn_history() {
+        run(async {
+            let harness = Harness::new("torn-history")?;
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+
+            let response = editor.put(&format!("pin/{}", token), b"maybe").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::BadRequest));
+            let response = editor.put("pin/bm8tc3VjaA", b"true").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::NotFound));
+            assert!(is_no_content(&editor.put(&format!("pin/{}", token), b"true").await?));
+            assert_eq!(harness.saved_ui_views.inner.borrow().history.len(), 1);
+
+            // A crash partway through appending the next record leaves part of it behind.
+            let path = harness.root.join("history");
+            let complete = ::std::fs::read(&path)?;
+            let mut torn = complete.clone();
+            torn.extend_from_slice(&complete[..complete.len() / 2]);
+            ::std::fs::write(&path, &torn)?;
+
+            let restarted = Harness::open(harness.root.clone())?;
+            assert_eq!(restarted.saved_ui_views.inner.borrow().history.len(), 1);
+            assert_eq!(::std::fs::read(&path)?, complete);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn unreadable_files_are_set_aside() {
+        run(async {
+            let harness = Harness::new("set-aside")?;
+            let root = harness.root.clone();
+            drop(harness);
+
+            // A link whose `addedBy` isn't an identity ID, and a note that isn't a message.
+            let mut message = ::capnp::message::Builder::new_default();
             {
-                let mut save_label = req.get().init_label();
-                save_label.set_default_text(&format!("grain with title: {}", grain_title));
+                let mut link: link_metadata::Builder = message.init_root();
+                link.set_title("Example");
+                link.set_url("https://example.com/");
+                link.set_added_by("not hex");
             }
-            Promise::from_future(req.send().promise.map(move |r| {
-                let response = r?;
-                let binary_token = response.get()?.get_token()?;
-                let token = base64::engine::general_purpose::URL_SAFE.encode(binary_token);
+            ::std::fs::write(root.join("links").join("bGluaw"),
+                             ::capnp::serialize::write_message_to_words(&message))?;
+            ::std::fs::write(root.join("notes").join("bm90ZQ"), b"garbage")?;
+
+            // A quarantined entry whose `addedBy` can still be read.
+            let added_by = IdentityId::from_bytes(&[7; 32])?;
+            let mut message = ::capnp::message::Builder::new_default();
+            message.init_root::<ui_view_metadata::Builder>().set_added_by(&added_by.to_string());
+            ::std::fs::write(root.join("quarantine").join("dG9rZW4"),
+                             ::capnp::serialize::write_message_to_words(&message))?;
+
+            let harness = Harness::open(root.clone())?;
+            assert!(harness.saved_ui_views.inner.borrow().links.is_empty());
+            assert!(harness.saved_ui_views.inner.borrow().notes.is_empty());
+            assert!(root.join("set-aside").join("links").join("bGluaw").exists());
+            assert_eq!(::std::fs::read(root.join("set-aside").join("notes").join("bm90ZQ"))?,
+                       b"garbage");
+            assert!(harness.saved_ui_views.referenced_identities().contains(&added_by));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn viewers_cannot_edit() {
+        run(async {
+            let harness = Harness::new("permissions")?;
+            let editor = harness.open_session(1, true).await?;
+            let viewer = harness.open_session(2, false).await?;
+            let socket = viewer.open_web_socket().await?;
+            socket.wait_for("{\"mirror\"").await;
+
+            let response = viewer.put("description", b"mine now").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::Forbidden));
+            assert!(viewer.context.activities().is_empty());
+
+            let response = editor.put("description", b"Team grains").await?;
+            assert!(is_no_content(&response));
+            assert_eq!(editor.context.activities(), vec![EDIT_DESCRIPTION_ACTIVITY_INDEX]);
+            assert_eq!(socket.wait_for("{\"description\":\"Team").await,
+                       "{\"description\":\"Team grains\"}");
+            assert_eq!(::std::fs::read_to_string(harness.root.join("description"))?,
+                       "Team grains");
+
+            let response = viewer.get("api/description").await?;
+            assert_eq!(body_bytes(&response), b"{\"description\":\"Team grains\"}");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn parse_link_form_accepts_only_web_urls() {
+        use super::parse_link_form;
+        assert_eq!(parse_link_form(b"url=https%3A%2F%2Fexample.com%2F&title=Example").unwrap(),
+                   ("https://example.com/".to_string(), "Example".to_string()));
+        assert_eq!(parse_link_form(b"url=http%3A%2F%2Fexample.com%2F&title=").unwrap(),
+                   ("http://example.com/".to_string(), "http://example.com/".to_string()));
+        assert_eq!(parse_link_form(b"url=http%3A%2F%2Fexample.com%2F").unwrap().1,
+                   "http://example.com/");
+
+        for rejected in &[&b"url=javascript%3Aalert(1)"[..],
+                          b"url=JavaScript%3Aalert(1)",
+                          b"url=data%3Atext%2Fhtml%2C%3Cscript%3Ealert(1)%3C%2Fscript%3E",
+                          b"url=file%3A%2F%2F%2Fetc%2Fpasswd",
+                          b"url=example.com",
+                          b"title=No+URL",
+                          b""] {
+            assert!(parse_link_form(rejected).is_err(), "{:?}", ::std::str::from_utf8(rejected));
+        }
+        assert_eq!(parse_link_form(b"title=No+URL").unwrap_err().extra, "missing url");
+    }
+
+    #[test]
+    fn rejected_links_are_not_saved() {
+        run(async {
+            let harness = Harness::new("rejected-link")?;
+            let editor = harness.open_session(1, true).await?;
+            let response = editor.post("link", b"url=javascript%3Aalert(1)&title=Click").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::BadRequest));
+            assert!(client_error_description(&response).ends_with("unsupported URL scheme: javascript"));
+            assert!(harness.saved_ui_views.inner.borrow().links.is_empty());
+            Ok(())
+        });
+    }
 
-                saved_ui_views.insert(token.clone(), grain_title, identity_id)?;
+    /// The ID in the body of a `Created` response.
+    fn created_id(response: &Response) -> String {
+        let body = ::std::str::from_utf8(&body_bytes(response)).unwrap().to_string();
+        body.trim_start_matches("{\"id\":\"").trim_end_matches("\"}").to_string()
+    }
 
-                SavedUiViewSet::retrieve_view_info(&saved_ui_views, token)?;
-                Ok(())
-            }))
+    #[test]
+    fn uploads_stay_within_quota() {
+        run(async {
+            let harness = Harness::new("file-quota")?;
+            let editor = harness.open_session(1, true).await?;
+
+            let response = editor.upload(false, "files/huge.bin", "application/octet-stream",
+                                         &vec![0; super::MAX_FILE_SIZE as usize + 1]).await?;
+            assert_eq!(client_error_code(&response),
+                       Some(response::ClientErrorCode::RequestEntityTooLarge));
+
+            // Pretend that the collection is nearly full, without writing that much.
+            harness.saved_ui_views.inner.borrow_mut().files.insert("big".into(), super::FileData {
+                name: "big.bin".into(),
+                mime_type: "application/octet-stream".into(),
+                size: super::FILE_QUOTA - 20,
+                date_added: 1,
+                added_by: None,
+            });
+            let response = editor.upload(false, "files/a.txt", "text/plain", &[b'a'; 20]).await?;
+            let id = created_id(&response);
+            let response = editor.upload(false, "files/b.txt", "text/plain", b"b").await?;
+            assert_eq!(client_error_code(&response),
+                       Some(response::ClientErrorCode::RequestEntityTooLarge));
+            assert_eq!(harness.saved_ui_views.inner.borrow().files.len(), 2);
+
+            // Replacing a file only counts the difference.
+            let path = format!("files/{}", id);
+            assert!(is_no_content(&editor.upload(true, &path, "text/plain", &[b'c'; 20]).await?));
+            let response = editor.upload(true, &path, "text/plain", &[b'd'; 21]).await?;
+            assert_eq!(client_error_code(&response),
+                       Some(response::ClientErrorCode::RequestEntityTooLarge));
+            assert_eq!(body_bytes(&editor.get(&path).await?), vec![b'c'; 20]);
+            Ok(())
         });
+    }
 
-        let context = self.context.clone();
-        Promise::from_future(do_stuff.then(move |r| match r {
-            Ok(()) => {
-                let mut req = context.activity_request();
-                req.get().init_event().set_type(ADD_GRAIN_ACTIVITY_INDEX);
-                Promise::from_future(req.send().promise.and_then(move |_| {
-                    let mut _content = results.get().init_content();
-                    Promise::ok(())
-                }))
-            }
-            Err(e) => {
-                let mut error = results.get().init_client_error();
-                error.set_description_html(&format!("error: {:?}", e));
-                Promise::ok(())
+    #[test]
+    fn only_safe_file_types_are_shown_inline() {
+        run(async {
+            let harness = Harness::new("file-disposition")?;
+            let editor = harness.open_session(1, true).await?;
+            let html = b"<script>alert(1)</script>";
+            for &(name, mime_type, inline) in &[("page.html", "text/html", false),
+                                                ("image.svg", "image/svg+xml", false),
+                                                ("photo.png", "image/png", true)] {
+                let response = editor.upload(false, &format!("files/{}", name), mime_type, html).await?;
+                let response = editor.get(&format!("files/{}", created_id(&response))).await?;
+                let content = match response.get()?.which()? {
+                    response::Content(c) => c,
+                    _ => panic!("no content for {}", name),
+                };
+                assert_eq!(content.get_mime_type()?.to_str()?, mime_type);
+                match content.get_disposition().which()? {
+                    response::content::disposition::Normal(()) => assert!(inline, "{} is inline", name),
+                    response::content::disposition::Download(n) => {
+                        assert!(!inline, "{} is a download", name);
+                        assert_eq!(n?.to_str()?, name);
+                    }
+                }
             }
-        }))
+            Ok(())
+        });
     }
 
-    fn require_canonical_path(&self, path: &str) -> Result<(), Error> {
-        // Require that the path doesn't contain "." or ".." or consecutive slashes, to prevent path
-        // injection attacks.
-        //
-        // Note that such attacks wouldn't actually accomplish much since everything outside /var
-        // is a read-only filesystem anyway, containing the app package contents which are non-secret.
+    #[test]
+    fn client_errors_escape_the_request() {
+        run(async {
+            let harness = Harness::new("escaped-errors")?;
+            let editor = harness.open_session(1, true).await?;
+            let response = editor.post("note", b"kind=%3Cscript%3Ealert(1)%3C%2Fscript%3E").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::BadRequest));
+            assert!(client_error_description(&response)
+                    .ends_with("unknown note kind: &lt;script&gt;alert(1)&lt;/script&gt;"));
+            assert!(harness.saved_ui_views.inner.borrow().notes.is_empty());
+            Ok(())
+        });
+    }
 
-        for (idx, component) in path.split_terminator("/").enumerate() {
-            if component == "." || component == ".." || (component == "" && idx > 0) {
-                return Err(Error::failed(format!("non-canonical path: {:?}", path)));
-            }
-        }
-        Ok(())
+    #[test]
+    fn api_tokens_only_reach_api_paths() {
+        run(async {
+            let harness = Harness::new("api-only")?;
+            let editor = harness.open_session(1, true).await?;
+            editor.put("description", b"Team grains").await?;
+
+            let api = harness.open_api_session(1, true).await?;
+            let response = api.get("api/description").await?;
+            assert_eq!(body_bytes(&response), b"{\"description\":\"Team grains\"}");
+
+            let response = api.put("description", b"Hijacked").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::Forbidden));
+            let response = api.get("export.json").await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::Forbidden));
+            assert!(api.open_web_socket().await.is_err());
+            assert_eq!(::std::fs::read_to_string(harness.root.join("description"))?,
+                       "Team grains");
+            Ok(())
+        });
     }
 
-    fn read_file(&self,
-                 filename: &str,
-                 mut results: web_session::GetResults,
-                 content_type: &str,
-                 encoding: Option<&str>)
-                 -> Result<(), Error>
-    {
-        match ::std::fs::File::open(filename) {
-            Ok(mut f) => {
-                let size = f.metadata()?.len();
-                let mut content = results.get().init_content();
-                content.set_status_code(web_session::response::SuccessCode::Ok);
-                content.set_mime_type(content_type);
-                encoding.map(|enc| content.set_encoding(enc));
+    #[test]
+    fn collection_session_lists_entries() {
+        run(async {
+            let harness = Harness::new("list-entries")?;
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+
+            let context = capnp_rpc::new_client(FakeSessionContext::default());
+            let session = super::open_collection_session(harness.view.clone(), context).await?;
+            let response = session.get_id_request().send().promise.await?;
+            assert_eq!(response.get()?.get_id()?.to_str()?,
+                       harness.saved_ui_views.inner.borrow().collection_id);
+            let response = session.list_entries_request().send().promise.await?;
+            let entries = response.get()?.get_entries()?;
+            assert_eq!(entries.len(), 1);
+            let metadata = entries.get(0).get_metadata()?;
+            assert_eq!(metadata.get_title()?.to_str()?, "Notes");
+            assert!(entries.get(0).has_view());
+            assert!(harness.entry_tokens().contains(&token));
+            Ok(())
+        });
+    }
 
-                let mut body = content.init_body().init_bytes(size as u32);
-                ::std::io::copy(&mut f, &mut body)?;
-                Ok(())
-            }
-            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
-                let mut error = results.get().init_client_error();
-                error.set_status_code(web_session::response::ClientErrorCode::NotFound);
-                Ok(())
-            }
-            Err(e) => {
-                Err(e.into())
-            }
-        }
+    /// Another collection grain, or with `is_collection` false, a grain of some other app. Counts
+    /// the sessions opened on it and how often it was listed, and records the titles of the
+    /// entries added to it. Refuses to add an entry titled `rejected`.
+    struct FakeCollection {
+        id: String,
+        is_collection: bool,
+        entries: RefCell<Vec<ui_view::Client>>,
+        sessions: Cell<u32>,
+        listed: Cell<u32>,
+        added: RefCell<Vec<String>>,
+        rejected: RefCell<Option<String>>,
     }
-}
 
-pub struct UiView {
-    sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-    saved_ui_views: SavedUiViewSet,
-}
+    impl FakeCollection {
+        fn new(id: &str, is_collection: bool) -> Rc<FakeCollection> {
+            Rc::new(FakeCollection {
+                id: id.into(),
+                is_collection: is_collection,
+                entries: RefCell::new(Vec::new()),
+                sessions: Cell::new(0),
+                listed: Cell::new(0),
+                added: RefCell::new(Vec::new()),
+                rejected: RefCell::new(None),
+            })
+        }
 
-impl UiView {
-    fn new(client: sandstorm_api::Client<::capnp::any_pointer::Owned>,
-           saved_ui_views: SavedUiViewSet)
-           -> UiView
-    {
-        UiView {
-            sandstorm_api: client,
-            saved_ui_views: saved_ui_views,
+        fn client(self: &Rc<Self>) -> ui_view::Client {
+            capnp_rpc::new_client_from_rc(self.clone())
         }
     }
-}
 
-impl ui_view::Server for UiView {
-    async fn get_view_info(self: Rc<Self>,
-                     _params: ui_view::GetViewInfoParams,
-                     mut results: ui_view::GetViewInfoResults)
-                     -> Result<(), Error>
-    {
-        let mut view_info = results.get();
+    impl ui_view::Server for FakeCollection {
+        async fn get_view_info(self: Rc<Self>,
+                               _params: ui_view::GetViewInfoParams,
+                               mut results: ui_view::GetViewInfoResults)
+                               -> Result<(), Error>
+        {
+            let mut view_info = results.get();
+            view_info.reborrow().init_app_title().set_default_text("Collections");
+            view_info.set_grain_icon(new_icon("icons.example.com/Collections.svg"));
+            Ok(())
+        }
 
-        // Define a "write" permission, and then define roles "editor" and "viewer" where only
-        // "editor" has the "write" permission. This will allow people to share read-only.
+        async fn new_session(self: Rc<Self>,
+                             _params: ui_view::NewSessionParams,
+                             mut results: ui_view::NewSessionResults)
+                             -> Result<(), Error>
         {
-            let perms = view_info.reborrow().init_permissions(1);
-            let mut write = perms.get(0);
-            write.set_name("write");
-            write.init_title().set_default_text("write");
+            self.sessions.set(self.sessions.get() + 1);
+            if !self.is_collection {
+                return Err(Error::unimplemented("not a collection".into()))
+            }
+            let session: collection_session::Client = capnp_rpc::new_client_from_rc(self.clone());
+            results.get().set_session(ui_session::Client { client: session.client });
+            Ok(())
         }
+    }
 
+    impl ui_session::Server for FakeCollection {}
+
+    impl collection_session::Server for FakeCollection {
+        async fn add_entry(self: Rc<Self>,
+                           params: collection_session::AddEntryParams,
+                           _results: collection_session::AddEntryResults)
+                           -> Result<(), Error>
         {
-            let mut roles = view_info.reborrow().init_roles(2);
-            {
-                let mut editor = roles.reborrow().get(0);
-                editor.reborrow().init_title().set_default_text("editor");
-                editor.reborrow().init_verb_phrase().set_default_text("can edit");
-                editor.init_permissions(1).set(0, true);   // has "write" permission
+            let params = params.get()?;
+            let title = params.get_metadata()?.get_title()?.to_string()?;
+            if self.rejected.borrow().as_ref() == Some(&title) {
+                return Err(Error::failed(format!("rejected {}", title)))
             }
-            {
-                let mut viewer = roles.get(1);
-                viewer.set_default(true);
-                viewer.reborrow().init_title().set_default_text("viewer");
-                viewer.reborrow().init_verb_phrase().set_default_text("can view");
-                viewer.init_permissions(1).set(0, false);  // does not have "write" permission
+            self.entries.borrow_mut().push(params.get_view()?);
+            self.added.borrow_mut().push(title);
+            Ok(())
+        }
+
+        async fn list_entries(self: Rc<Self>,
+                              _params: collection_session::ListEntriesParams,
+                              mut results: collection_session::ListEntriesResults)
+                              -> Result<(), Error>
+        {
+            self.listed.set(self.listed.get() + 1);
+            let views = self.entries.borrow();
+            let mut entries = results.get().init_entries(views.len() as u32);
+            for (idx, view) in views.iter().enumerate() {
+                let mut entry = entries.reborrow().get(idx as u32);
+                entry.reborrow().init_metadata().set_title(&format!("entry {}", idx));
+                entry.set_view(view.clone());
             }
+            Ok(())
         }
 
+        async fn get_id(self: Rc<Self>,
+                        _params: collection_session::GetIdParams,
+                        mut results: collection_session::GetIdResults)
+                        -> Result<(), Error>
         {
-            let mut event_types = view_info.init_event_types(3);
-            {
-                let mut added = event_types.reborrow().get(ADD_GRAIN_ACTIVITY_INDEX as u32);
-                added.set_name("add");
-                added.reborrow().init_verb_phrase().set_default_text("added grain");
+            results.get().set_id(&self.id);
+            Ok(())
+        }
+    }
+
+    /// Waits until `condition` holds, letting background tasks run.
+    async fn wait_until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return
             }
-            {
-                let mut removed = event_types.reborrow().get(REMOVE_GRAIN_ACTIVITY_INDEX as u32);
-                removed.set_name("remove");
-                removed.reborrow().init_verb_phrase().set_default_text("removed grain");
+            tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+        }
+        panic!("condition never held");
+    }
+
+    #[test]
+    fn nested_collections_stop_at_cycles_and_depth() {
+        run(async {
+            let harness = Harness::new("nested-limits")?;
+            let editor = harness.open_session(1, true).await?;
+
+            // c1 contains c2, which contains c1 again and a chain that goes on too deep.
+            let chain: Vec<Rc<FakeCollection>> = (1..=super::MAX_NESTING_DEPTH + 1)
+                .map(|n| FakeCollection::new(&format!("c{}", n), true))
+                .collect();
+            for pair in chain.windows(2) {
+                pair[0].entries.borrow_mut().push(pair[1].client());
             }
-            {
-                let mut removed = event_types.reborrow().get(EDIT_DESCRIPTION_ACTIVITY_INDEX as u32);
-                removed.set_name("description");
-                removed.reborrow().init_verb_phrase().set_default_text("edited description");
+            chain[1].entries.borrow_mut().push(chain[0].client());
+
+            let token = editor.add_view(&harness, "request-1", "Chain", chain[0].client()).await?;
+            wait_until(|| harness.saved_ui_views.inner.borrow().nested.contains_key(&token)).await;
+
+            // The cycle is caught before c1 lists its entries a second time.
+            assert_eq!(chain[0].listed.get(), 1);
+            let nested = harness.saved_ui_views.inner.borrow().nested[&token].to_json();
+            assert!(nested.contains("\"nested\":{\"cycle\":true}"));
+
+            // The last collection in the chain is too deep to even open a session on.
+            for c in &chain[1..super::MAX_NESTING_DEPTH as usize] {
+                assert_eq!(c.listed.get(), 1);
             }
-        }
+            let last = chain.last().unwrap();
+            assert_eq!((last.sessions.get(), last.listed.get()), (0, 0));
+            Ok(())
+        });
+    }
 
-        Ok(())
+    #[test]
+    fn contents_are_only_fetched_again_on_demand() {
+        run(async {
+            let harness = Harness::new("nested-on-demand")?;
+            let editor = harness.open_session(1, true).await?;
+            let collection = FakeCollection::new("c1", true);
+            collection.entries.borrow_mut().push(FakeUiView::new("Etherpad", "Notes").client());
+            let other = FakeCollection::new("not a collection", false);
+            let collection_token = editor.add_view(&harness, "request-1", "Collection",
+                                                   collection.client()).await?;
+            let other_token = editor.add_view(&harness, "request-2", "Other", other.client()).await?;
+            wait_until(|| collection.listed.get() == 1 && other.sessions.get() == 1).await;
+
+            // Health checks and bulk refreshes don't look again.
+            harness.saved_ui_views.refresh_view_info(collection_token.clone()).await?;
+            harness.saved_ui_views.refresh_view_info(other_token.clone()).await?;
+            tokio::time::delay_for(::std::time::Duration::from_millis(50)).await;
+            assert_eq!((collection.listed.get(), other.sessions.get()), (1, 1));
+
+            // A user asking for a refresh does.
+            assert!(is_no_content(&editor.post(&format!("refresh/{}", collection_token), b"").await?));
+            assert!(is_no_content(&editor.post(&format!("api/entries/{}/refresh", other_token), b"").await?));
+            wait_until(|| collection.listed.get() == 2 && other.sessions.get() == 2).await;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn transfers_report_each_entry() {
+        run(async {
+            let harness = Harness::new("transfers")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            let mut tokens = Vec::new();
+            for (idx, title) in ["A", "B", "C"].iter().enumerate() {
+                let view = FakeUiView::new("Etherpad", title);
+                tokens.push(editor.add_grain(&harness, &format!("request-{}", idx), title, &view).await?);
+            }
+            let target = FakeCollection::new("c2", true);
+            *target.rejected.borrow_mut() = Some("B".into());
+
+            // A copy leaves every entry here, whether or not it made it over.
+            editor.context.add_claimable("target-1", target.client());
+            let body = format!("{}\n{}\nno-such-token\n", tokens[0], tokens[1]);
+            let response = editor.post("copy/target-1", body.as_bytes()).await?;
+            assert_eq!(body_bytes(&response), b"{\"transferId\":0}");
+            socket.wait_for("{\"transferDone\":{\"id\":0}").await;
+            assert_eq!(*target.added.borrow(), vec!["A".to_string()]);
+            assert_eq!(harness.entry_tokens().len(), 3);
+            let reports: Vec<String> = socket.actions().into_iter()
+                .filter(|a| a.starts_with("{\"transferred\":{\"id\":0,"))
+                .collect();
+            assert_eq!(reports.len(), 3);
+            assert_eq!(reports[0],
+                       format!("{{\"transferred\":{{\"id\":0,\"token\":\"{}\"}}}}", tokens[0]));
+            assert!(reports[1].contains("\"failed\":") && reports[1].contains("rejected B"));
+            assert!(reports[2].contains("no such entry"));
+
+            // A move takes the entries that made it over out of this collection together, after
+            // reporting the ones that didn't.
+            editor.context.add_claimable("target-2", target.client());
+            let body = format!("{}\n{}\n{}\n", tokens[0], tokens[1], tokens[2]);
+            let response = editor.post("move/target-2", body.as_bytes()).await?;
+            assert_eq!(body_bytes(&response), b"{\"transferId\":1}");
+            socket.wait_for("{\"transferDone\":{\"id\":1}").await;
+            assert_eq!(*target.added.borrow(), vec!["A".to_string(), "A".into(), "C".into()]);
+            assert_eq!(harness.entry_tokens().into_iter().collect::<Vec<_>>(), vec![tokens[1].clone()]);
+            let reports: Vec<String> = socket.actions().into_iter()
+                .filter(|a| a.starts_with("{\"transferred\":{\"id\":1,"))
+                .collect();
+            assert_eq!(reports.len(), 3);
+            assert!(reports[0].contains(&tokens[1]) && reports[0].contains("rejected B"));
+            for (report, token) in reports[1..].iter().zip([&tokens[0], &tokens[2]].iter()) {
+                assert_eq!(*report,
+                           format!("{{\"transferred\":{{\"id\":1,\"token\":\"{}\"}}}}", token));
+            }
+            assert_eq!(editor.context.activities().iter()
+                           .filter(|&&a| a == REMOVE_GRAIN_ACTIVITY_INDEX).count(), 2);
+            assert_eq!(harness.saved_ui_views.inner.borrow().mirror.tombstones.len(), 2);
+            for token in [&tokens[0], &tokens[2]].iter() {
+                let mut req = harness.sandstorm_api.restore_request();
+                req.get().set_token(&super::decode_token(token)?[..]);
+                assert!(req.send().promise.await.is_err());
+            }
+            Ok(())
+        });
     }
 
+    #[test]
+    fn restores_stay_within_the_limit() {
+        run(async {
+            let api = Rc::new(FakeSandstormApi::default());
+            let root = Harness::new("restore-limit")?.root;
+            let harness = Harness::open_with(root.clone(), api.clone(), 2)?;
+            let editor = harness.open_session(1, true).await?;
+            let mut tokens = Vec::new();
+            for idx in 0..5 {
+                let title = format!("Notes {}", idx);
+                let view = FakeUiView::new("Etherpad", &title);
+                tokens.push(editor.add_grain(&harness, &format!("request-{}", idx), &title, &view).await?);
+            }
+            wait_until(|| {
+                let inner = harness.saved_ui_views.inner.borrow();
+                inner.restores_in_flight == 0 && tokens.iter().all(|t| inner.view_infos.contains_key(t))
+            }).await;
+
+            // After a restart, nothing is restored until someone looks. Then the entry without a
+            // cached icon comes first, and no more than two restores run at once.
+            ::std::fs::remove_file(root.join("view-info").join(&tokens[1]))?;
+            let binary_tokens: Vec<Vec<u8>> =
+                tokens.iter().map(|t| super::decode_token(t)).collect::<Result<_, _>>()?;
+            for t in &binary_tokens {
+                api.hold_restores(t);
+            }
+            let harness = Harness::open_with(root, api.clone(), 2)?;
+            tokio::time::delay_for(::std::time::Duration::from_millis(50)).await;
+            assert!(api.held_restores().is_empty());
+
+            let viewer = harness.open_session(2, true).await?;
+            let _socket = viewer.open_web_socket().await?;
+            wait_until(|| api.held_restores().len() == 2).await;
+            assert_eq!(api.held_restores()[0], binary_tokens[1]);
+            tokio::time::delay_for(::std::time::Duration::from_millis(50)).await;
+            assert_eq!(api.held_restores().len(), 2);
+            assert_eq!(harness.saved_ui_views.inner.borrow().restores_in_flight, 2);
+
+            // A new entry waits for a free slot too, but then goes ahead of the deferred ones.
+            let view = FakeUiView::new("Etherpad", "New");
+            let new_token = viewer.add_grain(&harness, "request-new", "New", &view).await?;
+            let new_binary_token = super::decode_token(&new_token)?;
+            tokio::time::delay_for(::std::time::Duration::from_millis(50)).await;
+            assert!(!api.restored().contains(&new_binary_token));
+
+            let started = api.held_restores();
+            api.release_restores(&binary_tokens[1]);
+            wait_until(|| api.held_restores().len() == 2).await;
+            let restored = api.restored();
+            let position = |t: &Vec<u8>| restored.iter().rposition(|r| r == t).unwrap();
+            let next_deferred = api.held_restores().into_iter()
+                .find(|t| !started.contains(t))
+                .unwrap();
+            assert!(position(&new_binary_token) < position(&next_deferred));
+            assert_eq!(harness.saved_ui_views.inner.borrow().restores_in_flight, 2);
+
+            for t in &binary_tokens {
+                api.release_restores(t);
+            }
+            wait_until(|| {
+                let inner = harness.saved_ui_views.inner.borrow();
+                inner.restores_in_flight == 0 && inner.deferred_view_info_queue.is_empty()
+            }).await;
+            Ok(())
+        });
+    }
 
-    async fn new_session(self: Rc<Self>,
-                   params: ui_view::NewSessionParams,
-                   mut results: ui_view::NewSessionResults)
-                   -> Result<(), Error>
+    /// An edit of the entry `entry_id` made at `timestamp`, as a linked collection sends it.
+    fn mirror_upsert(entry_id: &str, timestamp: u64, title: &str, title_pinned: bool,
+                     view: Option<ui_view::Client>) -> super::MirrorChange
     {
-        use ::capnp::traits::HasTypeId;
-        let params = params.get()?;
+        let data = super::SavedUiViewData {
+            title: title.into(),
+            date_added: 1,
+            added_by: None,
+            entry_id: entry_id.into(),
+            modified_at: timestamp,
+            title_pinned: title_pinned,
+            added_by_name: None,
+        };
+        super::MirrorChange {
+            entry_id: entry_id.into(),
+            timestamp: timestamp,
+            kind: super::MirrorChangeKind::Upsert { data: data, view: view },
+        }
+    }
 
-        if params.get_session_type() != web_session::Client::TYPE_ID {
-            return Err(Error::failed("unsupported session type".to_string()));
+    fn mirror_remove(entry_id: &str, timestamp: u64) -> super::MirrorChange {
+        super::MirrorChange {
+            entry_id: entry_id.into(),
+            timestamp: timestamp,
+            kind: super::MirrorChangeKind::Remove,
         }
+    }
 
-        let user_info = params.get_user_info()?;
+    #[test]
+    fn mirror_conflicts_keep_the_latest_change() {
+        struct NullListener;
+        impl mirror_listener::Server for NullListener {}
+
+        run(async {
+            let harness = Harness::new("mirror-conflicts")?;
+            let views = harness.saved_ui_views.clone();
+            let peer = views.add_mirror_peer(capnp_rpc::new_client(NullListener));
+            let state = |views: &SavedUiViewSet, entry_id: &str| -> Option<(String, bool)> {
+                views.inner.borrow().views.values()
+                    .find(|v| v.entry_id == entry_id)
+                    .map(|v| (v.title.clone(), v.title_pinned))
+            };
 
-        let session = WebSession::new(
-            user_info.clone(),
-            params.get_context()?,
-            params.get_session_params().get_as()?,
-            self.sandstorm_api.clone(),
-            self.saved_ui_views.clone())?;
-        let client: web_session::Client = capnp_rpc::new_client(session);
+            let view = FakeUiView::new("Etherpad", "Notes");
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 100, "Notes", false, Some(view.client()))],
+                                       peer).await?;
+            assert_eq!(state(&views, "e1"), Some(("Notes".into(), false)));
+
+            // An older edit loses. A newer one wins, along with whether the title is pinned.
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 50, "Older", false, None)], peer).await?;
+            assert_eq!(state(&views, "e1"), Some(("Notes".into(), false)));
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 200, "Renamed", true, None)], peer).await?;
+            assert_eq!(state(&views, "e1"), Some(("Renamed".into(), true)));
+
+            // Between edits made at the same millisecond, the greater title wins.
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 200, "Apples", true, None)], peer).await?;
+            assert_eq!(state(&views, "e1"), Some(("Renamed".into(), true)));
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 200, "Zebras", false, None)], peer).await?;
+            assert_eq!(state(&views, "e1"), Some(("Zebras".into(), false)));
+
+            // A removal older than the last edit loses. One made at the same millisecond wins,
+            // and then keeps edits that are no newer from bringing the entry back.
+            views.apply_mirror_changes(vec![mirror_remove("e1", 150)], peer).await?;
+            assert!(state(&views, "e1").is_some());
+            views.apply_mirror_changes(vec![mirror_remove("e1", 200)], peer).await?;
+            assert_eq!(state(&views, "e1"), None);
+            let view = FakeUiView::new("Etherpad", "Notes");
+            views.apply_mirror_changes(vec![mirror_upsert("e1", 200, "Zebras", false, Some(view.client()))],
+                                       peer).await?;
+            assert_eq!(state(&views, "e1"), None);
+
+            // A removal cut short after its tombstone was recorded is finished on startup.
+            let view = FakeUiView::new("Etherpad", "Plans");
+            views.apply_mirror_changes(vec![mirror_upsert("e2", 300, "Plans", false, Some(view.client()))],
+                                       peer).await?;
+            views.inner.borrow_mut().mirror.record_tombstone("e2", 300)?;
+            let restarted = Harness::open(harness.root.clone())?;
+            for _ in 0..100 {
+                if state(&restarted.saved_ui_views, "e2").is_none() {
+                    break
+                }
+                tokio::time::delay_for(::std::time::Duration::from_millis(10)).await;
+            }
+            assert_eq!(state(&restarted.saved_ui_views, "e2"), None);
+            let tombstones = restarted.saved_ui_views.inner.borrow().mirror.tombstones.clone();
+            assert_eq!(tombstones.get("e1"), Some(&200));
+            assert_eq!(tombstones.get("e2"), Some(&300));
+            Ok(())
+        });
+    }
 
-        // We need to do this silly dance to upcast.
-        results.get().set_session(ui_session::Client { client : client.client});
+    #[test]
+    fn unlinked_mirror_peer_is_rejected() {
+        struct NullListener;
+        impl mirror_listener::Server for NullListener {}
+
+        run(async {
+            let harness = Harness::new("mirror-unlink")?;
+            let context = capnp_rpc::new_client(FakeSessionContext::default());
+            let mut user_info = ::capnp::message::Builder::new_default();
+            user_info.init_root::<user_info::Builder>().init_permissions(1).set(0, true);
+            let session = {
+                use capnp::traits::HasTypeId;
+                let mut req = harness.view.new_session_request();
+                req.get().set_session_type(collection_session::Client::TYPE_ID);
+                req.get().set_user_info(user_info.get_root_as_reader()?)?;
+                req.get().set_context(context);
+                let response = req.send().promise.await?;
+                collection_session::Client { client: response.get()?.get_session()?.client }
+            };
+            let own_id = harness.saved_ui_views.inner.borrow().collection_id.clone();
+
+            let mut req = session.link_request();
+            req.get().set_listener(capnp_rpc::new_client(NullListener));
+            req.get().set_id(&own_id);
+            assert!(req.send().promise.await.is_err());
+            assert!(harness.saved_ui_views.inner.borrow().mirror.peers.is_empty());
+
+            let mut req = session.link_request();
+            req.get().set_listener(capnp_rpc::new_client(NullListener));
+            req.get().set_id("another collection");
+            let response = req.send().promise.await?;
+            let listener = response.get()?.get_listener()?;
+            assert_eq!(harness.saved_ui_views.inner.borrow().mirror.peers.len(), 1);
+
+            listener.unlink_request().send().promise.await?;
+            assert!(harness.saved_ui_views.inner.borrow().mirror.peers.is_empty());
+            let mut req = listener.apply_changes_request();
+            req.get().init_changes(0);
+            assert!(req.send().promise.await.is_err());
+            Ok(())
+        });
+    }
 
-        if user_info.has_identity_id() {
-            let identity = user_info.get_identity()?;
+    #[test]
+    fn failed_refresh_keeps_cached_view_info() {
+        run(async {
+            let harness = Harness::new("failed-refresh")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+            socket.wait_for("{\"viewInfo\"").await;
+
+            // Sandstorm forgets the grain, so the next refresh fails.
+            let mut req = harness.sandstorm_api.drop_request();
+            req.get().set_token(&super::decode_token(&token)?[..]);
+            req.send().promise.await?;
+            assert!(harness.saved_ui_views.refresh_view_info(token.clone()).await.is_err());
 
-            // TODO(cleanup)
-            self.saved_ui_views.inner.borrow_mut().identity_map.put(user_info.get_identity_id()?, identity)?;
+            let stored = harness.saved_ui_views.inner.borrow().view_infos[&token].clone();
+            let cached = stored.as_ref().err().and_then(|e| e.cached.clone()).unwrap();
+            assert_eq!(cached.app_title, "Etherpad");
+            assert!(cached.stale);
+            assert_eq!(harness.saved_ui_views.broken_tokens(false), vec![token.clone()]);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn health_checks_back_off() {
+        use super::{EntryHealth, HealthStatus, DISCONNECTED_RETRY_MILLIS, FAILED_RETRY_MILLIS,
+                    HEALTH_CHECK_INTERVAL_MILLIS, MAX_RETRY_MILLIS};
+        let disconnected: Result<(), Error> = Err(Error::disconnected("restarting".into()));
+        let failed: Result<(), Error> = Err(Error::failed("no such grain".into()));
+
+        let mut health = EntryHealth::new(Some(1));
+        health.record(&disconnected, 1000);
+        assert_eq!(health.status, HealthStatus::Disconnected);
+        assert_eq!((health.failures, health.failing_since), (1, Some(1000)));
+        assert_eq!(health.next_check_at, 1000 + DISCONNECTED_RETRY_MILLIS);
+        health.record(&disconnected, 2000);
+        assert_eq!(health.next_check_at, 2000 + 2 * DISCONNECTED_RETRY_MILLIS);
+
+        // The delay keeps doubling from the base of the latest kind of failure, and the run of
+        // failures goes on.
+        health.record(&failed, 3000);
+        assert_eq!(health.status, HealthStatus::Failed);
+        assert_eq!((health.failures, health.failing_since), (3, Some(1000)));
+        assert_eq!(health.next_check_at, 3000 + 4 * FAILED_RETRY_MILLIS);
+        assert_eq!(health.last_ok_at, Some(1));
+
+        for _ in 0..100 {
+            health.record(&failed, 4000);
         }
+        assert_eq!(health.next_check_at, 4000 + MAX_RETRY_MILLIS);
+
+        health.record(&Ok::<(), Error>(()), 5000);
+        assert_eq!(health.status, HealthStatus::Ok);
+        assert_eq!((health.failures, health.failing_since), (0, None));
+        assert_eq!((health.last_ok_at, health.last_error.clone()), (Some(5000), None));
+        assert_eq!(health.next_check_at, 5000 + HEALTH_CHECK_INTERVAL_MILLIS);
+
+        health.record(&failed, 6000);
+        assert_eq!((health.failures, health.failing_since), (1, Some(6000)));
+        assert_eq!(health.next_check_at, 6000 + FAILED_RETRY_MILLIS);
+        assert!(health.last_error.unwrap().ends_with("no such grain"));
+    }
 
-        Ok(())
+    #[test]
+    fn bulk_remove_is_not_mirrored() {
+        run(async {
+            let harness = Harness::new("bulk-remove")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+            socket.wait_for("{\"viewInfo\"").await;
+
+            let mut req = harness.sandstorm_api.drop_request();
+            req.get().set_token(&super::decode_token(&token)?[..]);
+            req.send().promise.await?;
+            assert!(harness.saved_ui_views.refresh_view_info(token.clone()).await.is_err());
+
+            editor.post("broken/remove", b"").await?;
+            socket.wait_for("{\"bulkDone\"").await;
+            assert!(!harness.entry_tokens().contains(&token));
+            assert_eq!(editor.context.activities(),
+                       vec![ADD_GRAIN_ACTIVITY_INDEX, REMOVE_GRAIN_ACTIVITY_INDEX]);
+            assert!(harness.saved_ui_views.inner.borrow().mirror.tombstones.is_empty());
+            Ok(())
+        });
     }
-}
 
-pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use ::std::os::unix::io::{FromRawFd};
-    use futures::io::AsyncReadExt;
+    #[test]
+    fn bulk_remove_keeps_disconnected_entries() {
+        run(async {
+            let harness = Harness::new("bulk-remove-disconnected")?;
+            let editor = harness.open_session(1, true).await?;
+            let socket = editor.open_web_socket().await?;
+            let deleted_view = FakeUiView::new("Etherpad", "Deleted");
+            let deleted = editor.add_grain(&harness, "request-1", "Deleted", &deleted_view).await?;
+            let restarting_view = FakeUiView::new("Etherpad", "Restarting");
+            let restarting = editor.add_grain(&harness, "request-2", "Restarting", &restarting_view).await?;
+            socket.wait_for("{\"viewInfo\"").await;
+
+            let mut req = harness.sandstorm_api.drop_request();
+            req.get().set_token(&super::decode_token(&deleted)?[..]);
+            req.send().promise.await?;
+            assert!(harness.saved_ui_views.refresh_view_info(deleted.clone()).await.is_err());
+            restarting_view.fail_with(Some(Error::disconnected("grain is restarting".into())));
+            assert!(harness.saved_ui_views.refresh_view_info(restarting.clone()).await.is_err());
+            assert_eq!(harness.saved_ui_views.broken_tokens(true),
+                       vec![restarting.clone(), deleted.clone()]);
+
+            editor.post("broken/remove", b"").await?;
+            socket.wait_for("{\"bulkDone\"").await;
+            assert!(!harness.entry_tokens().contains(&deleted));
+            assert!(harness.entry_tokens().contains(&restarting));
+
+            // Once the grain is back, the entry works again.
+            restarting_view.fail_with(None);
+            harness.saved_ui_views.refresh_view_info(restarting.clone()).await?;
+            assert!(harness.saved_ui_views.broken_tokens(true).is_empty());
+            Ok(())
+        });
+    }
 
-    let mut rt = tokio::runtime::Runtime::new().unwrap();
-    let local = tokio::task::LocalSet::new();
+    #[test]
+    fn remove_grain_drops_capability() {
+        run(async {
+            let harness = Harness::new("remove-grain")?;
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Old notes");
+            let token = editor.add_grain(&harness, "request-1", "Old notes", &view).await?;
+            let socket = editor.open_web_socket().await?;
+            socket.wait_for("{\"insert\"").await;
+
+            let viewer = harness.open_session(2, false).await?;
+            let response = viewer.delete(&format!("sturdyref/{}", token)).await?;
+            assert_eq!(client_error_code(&response), Some(response::ClientErrorCode::Forbidden));
+            assert!(harness.entry_tokens().contains(&token));
+
+            let response = editor.delete(&format!("sturdyref/{}", token)).await?;
+            assert!(is_no_content(&response));
+            assert_eq!(editor.context.activities(),
+                       vec![ADD_GRAIN_ACTIVITY_INDEX, REMOVE_GRAIN_ACTIVITY_INDEX]);
+            assert_eq!(socket.wait_for("{\"remove\"").await,
+                       format!("{{\"remove\":{{\"token\":\"{}\"}}}}", token));
+            let mut req = harness.sandstorm_api.restore_request();
+            req.get().set_token(&super::decode_token(&token)?[..]);
+            assert!(req.send().promise.await.is_err());
+            assert!(!entry_store::read(harness.root.join("entries"))?.entries.contains_key(&token));
+            assert!(!harness.root.join("view-info").join(&token).exists());
+            Ok(())
+        });
+    }
 
-    local.block_on(&mut rt, async move {
-        let stream: ::std::os::unix::net::UnixStream = unsafe { FromRawFd::from_raw_fd(3) };
-        let stream = tokio::net::UnixStream::from_std(stream)?;
-        let (read_half, write_half) =
-            tokio_util::compat::Tokio02AsyncReadCompatExt::compat(stream).split();
+    #[test]
+    fn profiles_are_cached_and_names_recorded() {
+        run(async {
+            let harness = Harness::new("profiles")?;
+            let user = IdentityId::from_bytes(&[1; 32])?;
+            let views = harness.saved_ui_views.clone();
+            let profile_name = || views.inner.borrow().profiles.get(&user).map(|p| p.display_name.clone());
+            let set_cached_profile = |name: &str, fetched_at: u64| {
+                let mut inner = views.inner.borrow_mut();
+                let profile = inner.profiles.get_mut(&user).unwrap();
+                profile.display_name = name.into();
+                profile.fetched_at = fetched_at;
+            };
+            let set_added_by_name = |token: &str, name: Option<&str>| -> Result<(), Error> {
+                let mut entry = views.inner.borrow().views[token].clone();
+                entry.added_by_name = name.map(|n| n.into());
+                views.update_entry(token.into(), entry)
+            };
 
-        let network =
-            Box::new(twoparty::VatNetwork::new(read_half, write_half,
-                                               rpc_twoparty_capnp::Side::Client,
-                                               Default::default()));
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            let token = editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+            let identity_file = harness.root.join("identities").join(user.truncated_text());
+            wait_until(|| ::std::fs::symlink_metadata(&identity_file).is_ok()).await;
+
+            // The first viewer fetches the profile, which fills in the name of the entry saved
+            // before names were recorded, without counting as an edit.
+            set_added_by_name(&token, None)?;
+            let modified_at = views.inner.borrow().views[&token].modified_at;
+            let socket = editor.open_web_socket().await?;
+            socket.wait_for("{\"user\"").await;
+            assert_eq!(profile_name(), Some("User 1".into()));
+            assert!(harness.root.join("profiles").join(user.to_string()).exists());
+            let entry = views.inner.borrow().views[&token].clone();
+            assert_eq!((entry.added_by_name, entry.modified_at), (Some("User 1".into()), modified_at));
+
+            // A cached profile is used as is until it's older than PROFILE_TTL_MILLIS.
+            let now = super::now_millis()?;
+            set_cached_profile("Cached", now);
+            views.refresh_user_profile(&user)?;
+            tokio::time::delay_for(::std::time::Duration::from_millis(50)).await;
+            assert_eq!(profile_name(), Some("Cached".into()));
+            set_cached_profile("Cached", now - super::PROFILE_TTL_MILLIS - 1);
+            views.refresh_user_profile(&user)?;
+            wait_until(|| profile_name() == Some("User 1".into())).await;
+
+            // Without the identity, an expired profile stays in place.
+            ::std::fs::remove_file(&identity_file)?;
+            set_cached_profile("Cached", 0);
+            views.refresh_user_profile(&user)?;
+            wait_until(|| !views.inner.borrow().profiles_in_flight.contains(&user)).await;
+            assert_eq!(profile_name(), Some("Cached".into()));
+
+            // With no profile at all, the name recorded in the entry stands in for it.
+            views.inner.borrow_mut().profiles.remove(&user);
+            set_added_by_name(&token, Some("Recorded"))?;
+            let before = socket.actions().len();
+            views.refresh_user_profile(&user)?;
+            wait_until(|| socket.actions()[before..].iter().any(|a| a.starts_with("{\"user\""))).await;
+            assert!(socket.actions()[before..].iter()
+                    .any(|a| a.starts_with("{\"user\"") && a.contains("\"displayName\":\"Recorded\"")));
+            assert_eq!(profile_name(), None);
+            let json = String::from_utf8(body_bytes(&editor.get("export.json").await?)).unwrap();
+            assert!(json.contains(&format!("\"addedBy\":{{\"id\":\"{}\",\"displayName\":\"Recorded\"}}",
+                                           user)));
+            Ok(())
+        });
+    }
 
-        let (tx, rx) = futures::channel::oneshot::channel();
-        let sandstorm_api: sandstorm_api::Client<::capnp::any_pointer::Owned> =
-            ::capnp_rpc::new_future_client(rx.map_err(|_e| capnp::Error::failed(format!("oneshot was canceled"))));
+    #[test]
+    fn identity_garbage_takes_cached_profiles_along() {
+        run(async {
+            let harness = Harness::new("identity-gc-profiles")?;
+            let views = harness.saved_ui_views.clone();
+            let editor = harness.open_session(1, true).await?;
+            let view = FakeUiView::new("Etherpad", "Notes");
+            editor.add_grain(&harness, "request-1", "Notes", &view).await?;
+            let user = IdentityId::from_bytes(&[1; 32])?;
+            let gone = IdentityId::from_bytes(&[3; 32])?;
+            views.inner.borrow_mut().identity_map.put(&gone, new_identity("User 3"))?;
+            wait_until(|| views.inner.borrow().identity_map.contains(&gone)).await;
+            for id in [user, gone].iter() {
+                views.refresh_user_profile(id)?;
+                wait_until(|| views.inner.borrow().profiles.contains_key(id)).await;
+            }
 
-        let identity_map = IdentityMap::new(
-            "/var/identities",
-            "/var/trash",
-            &sandstorm_api)?;
-        let saved_uiviews = SavedUiViewSet::new(
-            "/var/tmp",
-            "/var/sturdyrefs",
-            &sandstorm_api,
-            identity_map)?;
-
-        let uiview = UiView::new(
-            sandstorm_api,
-            saved_uiviews);
-
-        let client: ui_view::Client = capnp_rpc::new_client(uiview);
-        let mut rpc_system = RpcSystem::new(network, Some(client.client));
+            views.collect_identity_garbage()?;
+            assert!(views.inner.borrow().profiles.contains_key(&user));
+            assert!(harness.root.join("profiles").join(user.to_string()).exists());
+            assert!(!views.inner.borrow().profiles.contains_key(&gone));
+            assert!(!harness.root.join("profiles").join(gone.to_string()).exists());
+            Ok(())
+        });
+    }
 
-        let _ = tx.send(rpc_system.bootstrap::<sandstorm_api::Client<::capnp::any_pointer::Owned>>(
-            ::capnp_rpc::rpc_twoparty_capnp::Side::Server));
+    #[test]
+    fn export_json() {
+        run(async {
+            let harness = Harness::new("export")?;
+            let editor = harness.open_session(1, true).await?;
+            editor.put("description", b"Team \"grains\"").await?;
+            let pad = FakeUiView::new("Etherpad", "Agenda");
+            let pad_token = editor.add_grain(&harness, "request-1", "Agenda", &pad).await?;
+            let socket = editor.open_web_socket().await?;
+            socket.wait_for("{\"viewInfo\"").await;
+
+            let viewer = harness.open_session(2, false).await?;
+            let json = String::from_utf8(body_bytes(&viewer.get("export.json").await?)).unwrap();
+            let collection_id = harness.saved_ui_views.inner.borrow().collection_id.clone();
+            assert!(json.starts_with("{\"version\":1,\"exportedAt\":\""));
+            assert!(json.contains(&format!(
+                "\"collectionId\":\"{}\",\"description\":\"Team \\\"grains\\\"\",\"entries\":[{{\"token\":\"{}\",",
+                collection_id, pad_token)));
+            assert!(json.contains("\"title\":\"Agenda\""));
+            assert!(json.contains(&format!("\"addedBy\":{{\"id\":\"{}\",\"displayName\":\"User 1\"}}",
+                                           IdentityId::from_bytes(&[1; 32])?)));
+            assert!(json.contains(
+                "\"appTitle\":\"Etherpad\",\"grainIconUrl\":\"https://icons.example.com/Etherpad.svg\""));
+            assert!(json.ends_with("}],\"links\":[],\"notes\":[],\"files\":[]}"));
+
+            let (response, collector) = viewer.get_streamed("export.json").await?;
+            assert!(matches!(response.get()?.which()?, response::Content(_)));
+            let streamed = String::from_utf8(collector.bytes.borrow().clone()).unwrap();
+            assert_eq!(without_exported_at(&streamed), without_exported_at(&json));
+            Ok(())
+        });
+    }
 
-        Ok::<_,  Box<dyn std::error::Error>>(rpc_system.await?)
-    })?;
-    Ok(())
+    #[test]
+    fn export_storage_matches_export_json() {
+        run(async {
+            let harness = Harness::new("export-storage")?;
+            let editor = harness.open_session(1, true).await?;
+            editor.put("description", b"Offline").await?;
+            let pad = FakeUiView::new("Etherpad", "Agenda");
+            editor.add_grain(&harness, "request-1", "Agenda", &pad).await?;
+            let socket = editor.open_web_socket().await?;
+            socket.wait_for("{\"viewInfo\"").await;
+
+            // Compare with a grain that has just started, which hasn't checked any entry yet.
+            let restarted = Harness::open(harness.root.clone())?;
+            let viewer = restarted.open_session(2, false).await?;
+            let json = String::from_utf8(body_bytes(&viewer.get("export.json").await?)).unwrap();
+            let mut stored = Vec::new();
+            super::export_storage(&harness.root, &mut stored)?;
+            let stored = String::from_utf8(stored).unwrap();
+            assert_eq!(without_exported_at(stored.trim_end()), without_exported_at(&json));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn export_streams_in_chunks() {
+        run(async {
+            let harness = Harness::new("export-chunks")?;
+            let editor = harness.open_session(1, true).await?;
+            let count = super::EXPORT_ENTRIES_PER_CHUNK + 1;
+            for i in 0..count {
+                let title = format!("Pad {}", i);
+                let view = FakeUiView::new("Etherpad", &title);
+                editor.add_grain(&harness, &format!("request-{}", i), &title, &view).await?;
+            }
+
+            let (_, collector) = editor.get_streamed("api/export.json").await?;
+            // The head, two chunks of entries, and the tail.
+            assert_eq!(collector.writes.get(), 4);
+            let json = String::from_utf8(collector.bytes.borrow().clone()).unwrap();
+            assert_eq!(json.matches("\"entryId\"").count(), count);
+            assert!(json.ends_with("}],\"links\":[],\"notes\":[],\"files\":[]}"));
+            Ok(())
+        });
+    }
 }
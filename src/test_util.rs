@@ -0,0 +1,93 @@
+// Copyright (c) 2016 Sandstorm Development Group, Inc.
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Helpers shared by the tests of several modules.
+
+use std::path::{Path, PathBuf};
+
+use crate::collections_capnp::ui_view_metadata;
+use crate::entry_store::EntryStore;
+
+/// A fresh, empty directory under the system's temporary directory. It is deleted, along with
+/// everything in it, when this is dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// `name` must be unique among the tests.
+    pub fn new(name: &str) -> TempDir {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("collections-test-{}-{}", ::std::process::id(), name));
+        let _ = ::std::fs::remove_dir_all(&path);
+        ::std::fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Runs `f` to completion on a fresh runtime, with a `LocalSet` for the RPC tasks, and panics
+/// if it fails.
+pub fn run<F>(f: F) where F: ::std::future::Future<Output=Result<(), ::capnp::Error>> {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&mut rt, f).unwrap();
+}
+
+/// A serialized `UiViewMetadata` message.
+pub fn metadata_bytes(title: &str) -> Vec<u8> {
+    let mut message = ::capnp::message::Builder::new_default();
+    {
+        let mut metadata: ui_view_metadata::Builder = message.init_root();
+        metadata.set_title(title);
+        metadata.set_date_added(1234);
+    }
+    ::capnp::serialize::write_message_to_words(&message)
+}
+
+/// The title in a serialized `UiViewMetadata` message.
+pub fn title_of(bytes: &[u8]) -> String {
+    let message = ::capnp::serialize::read_message(&mut &bytes[..], Default::default()).unwrap();
+    let metadata: ui_view_metadata::Reader = message.get_root().unwrap();
+    metadata.get_title().unwrap().to_string().unwrap()
+}
+
+/// The token and title of each entry in `store`, sorted.
+pub fn titles(store: &EntryStore) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = store.entries().iter()
+        .map(|(token, bytes)| (token.clone(), title_of(bytes)))
+        .collect();
+    result.sort();
+    result
+}